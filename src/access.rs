@@ -0,0 +1,264 @@
+//! Allow and deny rules deciding who may log in to a relying party.
+
+use anyhow::Result;
+use serde::Deserialize;
+
+/// Prefix of the sled trees backing [`AccessRule::List`].
+const LIST_TREE_PREFIX: &str = "list:";
+
+/// A single matcher in [`AccessRules`].
+///
+/// In TOML, rules are written as one-key tables, e.g. `{ domain = "example.org" }`.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessRule {
+    /// Matches every address at this domain.
+    Domain(String),
+    /// Matches exactly this address.
+    Address(String),
+    /// Matches the key with this fingerprint (hex, spaces ignored).
+    Fingerprint(String),
+    /// Matches addresses and fingerprints stored in the named sled list.
+    List(String),
+}
+
+/// Per-client access control, evaluated before `/authorize` issues a code.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct AccessRules {
+    /// If non-empty, only users matching one of these rules may log in.
+    #[serde(default)]
+    pub allow: Vec<AccessRule>,
+    /// Users matching any of these rules may not log in, even if allowed.
+    #[serde(default)]
+    pub deny: Vec<AccessRule>,
+    /// Also explain a denial to the user over Delta Chat.
+    #[serde(default)]
+    pub notify_denied: bool,
+}
+
+impl AccessRule {
+    fn matches(&self, db: &sled::Db, addr: &str, fingerprint: Option<&str>) -> Result<bool> {
+        Ok(match self {
+            AccessRule::Domain(domain) => addr
+                .rsplit_once('@')
+                .is_some_and(|(_, d)| d.eq_ignore_ascii_case(domain)),
+            AccessRule::Address(rule_addr) => addr.eq_ignore_ascii_case(rule_addr),
+            AccessRule::Fingerprint(fp) => {
+                fingerprint.is_some_and(|fingerprint| normalize_fingerprint(fp) == fingerprint)
+            }
+            AccessRule::List(name) => {
+                let tree = list_tree(db, name)?;
+                tree.contains_key(addr.to_lowercase())?
+                    || match fingerprint {
+                        Some(fingerprint) => tree.contains_key(fingerprint)?,
+                        None => false,
+                    }
+            }
+        })
+    }
+}
+
+impl AccessRules {
+    /// Check whether `addr` with key `fingerprint` may log in.
+    ///
+    /// Returns the reason shown to the user if the login is denied.
+    pub(crate) fn check(
+        &self,
+        db: &sled::Db,
+        addr: &str,
+        fingerprint: Option<&str>,
+    ) -> Result<Option<String>> {
+        let fingerprint = fingerprint.map(normalize_fingerprint);
+        let fingerprint = fingerprint.as_deref();
+        for rule in &self.deny {
+            if rule.matches(db, addr, fingerprint)? {
+                return Ok(Some(format!("{addr} is not allowed to log in here")));
+            }
+        }
+        if self.allow.is_empty() {
+            return Ok(None);
+        }
+        for rule in &self.allow {
+            if rule.matches(db, addr, fingerprint)? {
+                return Ok(None);
+            }
+        }
+        Ok(Some(format!("{addr} is not on the list of allowed users")))
+    }
+}
+
+/// Open the sled tree backing the access list `name`.
+///
+/// Keys are lowercased addresses or uppercase fingerprints; values are unused.
+pub(crate) fn list_tree(db: &sled::Db, name: &str) -> Result<sled::Tree> {
+    Ok(db.open_tree(format!("{LIST_TREE_PREFIX}{name}"))?)
+}
+
+/// Add `key`, an address or fingerprint, to the access list `name`.
+///
+/// Returns `false` if it was on the list already.
+pub(crate) fn list_add(db: &sled::Db, name: &str, key: &str) -> Result<bool> {
+    Ok(list_tree(db, name)?
+        .insert(normalize_key(key), b"")?
+        .is_none())
+}
+
+/// Remove `key` from the access list `name`. Returns `false` if it was not on it.
+pub(crate) fn list_remove(db: &sled::Db, name: &str, key: &str) -> Result<bool> {
+    Ok(list_tree(db, name)?.remove(normalize_key(key))?.is_some())
+}
+
+/// The addresses and fingerprints on the access list `name`.
+pub(crate) fn list_members(db: &sled::Db, name: &str) -> Result<Vec<String>> {
+    let mut members = Vec::new();
+    for key in list_tree(db, name)?.iter().keys() {
+        members.push(String::from_utf8(key?.to_vec())?);
+    }
+    Ok(members)
+}
+
+/// The names of all access lists with members.
+pub(crate) fn list_names(db: &sled::Db) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for tree_name in db.tree_names() {
+        let Some(name) = std::str::from_utf8(&tree_name)
+            .ok()
+            .and_then(|tree_name| tree_name.strip_prefix(LIST_TREE_PREFIX))
+        else {
+            continue;
+        };
+        if !list_tree(db, name)?.is_empty() {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// Normalize an address or fingerprint: addresses are lowercased,
+/// fingerprints uppercased.
+pub(crate) fn normalize_key(key: &str) -> String {
+    if key.contains('@') {
+        key.trim().to_lowercase()
+    } else {
+        normalize_fingerprint(key)
+    }
+}
+
+/// Uppercase a hex fingerprint and strip the spaces it is often displayed with.
+pub(crate) fn normalize_fingerprint(fp: &str) -> String {
+    fp.chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// Whether `fp` is a hex OpenPGP fingerprint (v4 or v6), spaces allowed.
+pub(crate) fn is_fingerprint(fp: &str) -> bool {
+    let fp = normalize_fingerprint(fp);
+    matches!(fp.len(), 40 | 64) && fp.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FP: &str = "1234 ABCD 5678 EF00";
+
+    fn db() -> sled::Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    #[test]
+    fn test_no_rules_allows_everyone() {
+        let rules = AccessRules::default();
+        assert_eq!(rules.check(&db(), "alice@example.org", None).unwrap(), None);
+    }
+
+    #[test]
+    fn test_allow_domain() {
+        let rules = AccessRules {
+            allow: vec![AccessRule::Domain("Example.org".into())],
+            ..Default::default()
+        };
+        let db = db();
+        assert!(rules
+            .check(&db, "alice@example.org", None)
+            .unwrap()
+            .is_none());
+        assert!(rules.check(&db, "bob@other.org", None).unwrap().is_some());
+        assert!(rules
+            .check(&db, "eve@evilexample.org", None)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_deny_beats_allow() {
+        let rules = AccessRules {
+            allow: vec![AccessRule::Domain("example.org".into())],
+            deny: vec![AccessRule::Fingerprint(FP.to_lowercase())],
+            ..Default::default()
+        };
+        let db = db();
+        assert!(rules
+            .check(&db, "alice@example.org", Some("1234ABCD5678EF00"))
+            .unwrap()
+            .is_some());
+        assert!(rules
+            .check(&db, "alice@example.org", Some("FFFF"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_list() {
+        let rules = AccessRules {
+            allow: vec![AccessRule::List("members".into())],
+            ..Default::default()
+        };
+        let db = db();
+        assert!(rules
+            .check(&db, "alice@example.org", None)
+            .unwrap()
+            .is_some());
+        assert!(list_add(&db, "members", "Alice@example.org").unwrap());
+        assert!(!list_add(&db, "members", "alice@example.org").unwrap());
+        assert!(rules
+            .check(&db, "Alice@example.org", None)
+            .unwrap()
+            .is_none());
+        list_add(&db, "members", FP).unwrap();
+        assert!(rules
+            .check(&db, "bob@example.org", Some(FP))
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            list_members(&db, "members").unwrap(),
+            ["1234ABCD5678EF00", "alice@example.org"]
+        );
+        assert_eq!(list_names(&db).unwrap(), ["members"]);
+        assert!(list_remove(&db, "members", "ALICE@example.org").unwrap());
+        assert!(!list_remove(&db, "members", "alice@example.org").unwrap());
+        assert!(rules
+            .check(&db, "alice@example.org", None)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_normalize_key() {
+        assert_eq!(normalize_key(" Alice@Example.org"), "alice@example.org");
+        assert_eq!(normalize_key("ab12 cd34"), "AB12CD34");
+    }
+
+    #[test]
+    fn test_is_fingerprint() {
+        assert!(is_fingerprint(
+            "1234 ABCD 5678 EF00 1234 abcd 5678 ef00 1234 ABCD"
+        ));
+        assert!(is_fingerprint(&"A".repeat(64)));
+        assert!(!is_fingerprint(FP));
+        assert!(!is_fingerprint(&"G".repeat(40)));
+        assert!(!is_fingerprint("alice@example.org"));
+    }
+}
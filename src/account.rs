@@ -0,0 +1,377 @@
+//! Self-service account page: users see what the bot knows about them,
+//! revoke access, change their canonical address or delete their account.
+//!
+//! `/account` is protected by the usual QR code login. The login page comes
+//! back with its completed flow, whose user is then kept in the session.
+
+use anyhow::Result;
+use axum::{
+    extract::{Form, Query, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::cookie::CookieJar;
+use data_encoding::HEXLOWER;
+use deltachat::contact::{Contact, ContactId};
+use serde::Deserialize;
+use tower_sessions::Session;
+
+use crate::flow::Flow;
+use crate::{
+    approval, blocks, canonical_addr, consent, devices, forward_auth, html, logout, secrets_match,
+    subjects, unix_time, webhooks, AccessToken, AppError, AppState,
+};
+
+/// Session key of the user logged in to the account page.
+const ACCOUNT_SESSION_KEY: &str = "account_contact_id";
+
+/// Session key of the token the forms of the account page have to post.
+const CSRF_SESSION_KEY: &str = "account_csrf";
+
+/// Query of `/account`: the flow the login page completed.
+#[derive(Debug, Deserialize)]
+pub(crate) struct AccountQuery {
+    flow: Option<String>,
+}
+
+/// Form posted by the buttons of the account page.
+#[derive(Debug, Deserialize)]
+pub(crate) struct AccountForm {
+    action: String,
+    #[serde(default)]
+    id: String,
+    /// The session's CSRF token, see [`CSRF_SESSION_KEY`].
+    #[serde(default)]
+    csrf: String,
+}
+
+/// What the bot knows about a user, across their keys and addresses.
+struct Account {
+    contact: Contact,
+    /// The canonical address, identifying the user towards clients.
+    identity: String,
+    /// Keys whose canonical address is `identity`.
+    fingerprints: Vec<String>,
+    /// Addresses that logged in with one of `fingerprints`.
+    addresses: Vec<String>,
+}
+
+impl Account {
+    async fn load(state: &AppState, contact_id: ContactId) -> Result<Account> {
+        let contact = Contact::get_by_id(&state.dc_context, contact_id).await?;
+        let identity = canonical_addr(state, &contact)?;
+        let (fingerprints, addresses) = keys_of(&state.db, &identity)?;
+        Ok(Account {
+            contact,
+            identity,
+            fingerprints,
+            addresses,
+        })
+    }
+}
+
+/// `GET /account`: show the account page, or the login page first.
+pub(crate) async fn get_account(
+    State(state): State<AppState>,
+    session: Session,
+    Query(query): Query<AccountQuery>,
+) -> Result<Response, AppError> {
+    if let Some(flow_id) = query.flow {
+        // Only the browser that started the flow may take it over.
+        let started_here = session.get::<String>("flow_id").await?.as_deref() == Some(&flow_id);
+        let flow = match started_here {
+            true => Flow::load(&state.db, &flow_id)?,
+            false => None,
+        };
+        if let Some(flow) = flow.as_ref().filter(|flow| flow.email_login) {
+            // The mailbox alone must not change or delete a key-bound account.
+            log::info!("/account rejected a login by email");
+            flow.remove(&state.db)?;
+            session.remove::<String>("flow_id").await?;
+            return Ok((
+                StatusCode::FORBIDDEN,
+                html::message_page(
+                    "Login by email not accepted",
+                    "To manage your account, log in by scanning the QR code with Delta Chat.",
+                ),
+            )
+                .into_response());
+        }
+        if let Some((flow, contact_id)) =
+            flow.and_then(|flow| flow.contact_id.map(|contact_id| (flow, contact_id)))
+        {
+            flow.remove(&state.db)?;
+            session.remove::<String>("flow_id").await?;
+            session.insert(ACCOUNT_SESSION_KEY, contact_id).await?;
+            let csrf = uuid::Uuid::new_v4().simple().to_string();
+            session.insert(CSRF_SESSION_KEY, csrf).await?;
+            log::info!("/account logged in contact {contact_id}");
+        }
+        return Ok(Redirect::to("/account").into_response());
+    }
+    let (Some(contact_id), Some(csrf)) = (
+        session.get::<u32>(ACCOUNT_SESSION_KEY).await?,
+        session.get::<String>(CSRF_SESSION_KEY).await?,
+    ) else {
+        return Ok(Html::from(state.login_html).into_response());
+    };
+    let account = Account::load(&state, ContactId::new(contact_id)).await?;
+    Ok(render(&state, &account, &csrf)?.into_response())
+}
+
+/// `POST /account`: revoke something, change the address or delete the account.
+pub(crate) async fn post_account(
+    State(state): State<AppState>,
+    session: Session,
+    jar: CookieJar,
+    Form(form): Form<AccountForm>,
+) -> Result<Response, AppError> {
+    let (Some(contact_id), Some(csrf)) = (
+        session.get::<u32>(ACCOUNT_SESSION_KEY).await?,
+        session.get::<String>(CSRF_SESSION_KEY).await?,
+    ) else {
+        return Ok(Redirect::to("/account").into_response());
+    };
+    if !secrets_match(&form.csrf, &csrf) {
+        log::info!("/account rejected a form without the session's CSRF token");
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+    let contact_id = ContactId::new(contact_id);
+    let account = Account::load(&state, contact_id).await?;
+    match form.action.as_str() {
+        "revoke_token" => {
+            let tokens = state.db.open_tree("tokens")?;
+            for token in tokens_of(&state, contact_id)? {
+                if token_id(&token.0) == form.id {
+                    tokens.remove(&token.0)?;
+                }
+            }
+        }
+        "revoke_device" => {
+            devices::revoke(&state.db, contact_id.to_u32(), &form.id)?;
+        }
+        "revoke_grant" => {
+            consent::ungrant(&state.db, &account.identity, &form.id)?;
+        }
+        "set_address" if account.addresses.contains(&form.id) => {
+            let id_tree = state.db.open_tree("identities")?;
+            for fp in &account.fingerprints {
+                id_tree.insert(fp, form.id.as_bytes())?;
+            }
+            consent::move_grants(&state.db, &account.identity, &form.id)?;
+            subjects::move_to(&state.db, &account.identity, &form.id)?;
+            webhooks::fire(
+                &state,
+                webhooks::Event::AddressChanged {
+                    identity: form.id.clone(),
+                    previous: account.identity.clone(),
+                },
+            )?;
+            log::info!(
+                "/account changed canonical address {} to {}",
+                account.identity,
+                form.id
+            );
+        }
+        "delete" => {
+            logout::notify_clients(&state, &account.identity)?;
+            webhooks::fire(
+                &state,
+                webhooks::Event::Deleted {
+                    identity: account.identity.clone(),
+                },
+            )?;
+            let jar = forward_auth::forget(&state, jar, Some(&account.identity))?;
+            delete(&state, &account)?;
+            session.flush().await?;
+            return Ok((
+                jar,
+                html::message_page(
+                    "Account deleted",
+                    "The bot forgot your account. Logging in again creates a new one.",
+                ),
+            )
+                .into_response());
+        }
+        _ => return Ok(StatusCode::BAD_REQUEST.into_response()),
+    }
+    Ok(Redirect::to("/account").into_response())
+}
+
+/// Forget everything about `account` except block list entries.
+fn delete(state: &AppState, account: &Account) -> Result<()> {
+    let contact_id = account.contact.get_id();
+    blocks::revoke_contact(&state.db, contact_id.to_u32())?;
+    consent::ungrant(&state.db, &account.identity, "all")?;
+    subjects::forget(&state.db, &account.identity)?;
+    webhooks::forget(&state.db, &account.identity)?;
+    approval::forget(&state.db, contact_id)?;
+    forget_keys(&state.db, &account.identity)?;
+    log::info!("/account deleted the account of {}", account.identity);
+    Ok(())
+}
+
+/// The keys whose canonical address is `identity`, and the addresses that
+/// logged in with one of them.
+pub(crate) fn keys_of(db: &sled::Db, identity: &str) -> Result<(Vec<String>, Vec<String>)> {
+    let mut fingerprints = Vec::new();
+    for entry in &db.open_tree("identities")? {
+        let (fp, canonical) = entry?;
+        if String::from_utf8_lossy(&canonical).eq_ignore_ascii_case(identity) {
+            fingerprints.push(String::from_utf8(fp.to_vec())?);
+        }
+    }
+    let mut addresses = Vec::new();
+    for entry in &db.open_tree("addr_fingerprints")? {
+        let (addr, fp) = entry?;
+        if fingerprints
+            .iter()
+            .any(|known| known.as_bytes() == fp.as_ref())
+        {
+            addresses.push(String::from_utf8(addr.to_vec())?);
+        }
+    }
+    Ok((fingerprints, addresses))
+}
+
+/// Forget which keys and addresses belong to `identity`, so that the next
+/// login of the user creates a new identity. Returns how many keys it had.
+pub(crate) fn forget_keys(db: &sled::Db, identity: &str) -> Result<usize> {
+    let (fingerprints, addresses) = keys_of(db, identity)?;
+    let id_tree = db.open_tree("identities")?;
+    for fp in &fingerprints {
+        id_tree.remove(fp)?;
+    }
+    let addr_tree = db.open_tree("addr_fingerprints")?;
+    for addr in &addresses {
+        addr_tree.remove(addr)?;
+    }
+    Ok(fingerprints.len())
+}
+
+/// The unexpired access tokens of `contact_id`.
+fn tokens_of(state: &AppState, contact_id: ContactId) -> Result<Vec<(String, AccessToken)>> {
+    let mut tokens = Vec::new();
+    for entry in &state.db.open_tree("tokens")? {
+        let (key, data) = entry?;
+        let Ok(token) = serde_json::from_slice::<AccessToken>(&data) else {
+            continue;
+        };
+        if token.contact_id == contact_id.to_u32() && token.expires > unix_time() {
+            tokens.push((String::from_utf8(key.to_vec())?, token));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Id of an access token on the account page, which must not show the token itself.
+fn token_id(token: &str) -> String {
+    let mut id = HEXLOWER.encode(&hmac_sha256::Hash::hash(token.as_bytes()));
+    id.truncate(12);
+    id
+}
+
+/// A button posting `action` for `id` with the session's `csrf` token.
+fn button(csrf: &str, action: &str, id: &str, label: &str) -> String {
+    format!(
+        r#"<form method="post" action="/account"><input type="hidden" name="csrf" value="{}"><input type="hidden" name="action" value="{action}"><input type="hidden" name="id" value="{}"><button type="submit">{label}</button></form>"#,
+        html::escape(csrf),
+        html::escape(id)
+    )
+}
+
+fn render(state: &AppState, account: &Account, csrf: &str) -> Result<Html<String>> {
+    let button = |action: &str, id: &str, label: &str| button(csrf, action, id, label);
+    let contact_id = account.contact.get_id();
+    let mut body = format!(
+        r#"<p>You are known to clients as <strong>{}</strong>. <a href="/end_session">Log out</a></p>"#,
+        html::escape(&account.identity)
+    );
+    let others: Vec<&String> = account
+        .addresses
+        .iter()
+        .filter(|addr| !addr.eq_ignore_ascii_case(&account.identity))
+        .collect();
+    for addr in others {
+        body += &button(
+            "set_address",
+            addr,
+            &format!("Use {} instead", html::escape(addr)),
+        );
+    }
+
+    body += "<h2>Keys</h2><ul>";
+    for fp in &account.fingerprints {
+        body += &format!("<li><code>{}</code></li>", html::escape(fp));
+    }
+    body += "</ul>";
+
+    body += "<h2>Recent logins</h2><ul>";
+    for login in approval::history(&state.db, contact_id)? {
+        body += &format!(
+            "<li>{} at {} by {}, {}</li>",
+            html::escape(&login.client),
+            html::escape(&login.origin),
+            html::escape(&login.method),
+            devices::days_ago(login.time)
+        );
+    }
+    body += "</ul>";
+
+    body += "<h2>Active sessions</h2><ul>";
+    for (token, info) in tokens_of(state, contact_id)? {
+        body += &format!(
+            "<li>{}, valid for {} more minutes {}</li>",
+            html::escape(client_name(state, &info.client_id)),
+            info.expires.saturating_sub(unix_time()) / 60,
+            button("revoke_token", &token_id(&token), "Revoke")
+        );
+    }
+    body += "</ul>";
+
+    body += "<h2>Trusted browsers</h2><ul>";
+    for device in devices::summaries(state, contact_id)? {
+        let text = |key: &str| {
+            device
+                .get(key)
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+                .to_string()
+        };
+        let from = match text("ip").as_str() {
+            "" => String::new(),
+            ip => format!(" from {}", html::escape(ip)),
+        };
+        body += &format!(
+            "<li>{}{from} {}</li>",
+            html::escape(&text("user_agent")),
+            button("revoke_device", &text("id"), "Revoke")
+        );
+    }
+    body += "</ul>";
+
+    body += "<h2>Clients you share data with</h2><ul>";
+    for (client_id, scopes) in consent::grants_of(&state.db, &account.identity)? {
+        body += &format!(
+            "<li>{}: {} {}</li>",
+            html::escape(client_name(state, &client_id)),
+            html::escape(&scopes.join(", ")),
+            button("revoke_grant", &client_id, "Withdraw")
+        );
+    }
+    body += "</ul>";
+
+    body += "<h2>Delete account</h2>\
+        <p>The bot forgets your keys, addresses, logins and grants. \
+        Clients keep what they already know about you.</p>";
+    body += &button("delete", "", "Delete my account");
+    Ok(html::page("Your account", &body))
+}
+
+fn client_name<'a>(state: &'a AppState, client_id: &'a str) -> &'a str {
+    state
+        .config
+        .client(client_id)
+        .and_then(|client| client.name.as_deref())
+        .unwrap_or(client_id)
+}
@@ -0,0 +1,337 @@
+//! Admin group: a Delta Chat group of operators identified by key fingerprint.
+//!
+//! The bot posts alerts and statistics there and accepts commands from admins.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use deltachat::chat::{
+    create_group, get_chat_contacts, remove_contact_from_chat, send_msg, send_text_msg, ChatId,
+};
+use deltachat::contact::{Contact, ContactId};
+use deltachat::message::{Message, MsgId, Viewtype};
+use deltachat::securejoin::get_securejoin_qr;
+use serde::Deserialize;
+
+use crate::access::{is_fingerprint, normalize_fingerprint};
+use crate::{
+    blocks, consent, normalize_addr, registration, stats, subjects, unix_time, webhooks, AppState,
+};
+
+/// Sled tree holding the admin group's chat id and the alert rate limit.
+const ADMIN_TREE: &str = "admin";
+const CHAT_ID_KEY: &str = "chat_id";
+const LAST_ERROR_ALERT_KEY: &str = "last_error_alert";
+
+const ERROR_ALERT_INTERVAL_IN_SECONDS: u64 = 10 * 60;
+
+/// Configuration of the admin group.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct AdminConfig {
+    /// Key fingerprints of the operators allowed in the admin group.
+    ///
+    /// The admin group is only created if this list is non-empty.
+    #[serde(default)]
+    pub fingerprints: Vec<String>,
+    /// Bearer token for the `/admin` HTTP API and the admin CLI.
+    ///
+    /// The API is disabled if unset.
+    pub api_token: Option<String>,
+}
+
+/// Create the admin group if needed and log how admins can join it.
+pub async fn setup_admin_group(state: &AppState) -> Result<()> {
+    let Some(chat_id) = admin_chat(state).await? else {
+        return Ok(());
+    };
+    let link = get_securejoin_qr(&state.dc_context, Some(chat_id)).await?;
+    if get_chat_contacts(&state.dc_context, chat_id).await?.len() <= 1 {
+        log::warn!("The admin group has no admins yet. Admins can join via {link}");
+    } else {
+        log::info!("Admins can join the admin group via {link}");
+    }
+    enforce_members(state, chat_id).await
+}
+
+/// The admin group, created on first use; `None` if no admins are configured.
+pub(crate) async fn admin_chat(state: &AppState) -> Result<Option<ChatId>> {
+    if state.config.admin.fingerprints.is_empty() {
+        return Ok(None);
+    }
+    let tree = state.db.open_tree(ADMIN_TREE)?;
+    if let Some(data) = tree.get(CHAT_ID_KEY)? {
+        return Ok(Some(ChatId::new(u32::from_le_bytes(data[..].try_into()?))));
+    }
+    let chat_id = create_group(&state.dc_context, "Loginbot admins").await?;
+    tree.insert(CHAT_ID_KEY, &chat_id.to_u32().to_le_bytes())?;
+    log::info!("created admin group {chat_id}");
+    Ok(Some(chat_id))
+}
+
+/// Whether `contact_id` holds one of the configured admin keys.
+pub(crate) async fn is_admin(state: &AppState, contact_id: ContactId) -> Result<bool> {
+    let contact = Contact::get_by_id(&state.dc_context, contact_id).await?;
+    let Some(fp) = contact.fingerprint() else {
+        return Ok(false);
+    };
+    let fp = fp.hex();
+    Ok(state
+        .config
+        .admin
+        .fingerprints
+        .iter()
+        .any(|admin_fp| normalize_fingerprint(admin_fp) == fp))
+}
+
+/// Remove everyone from the admin group whose key is not an admin key.
+pub(crate) async fn enforce_members(state: &AppState, chat_id: ChatId) -> Result<()> {
+    if admin_chat(state).await? != Some(chat_id) {
+        return Ok(());
+    }
+    for member in get_chat_contacts(&state.dc_context, chat_id).await? {
+        if member != ContactId::SELF && !is_admin(state, member).await? {
+            log::warn!("removing non-admin {member} from the admin group");
+            remove_contact_from_chat(&state.dc_context, chat_id, member).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Post `text` to the admin group, if there is one.
+pub(crate) async fn post(state: &AppState, text: &str) -> Result<Option<MsgId>> {
+    let Some(chat_id) = admin_chat(state).await? else {
+        return Ok(None);
+    };
+    Ok(Some(
+        send_text_msg(&state.dc_context, chat_id, text.to_string()).await?,
+    ))
+}
+
+/// Notify the operators: log `text` and post it to the admin group.
+///
+/// Failing to post is only logged, so alerts never break the calling code.
+pub(crate) async fn alert(state: &AppState, text: &str) {
+    log::warn!("ALERT: {text}");
+    if let Err(err) = post(state, &format!("⚠️ {text}")).await {
+        log::error!("failed to post alert to the admin group: {err:#}");
+    }
+}
+
+/// Forward a Delta Chat core error, at most once per [`ERROR_ALERT_INTERVAL_IN_SECONDS`].
+pub(crate) async fn alert_core_error(state: &AppState, text: &str) -> Result<()> {
+    let tree = state.db.open_tree(ADMIN_TREE)?;
+    let now = unix_time();
+    let last = tree
+        .get(LAST_ERROR_ALERT_KEY)?
+        .and_then(|data| data.as_ref().try_into().ok())
+        .map(u64::from_le_bytes)
+        .unwrap_or_default();
+    if now.saturating_sub(last) < ERROR_ALERT_INTERVAL_IN_SECONDS {
+        return Ok(());
+    }
+    tree.insert(LAST_ERROR_ALERT_KEY, &now.to_le_bytes())?;
+    alert(state, &format!("Delta Chat error: {text}")).await;
+    Ok(())
+}
+
+/// Post the counters of the past day to the admin group, once a day.
+pub async fn run_daily_stats(state: AppState) {
+    let mut interval = tokio::time::interval(Duration::from_secs(24 * 60 * 60));
+    // The first tick completes immediately.
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let res = async {
+            let text = format!("Daily statistics:\n{}", stats_text(&state, true)?);
+            post(&state, &text).await
+        }
+        .await;
+        if let Err(err) = res {
+            log::error!("failed to post daily statistics: {err:#}");
+        }
+    }
+}
+
+/// Handle a message an admin sent to the admin group.
+pub(crate) async fn handle_message(state: &AppState, msg: &Message) -> Result<()> {
+    if !is_admin(state, msg.get_from_id()).await? {
+        return Ok(());
+    }
+    let text = msg.get_text();
+    let text = text.trim();
+    if let Some(quoted) = msg.quoted_message(&state.dc_context).await? {
+        if let Some(approve) = parse_decision(text) {
+            registration::decide(state, quoted.get_id(), approve, msg.get_from_id()).await?;
+        }
+        return Ok(());
+    }
+    if let Some(reply) = run_command(state, msg.get_from_id(), text).await? {
+        let mut answer = Message::new(Viewtype::Text);
+        answer.set_text(reply);
+        answer.set_quote(&state.dc_context, Some(msg)).await?;
+        send_msg(&state.dc_context, msg.get_chat_id(), &mut answer).await?;
+    }
+    Ok(())
+}
+
+/// Execute an admin command and return the reply, or `None` for non-commands.
+async fn run_command(state: &AppState, admin_id: ContactId, text: &str) -> Result<Option<String>> {
+    let mut args = text.split_whitespace();
+    let Some(command) = args.next().filter(|command| command.starts_with('/')) else {
+        return Ok(None);
+    };
+    let arg = args.next();
+    let rest = args.collect::<Vec<_>>().join(" ");
+    let reply = match (command, arg) {
+        ("/invite", _) => format!(
+            "New invite code: {}",
+            registration::create_invite(&state.db)?
+        ),
+        ("/stats", _) => stats_text(state, false)?,
+        ("/block", Some(key)) => {
+            let (duration, reason) = match rest.split_once(' ').unwrap_or((&rest, "")) {
+                (first, reason) if blocks::parse_duration(first).is_ok() => {
+                    (Some(blocks::parse_duration(first)?), reason.to_string())
+                }
+                _ => (None, rest.clone()),
+            };
+            let reason = Some(reason).filter(|reason| !reason.is_empty());
+            let revoked = blocks::block(state, key, reason, duration).await?;
+            let admin = Contact::get_by_id(&state.dc_context, admin_id).await?;
+            log::info!("{} blocked {key}", admin.get_addr());
+            format!("Blocked {key}, revoked {revoked} codes and tokens.")
+        }
+        ("/unblock", Some(key)) => match blocks::unblock(&state.db, key)? {
+            true => format!("Unblocked {key}."),
+            false => format!("{key} was not blocked."),
+        },
+        ("/lookup", Some(key)) => lookup_text(state, key)?,
+        ("/remap", Some(fp)) if !is_fingerprint(fp) => {
+            format!("{fp:?} is not a valid fingerprint.\n/remap <fingerprint> <addr>")
+        }
+        ("/remap", Some(fp)) => match normalize_addr(&rest) {
+            Some(addr) => remap(state, fp, &addr)?,
+            None => format!("{rest:?} is not a valid address.\n/remap <fingerprint> <addr>"),
+        },
+        _ => HELP.to_string(),
+    };
+    Ok(Some(reply))
+}
+
+/// Make `addr` the canonical address of the key `fp`; returns the reply.
+fn remap(state: &AppState, fp: &str, addr: &str) -> Result<String> {
+    let fp = normalize_fingerprint(fp);
+    let id_tree = state.db.open_tree("identities")?;
+    Ok(match id_tree.insert(&fp, addr.as_bytes())? {
+        Some(old) => {
+            let previous = String::from_utf8_lossy(&old).into_owned();
+            // The grants and the subject follow the identity once none of its keys is left.
+            let mut orphaned = true;
+            for entry in &id_tree {
+                orphaned &= entry?.1.as_ref() != old.as_ref();
+            }
+            if orphaned {
+                consent::move_grants(&state.db, &previous, addr)?;
+                subjects::move_to(&state.db, &previous, addr)?;
+            }
+            webhooks::fire(
+                state,
+                webhooks::Event::AddressChanged {
+                    identity: addr.to_string(),
+                    previous: previous.clone(),
+                },
+            )?;
+            format!("Canonical address of {fp} changed from {previous} to {addr}.")
+        }
+        None => format!("Canonical address of {fp} set to {addr}."),
+    })
+}
+
+const HELP: &str = "Commands:
+/stats – show today's statistics
+/invite – create a single-use invite code
+/block <addr|fingerprint> [duration] [reason] – block a user, e.g. for 7d
+/unblock <addr|fingerprint> – lift a block
+/lookup <addr|fingerprint> – show what is known about a user
+/remap <fingerprint> <addr> – change the canonical address of a key";
+
+/// Today's counters plus totals; `reset` starts a new day.
+fn stats_text(state: &AppState, reset: bool) -> Result<String> {
+    let mut text = String::new();
+    for (counter, value) in stats::snapshot(&state.db, reset)? {
+        text += &format!("{counter}: {value}\n");
+    }
+    text += &format!(
+        "registered keys: {}\nblocked: {}",
+        state.db.open_tree("identities")?.len(),
+        blocks::count(&state.db)?
+    );
+    Ok(text)
+}
+
+/// Describe the identities matching an address or fingerprint.
+fn lookup_text(state: &AppState, key: &str) -> Result<String> {
+    let id_tree = state.db.open_tree("identities")?;
+    let mut lines = Vec::new();
+    if key.contains('@') {
+        let addr = key.to_lowercase();
+        if let Some(fp) = state.db.open_tree("addr_fingerprints")?.get(&addr)? {
+            lines.push(format!(
+                "{addr} last logged in with key {}",
+                String::from_utf8_lossy(&fp)
+            ));
+        }
+        for entry in &id_tree {
+            let (fp, canonical) = entry?;
+            if String::from_utf8_lossy(&canonical).eq_ignore_ascii_case(&addr) {
+                lines.push(format!(
+                    "key {} has canonical address {addr}",
+                    String::from_utf8_lossy(&fp)
+                ));
+            }
+        }
+    } else {
+        let fp = normalize_fingerprint(key);
+        if let Some(canonical) = id_tree.get(&fp)? {
+            lines.push(format!(
+                "key {fp} has canonical address {}",
+                String::from_utf8_lossy(&canonical)
+            ));
+        }
+    }
+    for block in blocks::get(&state.db, key)? {
+        lines.push(format!("{key} is blocked: {}", blocks::describe(&block)));
+    }
+    if lines.is_empty() {
+        lines.push(format!("Nothing known about {key}."));
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Handle an admin's reaction to one of the bot's messages.
+pub(crate) async fn handle_reaction(
+    state: &AppState,
+    contact_id: ContactId,
+    msg_id: MsgId,
+    reaction: &str,
+) -> Result<()> {
+    if !is_admin(state, contact_id).await? {
+        return Ok(());
+    }
+    if let Some(approve) = parse_decision(reaction) {
+        registration::decide(state, msg_id, approve, contact_id).await?;
+    }
+    Ok(())
+}
+
+/// `Some(true)` for an approval, `Some(false)` for a rejection.
+fn parse_decision(text: &str) -> Option<bool> {
+    let text = text.trim().to_lowercase();
+    if text.starts_with("approve") || text.starts_with('👍') {
+        Some(true)
+    } else if text.starts_with("reject") || text.starts_with('👎') {
+        Some(false)
+    } else {
+        None
+    }
+}
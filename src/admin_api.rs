@@ -0,0 +1,209 @@
+//! Admin HTTP API under `/admin`, authenticated with `[admin] api_token`.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::{get, put},
+    Json, Router,
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{access, blocks, secrets_match, unix_time, AppError, AppState};
+
+/// Body of `PUT /admin/blocks/:key`.
+#[derive(Debug, Default, Deserialize)]
+struct BlockRequest {
+    reason: Option<String>,
+    /// How long the block lasts, e.g. `"7d"`; forever if absent.
+    duration: Option<String>,
+    /// Unix time the block ends, instead of a `duration`.
+    until: Option<u64>,
+}
+
+/// Routes of the admin API, to be nested under `/admin`.
+pub(crate) fn router() -> Router<AppState> {
+    Router::new()
+        .route("/blocks", get(get_blocks))
+        .route(
+            "/blocks/:key",
+            get(get_block).put(put_block).delete(delete_block),
+        )
+        .route("/lists", get(get_lists))
+        .route("/lists/:name", get(get_list))
+        .route(
+            "/lists/:name/:key",
+            put(put_list_member).delete(delete_list_member),
+        )
+}
+
+type ApiResult = Result<(StatusCode, Json<Value>), AppError>;
+
+/// `Err` with the response to send if the request lacks the admin API token.
+fn authorize(
+    state: &AppState,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<(), (StatusCode, Json<Value>)> {
+    let Some(api_token) = state.config.admin.api_token.as_deref() else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "the admin API is disabled" })),
+        ));
+    };
+    match auth {
+        Some(TypedHeader(auth)) if secrets_match(auth.token(), api_token) => Ok(()),
+        _ => {
+            log::info!("admin API returned 401 because the token was missing or wrong");
+            Err((
+                StatusCode::UNAUTHORIZED,
+                Json(json!({ "error": "invalid admin API token" })),
+            ))
+        }
+    }
+}
+
+fn block_json(key: &str, block: &blocks::Block) -> Value {
+    json!({
+        "key": key,
+        "reason": block.reason,
+        "created": block.created,
+        "expires": block.expires,
+        "client_id": block.client_id,
+    })
+}
+
+async fn get_blocks(
+    State(state): State<AppState>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+) -> ApiResult {
+    if let Err(response) = authorize(&state, auth) {
+        return Ok(response);
+    }
+    let blocks = blocks::list(&state.db)?
+        .iter()
+        .map(|(key, block)| block_json(key, block))
+        .collect::<Vec<_>>();
+    Ok((StatusCode::OK, Json(json!({ "blocks": blocks }))))
+}
+
+async fn get_block(
+    State(state): State<AppState>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Path(key): Path<String>,
+) -> ApiResult {
+    if let Err(response) = authorize(&state, auth) {
+        return Ok(response);
+    }
+    let blocks = blocks::get(&state.db, &key)?;
+    if blocks.is_empty() {
+        return Ok((
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": format!("{key} is not blocked") })),
+        ));
+    }
+    let blocks = blocks
+        .iter()
+        .map(|block| block_json(&key, block))
+        .collect::<Vec<_>>();
+    Ok((StatusCode::OK, Json(json!({ "blocks": blocks }))))
+}
+
+async fn put_block(
+    State(state): State<AppState>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Path(key): Path<String>,
+    body: Option<Json<BlockRequest>>,
+) -> ApiResult {
+    if let Err(response) = authorize(&state, auth) {
+        return Ok(response);
+    }
+    let Json(request) = body.unwrap_or_default();
+    let bad_request =
+        |error: String| Ok((StatusCode::BAD_REQUEST, Json(json!({ "error": error }))));
+    let duration = match (request.duration.as_deref(), request.until) {
+        (Some(_), Some(_)) => return bad_request("give duration or until, not both".to_string()),
+        (Some(duration), None) => match blocks::parse_duration(duration) {
+            Ok(duration) => Some(duration),
+            Err(err) => return bad_request(format!("{err:#}")),
+        },
+        (None, Some(until)) if until <= unix_time() => {
+            return bad_request("until is in the past".to_string())
+        }
+        (None, Some(until)) => Some(until.saturating_sub(unix_time())),
+        (None, None) => None,
+    };
+    let revoked = blocks::block(&state, &key, request.reason, duration).await?;
+    log::info!("admin API blocked {key}");
+    Ok((StatusCode::OK, Json(json!({ "revoked": revoked }))))
+}
+
+async fn delete_block(
+    State(state): State<AppState>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Path(key): Path<String>,
+) -> ApiResult {
+    if let Err(response) = authorize(&state, auth) {
+        return Ok(response);
+    }
+    let unblocked = blocks::unblock(&state.db, &key)?;
+    log::info!("admin API unblocked {key}");
+    Ok((StatusCode::OK, Json(json!({ "unblocked": unblocked }))))
+}
+
+async fn get_lists(
+    State(state): State<AppState>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+) -> ApiResult {
+    if let Err(response) = authorize(&state, auth) {
+        return Ok(response);
+    }
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "lists": access::list_names(&state.db)? })),
+    ))
+}
+
+async fn get_list(
+    State(state): State<AppState>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Path(name): Path<String>,
+) -> ApiResult {
+    if let Err(response) = authorize(&state, auth) {
+        return Ok(response);
+    }
+    let members = access::list_members(&state.db, &name)?;
+    Ok((
+        StatusCode::OK,
+        Json(json!({ "name": name, "members": members })),
+    ))
+}
+
+async fn put_list_member(
+    State(state): State<AppState>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Path((name, key)): Path<(String, String)>,
+) -> ApiResult {
+    if let Err(response) = authorize(&state, auth) {
+        return Ok(response);
+    }
+    let added = access::list_add(&state.db, &name, &key)?;
+    log::info!("admin API added {key} to list {name}");
+    Ok((StatusCode::OK, Json(json!({ "added": added }))))
+}
+
+async fn delete_list_member(
+    State(state): State<AppState>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Path((name, key)): Path<(String, String)>,
+) -> ApiResult {
+    if let Err(response) = authorize(&state, auth) {
+        return Ok(response);
+    }
+    let removed = access::list_remove(&state.db, &name, &key)?;
+    log::info!("admin API removed {key} from list {name}");
+    Ok((StatusCode::OK, Json(json!({ "removed": removed }))))
+}
@@ -0,0 +1,256 @@
+//! Login approval app: a webxdc app the bot sends into the user's chat.
+//!
+//! The bot publishes the user's pending login requests, trusted browsers
+//! and recent logins as status updates; the app sends back decisions and
+//! revocations. Each user has one app instance, reused for all logins.
+
+use std::io::Write as _;
+
+use anyhow::{Context as _, Result};
+use deltachat::chat::{send_msg, ChatId};
+use deltachat::contact::ContactId;
+use deltachat::message::{Message, MsgId, Viewtype};
+use deltachat::webxdc::StatusUpdateSerial;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{confirmation, devices, unix_time, AppState};
+
+/// Sled tree mapping a contact id (u32 le) to its app's message id (u32 le).
+const APPS_TREE: &str = "approval_apps";
+
+/// Sled tree of [`LoginRecord`]s, keyed by contact id (u32 be) and a sled id.
+const HISTORY_TREE: &str = "login_history";
+
+/// Number of recent logins kept per user.
+const HISTORY_LENGTH: usize = 10;
+
+const INDEX_HTML: &str = include_str!("../webxdc/approval/index.html");
+const MANIFEST_TOML: &str = include_str!("../webxdc/approval/manifest.toml");
+
+/// A completed login, shown in the app and on the account page.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct LoginRecord {
+    pub client: String,
+    pub origin: String,
+    /// `securejoin` or `email`, like the `amr` claim.
+    pub method: String,
+    pub time: u64,
+}
+
+/// A status update sent by the app.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Command {
+    /// Approve or deny the login in login group `id`.
+    Decide { id: u32, approve: bool },
+    /// Stop trusting the browser `id`.
+    Revoke { id: String },
+    /// Status published by the bot itself.
+    State,
+}
+
+/// Status update as returned by `get_webxdc_status_updates`.
+#[derive(Debug, Deserialize)]
+struct StatusUpdate {
+    payload: Value,
+    serial: u32,
+}
+
+/// Publish the current state to the app of `contact_id`.
+///
+/// With `create`, the app is sent to the user first if they do not have one.
+pub(crate) async fn publish(state: &AppState, contact_id: ContactId, create: bool) -> Result<()> {
+    let msg_id = match app_of(state, contact_id)? {
+        Some(msg_id) => msg_id,
+        None if create => send_app(state, contact_id).await?,
+        None => return Ok(()),
+    };
+    let update = json!({
+        "payload": {
+            "type": "state",
+            "requests": confirmation::pending_approvals(&state.db, contact_id)?,
+            "devices": devices::summaries(state, contact_id)?,
+            "logins": history(&state.db, contact_id)?,
+        }
+    });
+    state
+        .dc_context
+        .send_webxdc_status_update(msg_id, &update.to_string())
+        .await?;
+    Ok(())
+}
+
+/// The recent logins of `contact_id`, newest first.
+pub(crate) fn history(db: &sled::Db, contact_id: ContactId) -> Result<Vec<LoginRecord>> {
+    let mut logins = Vec::new();
+    for entry in db
+        .open_tree(HISTORY_TREE)?
+        .scan_prefix(contact_id.to_u32().to_be_bytes())
+        .rev()
+    {
+        let (_, data) = entry?;
+        logins.push(serde_json::from_slice::<LoginRecord>(&data)?);
+    }
+    Ok(logins)
+}
+
+/// Forget the login history and the app of `contact_id`.
+pub(crate) fn forget(db: &sled::Db, contact_id: ContactId) -> Result<()> {
+    let history = db.open_tree(HISTORY_TREE)?;
+    for key in history
+        .scan_prefix(contact_id.to_u32().to_be_bytes())
+        .keys()
+    {
+        history.remove(key?)?;
+    }
+    db.open_tree(APPS_TREE)?
+        .remove(contact_id.to_u32().to_le_bytes())?;
+    Ok(())
+}
+
+/// Remember a completed login of `contact_id` and show it in the app.
+pub(crate) async fn record_login(
+    state: &AppState,
+    contact_id: ContactId,
+    client: &str,
+    origin: &str,
+    method: &str,
+) -> Result<()> {
+    let history = state.db.open_tree(HISTORY_TREE)?;
+    let prefix = contact_id.to_u32().to_be_bytes();
+    let mut key = prefix.to_vec();
+    key.extend_from_slice(&state.db.generate_id()?.to_be_bytes());
+    let record = LoginRecord {
+        client: client.to_string(),
+        origin: origin.to_string(),
+        method: method.to_string(),
+        time: unix_time(),
+    };
+    history.insert(key, serde_json::to_vec(&record)?)?;
+    let keys = history
+        .scan_prefix(prefix)
+        .keys()
+        .collect::<Result<Vec<_>, _>>()?;
+    for old in keys.iter().rev().skip(HISTORY_LENGTH) {
+        history.remove(old)?;
+    }
+    publish(state, contact_id, false).await
+}
+
+/// Act on status update `serial` of the app `msg_id`.
+pub(crate) async fn handle_status_update(
+    state: &AppState,
+    msg_id: MsgId,
+    serial: StatusUpdateSerial,
+) -> Result<()> {
+    let Some(contact_id) = owner_of(state, msg_id)? else {
+        return Ok(());
+    };
+    let since = StatusUpdateSerial::new(serial.to_u32().saturating_sub(1));
+    let updates: Vec<StatusUpdate> = serde_json::from_str(
+        &state
+            .dc_context
+            .get_webxdc_status_updates(msg_id, since)
+            .await?,
+    )?;
+    let Some(update) = updates
+        .into_iter()
+        .find(|update| update.serial == serial.to_u32())
+    else {
+        return Ok(());
+    };
+    match serde_json::from_value::<Command>(update.payload) {
+        Ok(Command::Decide { id, approve }) => {
+            let group_id = ChatId::new(id);
+            if confirmation::decide(state, group_id, contact_id, approve).await? {
+                crate::advance_group_flow(state, group_id).await?;
+            }
+        }
+        Ok(Command::Revoke { id }) => {
+            devices::revoke(&state.db, contact_id.to_u32(), &id)?;
+        }
+        Ok(Command::State) => return Ok(()),
+        Err(err) => {
+            log::warn!("ignoring unknown update from approval app {msg_id:?}: {err}");
+            return Ok(());
+        }
+    }
+    publish(state, contact_id, false).await
+}
+
+/// Answer `/app` in `msg` by sending a fresh approval app.
+pub(crate) async fn handle_message(state: &AppState, msg: &Message) -> Result<()> {
+    if msg.get_text().trim() != "/app" {
+        return Ok(());
+    }
+    send_app(state, msg.get_from_id()).await?;
+    publish(state, msg.get_from_id(), false).await
+}
+
+/// The message id of `contact_id`'s app, if it has one.
+fn app_of(state: &AppState, contact_id: ContactId) -> Result<Option<MsgId>> {
+    let data = state
+        .db
+        .open_tree(APPS_TREE)?
+        .get(contact_id.to_u32().to_le_bytes())?;
+    match data {
+        Some(data) => Ok(Some(MsgId::new(u32::from_le_bytes(
+            data.as_ref().try_into()?,
+        )))),
+        None => Ok(None),
+    }
+}
+
+/// The user whose app is `msg_id`.
+fn owner_of(state: &AppState, msg_id: MsgId) -> Result<Option<ContactId>> {
+    for entry in &state.db.open_tree(APPS_TREE)? {
+        let (contact_id, app) = entry?;
+        if app.as_ref() == msg_id.to_u32().to_le_bytes() {
+            return Ok(Some(ContactId::new(u32::from_le_bytes(
+                contact_id.as_ref().try_into()?,
+            ))));
+        }
+    }
+    Ok(None)
+}
+
+/// Send a new app into the chat with `contact_id` and make it the current one.
+async fn send_app(state: &AppState, contact_id: ContactId) -> Result<MsgId> {
+    let mut file = tempfile::Builder::new()
+        .prefix("loginbot-approval-")
+        .suffix(".xdc")
+        .tempfile()?;
+    file.write_all(&xdc()?)
+        .context("cannot write the approval app")?;
+    let chat_id = ChatId::create_for_contact(&state.dc_context, contact_id).await?;
+    let mut msg = Message::new(Viewtype::Webxdc);
+    msg.set_text("Approve logins and manage your trusted browsers here.".to_string());
+    msg.set_file_and_deduplicate(
+        &state.dc_context,
+        file.path(),
+        Some("login-approval.xdc"),
+        None,
+    )?;
+    // The core copied the app into its blob directory.
+    file.close()?;
+    let msg_id = send_msg(&state.dc_context, chat_id, &mut msg).await?;
+    state.db.open_tree(APPS_TREE)?.insert(
+        contact_id.to_u32().to_le_bytes(),
+        &msg_id.to_u32().to_le_bytes(),
+    )?;
+    log::info!("sent the approval app to {contact_id}");
+    Ok(msg_id)
+}
+
+/// The approval app packaged as a `.xdc` archive.
+fn xdc() -> Result<Vec<u8>> {
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let options =
+        zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    for (name, content) in [("index.html", INDEX_HTML), ("manifest.toml", MANIFEST_TOML)] {
+        zip.start_file(name, options)?;
+        zip.write_all(content.as_bytes())?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
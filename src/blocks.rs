@@ -0,0 +1,445 @@
+//! Block list of addresses and key fingerprints that may not log in.
+
+use anyhow::{bail, Result};
+use deltachat::contact::{Contact, ContactId};
+use serde::{Deserialize, Serialize};
+
+use crate::access::normalize_key;
+use crate::{logout, unix_time, webhooks, AppState};
+
+/// Sled tree mapping a blocked address or fingerprint to its [`Block`].
+const BLOCKED_TREE: &str = "blocked";
+
+/// Sled tree mapping `<client_id>/<address or fingerprint>` to a [`Block`]
+/// a client created for logins to itself.
+const CLIENT_BLOCKED_TREE: &str = "client_blocked";
+
+/// A block list entry.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Block {
+    /// Why the user was blocked, shown to admins.
+    pub reason: Option<String>,
+    /// Unix time the block was created.
+    pub created: u64,
+    /// Unix time the block ends; `None` blocks forever.
+    #[serde(default)]
+    pub expires: Option<u64>,
+    /// The client that created the block, which then only applies to logins
+    /// to that client and only that client can lift; `None` for blocks by admins.
+    #[serde(default)]
+    pub client_id: Option<String>,
+}
+
+impl Block {
+    fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= unix_time())
+    }
+}
+
+/// The part of an auth code, CAS ticket, access token, device, forward auth
+/// session, magic link or completed login flow record that names the user.
+#[derive(Deserialize)]
+struct ContactRecord {
+    contact_id: u32,
+    /// The client the record logs in to; devices and sessions have none.
+    #[serde(default)]
+    client_id: Option<String>,
+}
+
+/// Block `key` (an address or fingerprint) for `duration` seconds, or forever.
+///
+/// Outstanding auth codes, CAS tickets, access tokens, trusted browsers,
+/// forward auth sessions, magic links and completed login flows of the user
+/// are revoked and clients are told to log the user out; returns how many.
+pub(crate) async fn block(
+    state: &AppState,
+    key: &str,
+    reason: Option<String>,
+    duration: Option<u64>,
+) -> Result<usize> {
+    let created = unix_time();
+    let entry = Block {
+        reason,
+        created,
+        expires: duration.map(|duration| created.saturating_add(duration)),
+        client_id: None,
+    };
+    state
+        .db
+        .open_tree(BLOCKED_TREE)?
+        .insert(normalize_key(key), serde_json::to_vec(&entry)?)?;
+    let revoked = revoke(state, key, None).await?;
+    log::info!("blocked {key}, revoked {revoked} codes and tokens");
+    if let Some(identity) = identity_of(&state.db, key)? {
+        logout::notify_clients(state, &identity)?;
+        webhooks::fire(state, webhooks::Event::Blocked { identity })?;
+    }
+    Ok(revoked)
+}
+
+/// The canonical address of the user an address or fingerprint `key` belongs to.
+fn identity_of(db: &sled::Db, key: &str) -> Result<Option<String>> {
+    let key = normalize_key(key);
+    let fp = match key.contains('@') {
+        true => match db.open_tree("addr_fingerprints")?.get(&key)? {
+            Some(fp) => fp.to_vec(),
+            None => return Ok(Some(key)),
+        },
+        false => key.into_bytes(),
+    };
+    Ok(db
+        .open_tree("identities")?
+        .get(fp)?
+        .map(|addr| String::from_utf8_lossy(&addr).into_owned()))
+}
+
+/// Block `key` (an address or fingerprint) for logins to `client_id`, on
+/// behalf of that client.
+///
+/// Outstanding auth codes, CAS tickets, access tokens, magic links and
+/// completed login flows of the user for that client are revoked; returns how many.
+pub(crate) async fn block_for_client(
+    state: &AppState,
+    client_id: &str,
+    key: &str,
+    reason: Option<String>,
+) -> Result<usize> {
+    store_client_block(&state.db, client_id, key, reason)?;
+    let revoked = revoke_for_client(state, client_id, key).await?;
+    log::info!("{client_id} blocked {key}, revoked {revoked} codes and tokens");
+    Ok(revoked)
+}
+
+fn client_block_key(client_id: &str, key: &str) -> String {
+    format!("{client_id}/{}", normalize_key(key))
+}
+
+fn store_client_block(
+    db: &sled::Db,
+    client_id: &str,
+    key: &str,
+    reason: Option<String>,
+) -> Result<()> {
+    let entry = Block {
+        reason,
+        created: unix_time(),
+        expires: None,
+        client_id: Some(client_id.to_string()),
+    };
+    db.open_tree(CLIENT_BLOCKED_TREE)?.insert(
+        client_block_key(client_id, key),
+        serde_json::to_vec(&entry)?,
+    )?;
+    Ok(())
+}
+
+/// Lift the block `client_id` created for `key`; blocks of admins and other
+/// clients stay. Returns `false` if there was none.
+pub(crate) fn unblock_for_client(db: &sled::Db, client_id: &str, key: &str) -> Result<bool> {
+    Ok(db
+        .open_tree(CLIENT_BLOCKED_TREE)?
+        .remove(client_block_key(client_id, key))?
+        .is_some())
+}
+
+/// Lift every block of `key`, including those clients created.
+/// Returns `false` if it was not blocked.
+pub(crate) fn unblock(db: &sled::Db, key: &str) -> Result<bool> {
+    let key = normalize_key(key);
+    let mut unblocked = db.open_tree(BLOCKED_TREE)?.remove(&key)?.is_some();
+    let client_blocks = db.open_tree(CLIENT_BLOCKED_TREE)?;
+    let suffix = format!("/{key}");
+    for entry in &client_blocks {
+        let (client_key, _) = entry?;
+        if client_key.ends_with(suffix.as_bytes()) {
+            client_blocks.remove(client_key)?;
+            unblocked = true;
+        }
+    }
+    Ok(unblocked)
+}
+
+/// The admins' block entry for `key`, if any. Expired entries are removed.
+fn admin_block(db: &sled::Db, key: &str) -> Result<Option<Block>> {
+    let tree = db.open_tree(BLOCKED_TREE)?;
+    let key = normalize_key(key);
+    let Some(data) = tree.get(&key)? else {
+        return Ok(None);
+    };
+    let block: Block = serde_json::from_slice(&data)?;
+    if block.is_expired() {
+        tree.remove(&key)?;
+        return Ok(None);
+    }
+    Ok(Some(block))
+}
+
+/// The block entries for `key`: the admins' block first, then those clients created.
+pub(crate) fn get(db: &sled::Db, key: &str) -> Result<Vec<Block>> {
+    let key = normalize_key(key);
+    let mut blocks: Vec<Block> = admin_block(db, &key)?.into_iter().collect();
+    for (blocked, block) in client_blocks(db)? {
+        if blocked == key {
+            blocks.push(block);
+        }
+    }
+    Ok(blocks)
+}
+
+/// All active blocks, keyed by address or fingerprint, including those clients created.
+pub(crate) fn list(db: &sled::Db) -> Result<Vec<(String, Block)>> {
+    let mut blocks = Vec::new();
+    for entry in &db.open_tree(BLOCKED_TREE)? {
+        let (key, data) = entry?;
+        let block: Block = serde_json::from_slice(&data)?;
+        if !block.is_expired() {
+            blocks.push((String::from_utf8(key.to_vec())?, block));
+        }
+    }
+    blocks.extend(client_blocks(db)?);
+    Ok(blocks)
+}
+
+/// The blocks clients created, keyed by address or fingerprint.
+fn client_blocks(db: &sled::Db) -> Result<Vec<(String, Block)>> {
+    let mut blocks = Vec::new();
+    for entry in &db.open_tree(CLIENT_BLOCKED_TREE)? {
+        let (key, data) = entry?;
+        let key = String::from_utf8(key.to_vec())?;
+        let block: Block = serde_json::from_slice(&data)?;
+        let blocked = block
+            .client_id
+            .as_ref()
+            .and_then(|client_id| key.strip_prefix(&format!("{client_id}/")))
+            .unwrap_or(&key)
+            .to_string();
+        blocks.push((blocked, block));
+    }
+    Ok(blocks)
+}
+
+/// Whether `addr` or `fingerprint` is blocked.
+pub(crate) fn is_blocked(db: &sled::Db, addr: &str, fingerprint: Option<&str>) -> Result<bool> {
+    if admin_block(db, addr)?.is_some() {
+        return Ok(true);
+    }
+    match fingerprint {
+        Some(fingerprint) => Ok(admin_block(db, fingerprint)?.is_some()),
+        None => Ok(false),
+    }
+}
+
+/// Whether `addr` or `fingerprint` is blocked everywhere or for logins to `client_id`.
+pub(crate) fn is_blocked_for(
+    db: &sled::Db,
+    client_id: &str,
+    addr: &str,
+    fingerprint: Option<&str>,
+) -> Result<bool> {
+    if is_blocked(db, addr, fingerprint)? {
+        return Ok(true);
+    }
+    let client_blocks = db.open_tree(CLIENT_BLOCKED_TREE)?;
+    for key in std::iter::once(addr).chain(fingerprint) {
+        if client_blocks.contains_key(client_block_key(client_id, key))? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Number of active blocks.
+pub(crate) fn count(db: &sled::Db) -> Result<usize> {
+    Ok(list(db)?.len())
+}
+
+/// Trees whose records are a [`ContactRecord`] granting or continuing a login.
+const LOGIN_RECORD_TREES: [&str; 7] = [
+    "default",
+    "tokens",
+    "devices",
+    "forward_auth_sessions",
+    "cas_tickets",
+    "magic_links",
+    "flows",
+];
+
+/// Remove the auth codes, access tokens, trusted browsers, sessions, links and
+/// completed login flows of the users matching `key`, only those for
+/// `client_id` if given.
+async fn revoke(state: &AppState, key: &str, client_id: Option<&str>) -> Result<usize> {
+    let key = normalize_key(key);
+    let mut revoked: usize = 0;
+    for tree_name in LOGIN_RECORD_TREES {
+        let tree = state.db.open_tree(tree_name)?;
+        for entry in &tree {
+            let (record_key, data) = entry?;
+            let Ok(record) = serde_json::from_slice::<ContactRecord>(&data) else {
+                continue;
+            };
+            if client_id.is_some_and(|client_id| record.client_id.as_deref() != Some(client_id)) {
+                continue;
+            }
+            // The block is stored already; one bad record must not keep the
+            // others alive.
+            let contact = match Contact::get_by_id(
+                &state.dc_context,
+                ContactId::new(record.contact_id),
+            )
+            .await
+            {
+                Ok(contact) => contact,
+                Err(err) => {
+                    log::warn!(
+                        "skipping login record of unreadable contact {}: {err:#}",
+                        record.contact_id
+                    );
+                    continue;
+                }
+            };
+            let matches = contact.get_addr().eq_ignore_ascii_case(&key)
+                || contact.fingerprint().is_some_and(|fp| fp.hex() == key);
+            if matches {
+                tree.remove(record_key)?;
+                revoked = revoked.saturating_add(1);
+            }
+        }
+    }
+    Ok(revoked)
+}
+
+/// Remove the auth codes, CAS tickets, access tokens, magic links and completed
+/// login flows for `client_id` of the users matching `key`; returns how many.
+pub(crate) async fn revoke_for_client(
+    state: &AppState,
+    client_id: &str,
+    key: &str,
+) -> Result<usize> {
+    revoke(state, key, Some(client_id)).await
+}
+
+/// Remove the auth codes, access tokens, trusted browsers, sessions, links and
+/// completed login flows of `contact_id`; returns how many.
+pub(crate) fn revoke_contact(db: &sled::Db, contact_id: u32) -> Result<usize> {
+    let mut revoked: usize = 0;
+    for tree_name in LOGIN_RECORD_TREES {
+        let tree = db.open_tree(tree_name)?;
+        for entry in &tree {
+            let (record_key, data) = entry?;
+            let owned = serde_json::from_slice::<ContactRecord>(&data)
+                .is_ok_and(|record| record.contact_id == contact_id);
+            if owned {
+                tree.remove(record_key)?;
+                revoked = revoked.saturating_add(1);
+            }
+        }
+    }
+    Ok(revoked)
+}
+
+/// Parse a duration such as `30m`, `12h`, `7d` or `2w` into seconds.
+pub(crate) fn parse_duration(text: &str) -> Result<u64> {
+    let Some(unit) = text.chars().last() else {
+        bail!("empty duration");
+    };
+    let number = text.strip_suffix(unit).unwrap_or(text);
+    let factor: u64 = match unit {
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => bail!("unknown duration unit in {text:?}, use m, h, d or w"),
+    };
+    number
+        .parse::<u64>()?
+        .checked_mul(factor)
+        .ok_or_else(|| anyhow::anyhow!("duration {text:?} is too long"))
+}
+
+/// Describe the reason, the client and the remaining time of `block` for humans.
+pub(crate) fn describe(block: &Block) -> String {
+    let reason = match &block.client_id {
+        Some(client_id) => format!(
+            "{} (for logins to {client_id})",
+            block.reason.as_deref().unwrap_or("no reason given")
+        ),
+        None => block
+            .reason
+            .as_deref()
+            .unwrap_or("no reason given")
+            .to_string(),
+    };
+    match block.expires {
+        Some(expires) => {
+            let hours = expires.saturating_sub(unix_time()) / (60 * 60);
+            format!("{reason} (ends in {hours} hours)")
+        }
+        None => reason,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_blocks() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let addr = "alice@example.org";
+        store_client_block(&db, "forum", "AAAA BBBB", Some("suspended".to_string())).unwrap();
+        assert!(is_blocked_for(&db, "forum", addr, Some("aaaabbbb")).unwrap());
+        let listed = list(&db).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].0, "AAAABBBB");
+        assert_eq!(listed[0].1.client_id.as_deref(), Some("forum"));
+        assert_eq!(get(&db, "aaaa bbbb").unwrap().len(), 1);
+        assert_eq!(count(&db).unwrap(), 1);
+        // The block only applies to logins to the client that created it.
+        assert!(!is_blocked_for(&db, "wiki", addr, Some("AAAABBBB")).unwrap());
+        assert!(!is_blocked(&db, addr, Some("AAAABBBB")).unwrap());
+        assert!(is_blocked_for(&db, "forum", addr, None).is_ok_and(|blocked| !blocked));
+
+        // Only that client lifts it.
+        assert!(!unblock_for_client(&db, "wiki", "AAAABBBB").unwrap());
+        assert!(is_blocked_for(&db, "forum", addr, Some("AAAABBBB")).unwrap());
+        assert!(unblock_for_client(&db, "forum", "AAAABBBB").unwrap());
+        assert!(!is_blocked_for(&db, "forum", addr, Some("AAAABBBB")).unwrap());
+
+        // A client cannot lift a block of the admins, but admins lift every block.
+        store_client_block(&db, "forum", addr, None).unwrap();
+        db.open_tree(BLOCKED_TREE)
+            .unwrap()
+            .insert(
+                normalize_key(addr),
+                serde_json::to_vec(&Block {
+                    reason: None,
+                    created: unix_time(),
+                    expires: None,
+                    client_id: None,
+                })
+                .unwrap(),
+            )
+            .unwrap();
+        let blocks = get(&db, addr).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].client_id, None);
+        assert_eq!(blocks[1].client_id.as_deref(), Some("forum"));
+        assert!(unblock_for_client(&db, "forum", addr).unwrap());
+        assert!(is_blocked_for(&db, "forum", addr, None).unwrap());
+        store_client_block(&db, "forum", addr, None).unwrap();
+        assert!(unblock(&db, addr).unwrap());
+        assert!(!is_blocked_for(&db, "forum", addr, None).unwrap());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30m").unwrap(), 30 * 60);
+        assert_eq!(parse_duration("12h").unwrap(), 12 * 60 * 60);
+        assert_eq!(parse_duration("7d").unwrap(), 7 * 24 * 60 * 60);
+        assert_eq!(parse_duration("2w").unwrap(), 14 * 24 * 60 * 60);
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("7").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("spam").is_err());
+    }
+}
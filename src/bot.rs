@@ -0,0 +1,62 @@
+//! Reactions to Delta Chat events: messages and reactions sent to the bot.
+
+use anyhow::Result;
+use deltachat::chat::{Chat, ChatId};
+use deltachat::message::{Message, MsgId};
+use deltachat::EventType;
+
+use crate::{admin, approval, confirmation, consent, devices, magic, AppState};
+
+/// The answer to commands about the sender's own account outside of 1:1
+/// chats, where other members would see the answer.
+pub(crate) const PRIVATE_COMMAND: &str = "Please send this command to me in a 1:1 chat.";
+
+/// Act on a Delta Chat event. Errors are logged, not returned.
+pub async fn handle_event(state: &AppState, event: &EventType) {
+    let res = match event {
+        EventType::IncomingMsg { chat_id, msg_id } => {
+            handle_message(state, *chat_id, *msg_id).await
+        }
+        EventType::IncomingReaction {
+            contact_id,
+            msg_id,
+            reaction,
+            ..
+        } => admin::handle_reaction(state, *contact_id, *msg_id, reaction.as_str()).await,
+        EventType::WebxdcStatusUpdate {
+            msg_id,
+            status_update_serial,
+        } => approval::handle_status_update(state, *msg_id, *status_update_serial).await,
+        EventType::ChatModified(chat_id) => handle_chat_modified(state, *chat_id).await,
+        EventType::Error(text) => admin::alert_core_error(state, text).await,
+        _ => Ok(()),
+    };
+    if let Err(err) = res {
+        log::error!("failed to handle {event:?}: {err:#}");
+    }
+}
+
+async fn handle_message(state: &AppState, chat_id: ChatId, msg_id: MsgId) -> Result<()> {
+    let msg = Message::load_from_db(&state.dc_context, msg_id).await?;
+    if admin::admin_chat(state).await? == Some(chat_id) {
+        admin::handle_message(state, &msg).await?;
+    } else {
+        if confirmation::handle_message(state, &msg).await? {
+            crate::advance_group_flow(state, chat_id).await?;
+        }
+        let chattype = Chat::load_from_db(&state.dc_context, chat_id)
+            .await?
+            .get_type();
+        magic::handle_message(state, &msg, chattype).await?;
+        devices::handle_message(state, &msg, chattype).await?;
+        approval::handle_message(state, &msg).await?;
+        consent::handle_message(state, &msg, chattype).await?;
+    }
+    Ok(())
+}
+
+/// Keep the admin group clean and let logins progress once the user joined.
+async fn handle_chat_modified(state: &AppState, chat_id: ChatId) -> Result<()> {
+    admin::enforce_members(state, chat_id).await?;
+    crate::advance_group_flow(state, chat_id).await
+}
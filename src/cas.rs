@@ -0,0 +1,342 @@
+//! CAS protocol server for apps that only speak Apereo CAS.
+//!
+//! Services are clients listing the URLs they may use in `cas_services`.
+//! `/cas/login` runs the usual QR code login and sends the browser back to
+//! the service with a service ticket, which the service redeems once at
+//! `/cas/serviceValidate` or `/cas/p3/serviceValidate`.
+
+use anyhow::Result;
+use axum::{
+    extract::{Query, RawQuery, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::cookie::CookieJar;
+use deltachat::contact::{Contact, ContactId};
+use serde::{Deserialize, Serialize};
+use tower_sessions::Session;
+
+use crate::policy::{self, Decision};
+use crate::{
+    blocks, canonical_addr, complete_protocol_login, consent, current_flow, devices, html,
+    report_denied, roles, unix_time, AppError, AppState, LoginOutcome, OAuthConfig, ProtocolLogin,
+};
+
+/// Sled tree mapping a service ticket to its [`Ticket`].
+const TICKETS_TREE: &str = "cas_tickets";
+
+const TICKET_EXPIRY_IN_SECONDS: u64 = 5 * 60;
+
+/// A service ticket, redeemable once by the service it was issued for.
+#[derive(Debug, Serialize, Deserialize)]
+struct Ticket {
+    contact_id: u32,
+    client_id: String,
+    service: String,
+    email_login: bool,
+    auth_time: u64,
+    expires: u64,
+}
+
+/// Query of `/cas/login`.
+#[derive(Debug, Deserialize)]
+pub(crate) struct LoginQuery {
+    service: Option<String>,
+    /// Log in with Delta Chat again, even in a trusted browser.
+    #[serde(default)]
+    renew: bool,
+    /// Send the browser back without a ticket instead of asking to log in.
+    #[serde(default)]
+    gateway: bool,
+    /// The client of `service`, added for the login page.
+    client_id: Option<String>,
+    /// The login flow the login page completed.
+    flow: Option<String>,
+    #[serde(default)]
+    remember: bool,
+    /// The answer of the consent page.
+    consent: Option<String>,
+}
+
+/// Query of `/cas/serviceValidate` and `/cas/p3/serviceValidate`.
+#[derive(Debug, Deserialize)]
+pub(crate) struct ValidateQuery {
+    service: Option<String>,
+    ticket: Option<String>,
+}
+
+/// Whether `service` is the URL `pattern` or below it.
+fn service_matches(pattern: &str, service: &str) -> bool {
+    service.strip_prefix(pattern).is_some_and(|rest| {
+        rest.is_empty() || pattern.ends_with('/') || rest.starts_with(['/', '?', '#'])
+    })
+}
+
+/// The client that may use `service`.
+fn client_of<'a>(state: &'a AppState, service: &str) -> Option<&'a OAuthConfig> {
+    let mut clients = std::iter::once(&state.config.oauth).chain(&state.config.clients);
+    clients.find(|client| {
+        client
+            .cas_services
+            .iter()
+            .any(|pattern| service_matches(pattern, service))
+    })
+}
+
+/// `GET /cas/login`: log the user in and send them to `service` with a ticket.
+pub(crate) async fn get_login(
+    Query(query): Query<LoginQuery>,
+    RawQuery(raw_query): RawQuery,
+    State(state): State<AppState>,
+    session: Session,
+    jar: CookieJar,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let Some(service) = &query.service else {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            html::message_page("Login failed", "No service to log in to."),
+        )
+            .into_response());
+    };
+    let Some(client) = client_of(&state, service) else {
+        log::info!("/cas/login Invalid service: {service}");
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            html::message_page("Login failed", "This service may not log in here."),
+        )
+            .into_response());
+    };
+
+    let flow = current_flow(&state, &session, query.flow.clone())
+        .await?
+        .filter(|flow| flow.client_id == client.client_id && flow.contact_id.is_some());
+    let login = match &flow {
+        Some(flow) => Some((
+            Contact::get_by_id(
+                &state.dc_context,
+                ContactId::new(flow.contact_id.unwrap_or_default()),
+            )
+            .await?,
+            flow.auth_time,
+        )),
+        None if query.renew => None,
+        None => devices::trusted_contact(&state, &jar).await?,
+    };
+    let Some((contact, auth_time)) = login else {
+        if query.gateway {
+            return Ok(Redirect::to(service).into_response());
+        }
+        if query.client_id.as_deref() != Some(client.client_id.as_str()) {
+            // The login page creates the flow for the client named in the URL.
+            let mut url = format!("/cas/login?{}", raw_query.unwrap_or_default());
+            url += &url::form_urlencoded::Serializer::new(String::from("&"))
+                .append_pair("client_id", &client.client_id)
+                .finish();
+            return Ok(Redirect::to(&url).into_response());
+        }
+        log::info!("/cas/login showing login screen");
+        return Ok(Html::from(state.login_html).into_response());
+    };
+    let email_login = flow.as_ref().is_some_and(|flow| flow.email_login);
+    let scopes = consent::requested_scopes(client, None);
+    let login = ProtocolLogin {
+        endpoint: "/cas/login",
+        client: Some(client),
+        access: &client.access,
+        name: client.name.as_deref().unwrap_or(&client.client_id),
+        flow,
+        scopes: &scopes,
+        consent: query.consent.as_deref(),
+        force_consent: false,
+        silent: false,
+        raw_query,
+        remember: query.remember,
+    };
+    let jar =
+        match complete_protocol_login(&state, &session, jar, &headers, &contact, login).await? {
+            LoginOutcome::Completed(jar) => jar,
+            LoginOutcome::Page(page) => return Ok(page),
+            LoginOutcome::ConsentDenied | LoginOutcome::ConsentRequired => {
+                return Ok(html::message_page(
+                    "Login cancelled",
+                    "You did not agree to share your data.",
+                )
+                .into_response())
+            }
+        };
+
+    let ticket_id = format!("ST-{}", uuid::Uuid::new_v4().simple());
+    let ticket = Ticket {
+        contact_id: contact.get_id().to_u32(),
+        client_id: client.client_id.clone(),
+        service: service.clone(),
+        email_login,
+        auth_time,
+        expires: unix_time().saturating_add(TICKET_EXPIRY_IN_SECONDS),
+    };
+    let tickets = state.db.open_tree(TICKETS_TREE)?;
+    prune_expired(&tickets)?;
+    tickets.insert(&ticket_id, serde_json::to_vec(&ticket)?)?;
+    log::info!("/cas/login issued a ticket to {service}");
+    let mut url = url::Url::parse(service)?;
+    url.query_pairs_mut().append_pair("ticket", &ticket_id);
+    Ok((jar, Redirect::to(url.as_str())).into_response())
+}
+
+/// `GET /cas/serviceValidate` and `GET /cas/p3/serviceValidate`: redeem a
+/// service ticket for the user and their attributes.
+pub(crate) async fn get_service_validate(
+    State(state): State<AppState>,
+    Query(query): Query<ValidateQuery>,
+) -> Result<Response, AppError> {
+    let (Some(service), Some(ticket_id)) = (&query.service, &query.ticket) else {
+        return Ok(failure(
+            "INVALID_REQUEST",
+            "service and ticket are required",
+        ));
+    };
+    // Tickets are single-use.
+    let ticket = state
+        .db
+        .open_tree(TICKETS_TREE)?
+        .remove(ticket_id)?
+        .map(|data| serde_json::from_slice::<Ticket>(&data))
+        .transpose()?
+        .filter(|ticket| ticket.expires > unix_time());
+    let Some(ticket) = ticket else {
+        log::info!("/cas/serviceValidate got an unknown or expired ticket");
+        return Ok(failure("INVALID_TICKET", "unknown or expired ticket"));
+    };
+    if ticket.service != *service {
+        log::info!("/cas/serviceValidate ticket was issued for another service");
+        return Ok(failure(
+            "INVALID_SERVICE",
+            "the ticket was issued for another service",
+        ));
+    }
+    let Some(client) = state.config.client(&ticket.client_id) else {
+        return Ok(failure("INVALID_SERVICE", "the service is not configured"));
+    };
+    let contact = Contact::get_by_id(&state.dc_context, ContactId::new(ticket.contact_id)).await?;
+    // The key may have changed or the user may have been blocked since the
+    // ticket was issued.
+    let fingerprint = contact.fingerprint().map(|fp| fp.hex());
+    let decision = if blocks::is_blocked_for(
+        &state.db,
+        &client.client_id,
+        contact.get_addr(),
+        fingerprint.as_deref(),
+    )? {
+        Decision::Deny("you are blocked".to_string())
+    } else if ticket.email_login {
+        // The client opted into email logins, which have no key.
+        Decision::Allow
+    } else {
+        policy::check_contact(&state, client.key_policy, &contact).await?
+    };
+    if let Decision::Deny(reason) = decision {
+        log::info!(
+            "/cas/serviceValidate denied login of {}: {reason}",
+            contact.get_addr()
+        );
+        report_denied(&state, &contact, &reason).await?;
+        return Ok(failure(
+            "INVALID_TICKET",
+            &format!("access denied: {reason}"),
+        ));
+    }
+    let identity = canonical_addr(&state, &contact)?;
+    let scopes = consent::requested_scopes(client, None);
+    let scope = |name: &str| scopes.iter().any(|scope| scope == name);
+    let mut attributes = String::new();
+    let mut add = |name: &str, value: &str| {
+        attributes += &format!("<cas:{name}>{}</cas:{name}>", html::escape(value));
+    };
+    if scope("email") {
+        add("email", &identity);
+    }
+    if scope("profile") {
+        add("displayName", contact.get_name());
+    }
+    if scope("groups") {
+        for role in roles::roles_of(&state, contact.get_id()).await? {
+            add("groups", &role);
+        }
+    }
+    add("authenticationDate", &ticket.auth_time.to_string());
+    add(
+        "authenticationMethod",
+        if ticket.email_login {
+            "email"
+        } else {
+            "securejoin"
+        },
+    );
+    log::info!("/cas/serviceValidate validated {identity} for {service}");
+    Ok(xml(format!(
+        "<cas:authenticationSuccess><cas:user>{}</cas:user><cas:attributes>{attributes}</cas:attributes></cas:authenticationSuccess>",
+        html::escape(&identity)
+    )))
+}
+
+/// A CAS `authenticationFailure` with `code`.
+fn failure(code: &str, description: &str) -> Response {
+    xml(format!(
+        r#"<cas:authenticationFailure code="{code}">{}</cas:authenticationFailure>"#,
+        html::escape(description)
+    ))
+}
+
+/// A CAS `serviceResponse` around `body`.
+fn xml(body: String) -> Response {
+    (
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        format!(
+            "<cas:serviceResponse xmlns:cas=\"http://www.yale.edu/tp/cas\">{body}</cas:serviceResponse>\n"
+        ),
+    )
+        .into_response()
+}
+
+/// Drop tickets whose expiry has passed.
+fn prune_expired(tickets: &sled::Tree) -> Result<()> {
+    let now = unix_time();
+    for entry in tickets {
+        let (key, data) = entry?;
+        let expired = serde_json::from_slice::<Ticket>(&data)
+            .map(|ticket| ticket.expires <= now)
+            .unwrap_or(true);
+        if expired {
+            tickets.remove(key)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_services() {
+        let pattern = "https://app.example.org";
+        assert!(service_matches(pattern, "https://app.example.org"));
+        assert!(service_matches(
+            pattern,
+            "https://app.example.org/login?x=1"
+        ));
+        assert!(!service_matches(
+            pattern,
+            "https://app.example.org.evil.com/"
+        ));
+        assert!(service_matches(
+            "https://example.org/app/",
+            "https://example.org/app/cas"
+        ));
+        assert!(!service_matches(
+            "https://example.org/app",
+            "https://example.org/application"
+        ));
+    }
+}
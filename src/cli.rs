@@ -0,0 +1,87 @@
+//! Admin command line: manages a running bot through its admin API.
+
+use std::net::Ipv4Addr;
+
+use anyhow::{bail, ensure, Context as _, Result};
+use deltachat_loginbot::BotConfig;
+use serde_json::{json, Value};
+
+pub(crate) const USAGE: &str = "usage:
+  loginbot [config.toml]
+  loginbot <config.toml> blocks
+  loginbot <config.toml> block <addr|fingerprint> [--for <duration> | --until <yyyy-mm-dd>] [reason]
+  loginbot <config.toml> unblock <addr|fingerprint>
+  loginbot <config.toml> lists
+  loginbot <config.toml> list <name> [add|remove <addr|fingerprint>]";
+
+/// Run the admin command `args` against the bot configured in `config`.
+pub(crate) async fn run(config: &BotConfig, args: &[String]) -> Result<()> {
+    let token = config
+        .admin
+        .api_token
+        .as_deref()
+        .context("set [admin] api_token to use admin commands")?;
+    let mut addr = config.listen_addr;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(Ipv4Addr::LOCALHOST.into());
+    }
+    let client = reqwest::Client::new();
+    let admin_url = |segments: &[&str]| -> Result<url::Url> {
+        let mut url = url::Url::parse(&format!("http://{addr}/admin"))?;
+        url.path_segments_mut()
+            .map_err(|()| anyhow::anyhow!("invalid admin API URL"))?
+            .extend(segments);
+        Ok(url)
+    };
+    let request = match args {
+        [command] if command == "blocks" => client.get(admin_url(&["blocks"])?),
+        [command, key, rest @ ..] if command == "block" => {
+            let (duration, until, reason) = match rest {
+                [flag, duration, reason @ ..] if flag == "--for" => {
+                    (Some(duration.as_str()), None, reason)
+                }
+                [flag, date, reason @ ..] if flag == "--until" => {
+                    (None, Some(parse_date(date)?), reason)
+                }
+                reason => (None, None, reason),
+            };
+            let reason = Some(reason.join(" ")).filter(|reason| !reason.is_empty());
+            client
+                .put(admin_url(&["blocks", key])?)
+                .json(&json!({ "reason": reason, "duration": duration, "until": until }))
+        }
+        [command, key] if command == "unblock" => client.delete(admin_url(&["blocks", key])?),
+        [command] if command == "lists" => client.get(admin_url(&["lists"])?),
+        [command, name] if command == "list" => client.get(admin_url(&["lists", name])?),
+        [command, name, action, key] if command == "list" && action == "add" => {
+            client.put(admin_url(&["lists", name, key])?)
+        }
+        [command, name, action, key] if command == "list" && action == "remove" => {
+            client.delete(admin_url(&["lists", name, key])?)
+        }
+        _ => bail!("{USAGE}"),
+    };
+    let response = request
+        .bearer_auth(token)
+        .send()
+        .await
+        .with_context(|| format!("cannot reach the bot at {addr}"))?;
+    let status = response.status();
+    let body: Value = response.json().await?;
+    println!("{}", serde_json::to_string_pretty(&body)?);
+    ensure!(status.is_success(), "admin API returned {status}");
+    Ok(())
+}
+
+/// Parse a `yyyy-mm-dd` date into the Unix time of its start in UTC.
+fn parse_date(text: &str) -> Result<u64> {
+    let parts: Vec<&str> = text.splitn(3, '-').collect();
+    let [year, month, day] = parts.as_slice() else {
+        bail!("invalid date {text:?}, use yyyy-mm-dd");
+    };
+    let month = time::Month::try_from(month.parse::<u8>()?)?;
+    let date = time::Date::from_calendar_date(year.parse()?, month, day.parse()?)?;
+    Ok(u64::try_from(
+        date.midnight().assume_utc().unix_timestamp(),
+    )?)
+}
@@ -0,0 +1,424 @@
+//! Confirmation of logins on the phone that scanned the QR code.
+//!
+//! Without it, whoever gets a victim to scan a QR code shown on a proxied
+//! login page is logged in. With number matching, the browser shows a
+//! number that the user has to pick from several candidates the bot sends
+//! to the login group; logins the bot pushed to a user by `login_hint`
+//! offer no candidates but ask for a longer typed code, so that tapping a
+//! candidate at random does not let whoever started the login in. With the
+//! webxdc approval app, the user approves the login in an app in their chat
+//! with the bot, see [`crate::approval`].
+
+use anyhow::Result;
+use deltachat::chat::{send_text_msg, ChatId};
+use deltachat::contact::ContactId;
+use deltachat::message::Message;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{approval, unix_time, AppState, OAuthConfig};
+
+/// Sled tree mapping a login group's chat id (u32 le) to its [`Challenge`].
+const CONFIRMATIONS_TREE: &str = "confirmations";
+
+/// Number of candidates offered in the chat, including the correct one.
+const CANDIDATES: usize = 3;
+
+/// Digits of the code typed to confirm a hinted login.
+const TYPED_CODE_DIGITS: usize = 6;
+
+/// Approvals older than this are no longer offered; the login flow expired.
+const APPROVAL_TIMEOUT_IN_SECONDS: u64 = 15 * 60;
+
+/// How a login is confirmed after the user joined the login group.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Confirmation {
+    /// Joining the login group completes the login.
+    #[default]
+    None,
+    /// The user has to reply with the number shown in the browser.
+    NumberMatching,
+    /// The user has to approve the login in the webxdc approval app.
+    Webxdc,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ChallengeState {
+    Pending,
+    Confirmed,
+    Failed,
+}
+
+/// Confirmation of one login group.
+#[derive(Serialize, Deserialize)]
+struct Challenge {
+    /// The number to match; `None` for approval in the webxdc app.
+    code: Option<String>,
+    state: ChallengeState,
+    /// The user who joined the login group.
+    #[serde(default)]
+    contact_id: u32,
+    /// Name of the relying party, shown in the approval app.
+    #[serde(default)]
+    client: String,
+    #[serde(default)]
+    origin: String,
+    #[serde(default)]
+    created: u64,
+}
+
+impl Challenge {
+    /// Whether `contact_id` still has to approve this login in the approval app.
+    fn awaits_approval_of(&self, contact_id: u32) -> bool {
+        self.state == ChallengeState::Pending
+            && self.code.is_none()
+            && self.contact_id == contact_id
+            && unix_time().saturating_sub(self.created) < APPROVAL_TIMEOUT_IN_SECONDS
+    }
+}
+
+/// Outcome of [`check`].
+pub(crate) enum Status {
+    /// The login may complete.
+    Confirmed,
+    /// The browser has to show this code until the user replied.
+    Pending(String),
+    /// The user has to approve the login in the approval app.
+    AwaitingApproval,
+    /// The user replied with a wrong number or denied the login.
+    Failed,
+}
+
+/// Check the confirmation of `contact_id`'s login in `group_id`, starting it if needed.
+///
+/// `mode` is usually the client's [`OAuthConfig::confirmation`]. `hinted`
+/// asks for a typed code instead of candidates. `origin` is the site the
+/// login page was loaded from; it is shown to the user so that they notice
+/// logins started on a proxy.
+pub(crate) async fn check(
+    state: &AppState,
+    client: &OAuthConfig,
+    mode: Confirmation,
+    hinted: bool,
+    group_id: ChatId,
+    contact_id: ContactId,
+    origin: &str,
+) -> Result<Status> {
+    if mode == Confirmation::None {
+        return Ok(Status::Confirmed);
+    }
+    let tree = state.db.open_tree(CONFIRMATIONS_TREE)?;
+    let key = group_id.to_u32().to_le_bytes();
+    if let Some(status) = status(&tree, key)? {
+        return Ok(status);
+    }
+    let name = client.name.as_deref().unwrap_or(&client.client_id);
+    let mut challenge = Challenge {
+        code: None,
+        state: ChallengeState::Pending,
+        contact_id: contact_id.to_u32(),
+        client: name.to_string(),
+        origin: origin.to_string(),
+        created: unix_time(),
+    };
+    if mode == Confirmation::Webxdc {
+        tree.insert(key, serde_json::to_vec(&challenge)?)?;
+        approval::publish(state, contact_id, true).await?;
+        let text = format!(
+            "Log in to {name} at {origin}? Approve or deny the login \
+            in the login approval app in your chat with me."
+        );
+        send_text_msg(&state.dc_context, group_id, text).await?;
+        return Ok(Status::AwaitingApproval);
+    }
+    let random = uuid::Uuid::new_v4().into_bytes();
+    let (code, request) = match hinted {
+        true => (
+            typed_code(&random),
+            format!("Reply with the {TYPED_CODE_DIGITS}-digit code your browser shows."),
+        ),
+        false => {
+            let (code, candidates) = candidates(&random);
+            let request = format!(
+                "Reply with the number your browser shows: {}",
+                candidates.join(", ")
+            );
+            (code, request)
+        }
+    };
+    challenge.code = Some(code.clone());
+    tree.insert(key, serde_json::to_vec(&challenge)?)?;
+    let text = format!(
+        "Log in to {name} at {origin}?\n\n{request}\n\n\
+        If you did not just start this login on {origin} yourself, do not reply: \
+        somebody may be trying to log in as you."
+    );
+    send_text_msg(&state.dc_context, group_id, text).await?;
+    Ok(Status::Pending(code))
+}
+
+/// Handle a message in a login group: the user's answer to the number matching.
+///
+/// Returns whether the message answered a pending number matching.
+pub(crate) async fn handle_message(state: &AppState, msg: &Message) -> Result<bool> {
+    if msg.get_from_id() == ContactId::SELF {
+        return Ok(false);
+    }
+    let tree = state.db.open_tree(CONFIRMATIONS_TREE)?;
+    let key = msg.get_chat_id().to_u32().to_le_bytes();
+    let Some(reply) = answer(&tree, key, &msg.get_text())? else {
+        return Ok(false);
+    };
+    send_text_msg(&state.dc_context, msg.get_chat_id(), reply.to_string()).await?;
+    Ok(true)
+}
+
+/// The state of the confirmation stored under `key`, forgetting it once it is decided.
+fn status(tree: &sled::Tree, key: [u8; 4]) -> Result<Option<Status>> {
+    let Some(data) = tree.get(key)? else {
+        return Ok(None);
+    };
+    let challenge: Challenge = serde_json::from_slice(&data)?;
+    if challenge.state == ChallengeState::Pending {
+        return Ok(Some(match challenge.code {
+            Some(code) => Status::Pending(code),
+            None => Status::AwaitingApproval,
+        }));
+    }
+    tree.remove(key)?;
+    Ok(Some(match challenge.state {
+        ChallengeState::Confirmed => Status::Confirmed,
+        _ => Status::Failed,
+    }))
+}
+
+/// Match `text` against the pending number matching stored under `key`.
+///
+/// Returns the reply to send, or `None` if `text` is no answer to a pending number matching.
+fn answer(tree: &sled::Tree, key: [u8; 4], text: &str) -> Result<Option<&'static str>> {
+    let Some(data) = tree.get(key)? else {
+        return Ok(None);
+    };
+    let mut challenge: Challenge = serde_json::from_slice(&data)?;
+    let answer = text.trim();
+    let Some(code) = &challenge.code else {
+        return Ok(None);
+    };
+    if challenge.state != ChallengeState::Pending
+        || answer.is_empty()
+        || !answer.chars().all(|c| c.is_ascii_digit())
+    {
+        return Ok(None);
+    }
+    let reply = if answer == code {
+        challenge.state = ChallengeState::Confirmed;
+        "Confirmed. Return to your browser to continue."
+    } else {
+        log::info!("wrong confirmation number in {}", u32::from_le_bytes(key));
+        challenge.state = ChallengeState::Failed;
+        "Wrong number, the login was cancelled. If it was you, start again in your browser."
+    };
+    tree.insert(key, serde_json::to_vec(&challenge)?)?;
+    Ok(Some(reply))
+}
+
+/// Approve or deny the login in `group_id` on behalf of `contact_id`.
+///
+/// Returns `false` if there is no such pending approval of that user.
+pub(crate) async fn decide(
+    state: &AppState,
+    group_id: ChatId,
+    contact_id: ContactId,
+    approve: bool,
+) -> Result<bool> {
+    let tree = state.db.open_tree(CONFIRMATIONS_TREE)?;
+    let key = group_id.to_u32().to_le_bytes();
+    let Some(reply) = record_decision(&tree, key, contact_id.to_u32(), approve)? else {
+        return Ok(false);
+    };
+    send_text_msg(&state.dc_context, group_id, reply.to_string()).await?;
+    Ok(true)
+}
+
+/// Record `contact_id`'s decision on the approval stored under `key`.
+///
+/// Returns the reply to send, or `None` if that user has no such pending approval.
+fn record_decision(
+    tree: &sled::Tree,
+    key: [u8; 4],
+    contact_id: u32,
+    approve: bool,
+) -> Result<Option<&'static str>> {
+    let Some(data) = tree.get(key)? else {
+        return Ok(None);
+    };
+    let mut challenge: Challenge = serde_json::from_slice(&data)?;
+    if !challenge.awaits_approval_of(contact_id) {
+        return Ok(None);
+    }
+    let reply = if approve {
+        challenge.state = ChallengeState::Confirmed;
+        "Approved. Return to your browser to continue."
+    } else {
+        log::info!(
+            "login in {} denied in the approval app",
+            u32::from_le_bytes(key)
+        );
+        challenge.state = ChallengeState::Failed;
+        "Denied, the login was cancelled."
+    };
+    tree.insert(key, serde_json::to_vec(&challenge)?)?;
+    Ok(Some(reply))
+}
+
+/// Pending approvals of `contact_id`, as shown in the approval app.
+pub(crate) fn pending_approvals(db: &sled::Db, contact_id: ContactId) -> Result<Vec<Value>> {
+    let mut requests = Vec::new();
+    for entry in &db.open_tree(CONFIRMATIONS_TREE)? {
+        let (key, data) = entry?;
+        let Ok(challenge) = serde_json::from_slice::<Challenge>(&data) else {
+            continue;
+        };
+        if challenge.awaits_approval_of(contact_id.to_u32()) {
+            let group_id = u32::from_le_bytes(key.as_ref().try_into()?);
+            requests.push(json!({
+                "id": group_id,
+                "client": challenge.client,
+                "origin": challenge.origin,
+                "time": challenge.created,
+            }));
+        }
+    }
+    Ok(requests)
+}
+
+/// The correct two-digit code and all candidates in ascending order,
+/// derived from the bytes of `random`.
+fn candidates(random: &[u8]) -> (String, Vec<String>) {
+    let mut numbers: Vec<u8> = Vec::with_capacity(CANDIDATES);
+    for byte in random {
+        let number = (byte % 90).saturating_add(10);
+        if !numbers.contains(&number) {
+            numbers.push(number);
+        }
+        if numbers.len() == CANDIDATES {
+            break;
+        }
+    }
+    let code = numbers.first().copied().unwrap_or(10).to_string();
+    numbers.sort_unstable();
+    (code, numbers.iter().map(u8::to_string).collect())
+}
+
+/// A code of [`TYPED_CODE_DIGITS`] digits derived from the bytes of `random`.
+fn typed_code(random: &[u8]) -> String {
+    random
+        .iter()
+        .take(TYPED_CODE_DIGITS)
+        .map(|byte| char::from(b'0'.saturating_add(byte % 10)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_typed_code() {
+        assert_eq!(typed_code(&[0, 1, 19, 255, 7, 42, 9, 9]), "019572");
+        assert_eq!(typed_code(&uuid::Uuid::new_v4().into_bytes()).len(), 6);
+    }
+
+    #[test]
+    fn test_candidates() {
+        let (code, numbers) = candidates(&[5, 95, 7, 3, 200]);
+        assert_eq!(code, "15");
+        assert_eq!(numbers, ["13", "15", "17"]);
+        let (code, numbers) = candidates(&[0; 16]);
+        assert_eq!(code, "10");
+        assert_eq!(numbers, ["10"]);
+    }
+
+    fn pending(tree: &sled::Tree, key: [u8; 4]) {
+        insert(tree, key, Some("42"), unix_time());
+    }
+
+    fn insert(tree: &sled::Tree, key: [u8; 4], code: Option<&str>, created: u64) {
+        let challenge = Challenge {
+            code: code.map(str::to_string),
+            state: ChallengeState::Pending,
+            contact_id: 10,
+            client: "Example".to_string(),
+            origin: "https://example.org".to_string(),
+            created,
+        };
+        tree.insert(key, serde_json::to_vec(&challenge).unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_number_matching() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree(CONFIRMATIONS_TREE).unwrap();
+        let key = 7u32.to_le_bytes();
+        assert!(status(&tree, key).unwrap().is_none());
+
+        pending(&tree, key);
+        assert!(matches!(status(&tree, key).unwrap(), Some(Status::Pending(code)) if code == "42"));
+        // Chatter that is not a number is not an answer.
+        assert!(answer(&tree, key, "which one?").unwrap().is_none());
+        assert!(matches!(
+            status(&tree, key).unwrap(),
+            Some(Status::Pending(_))
+        ));
+
+        // A wrong number aborts the login, and later answers change nothing.
+        assert!(answer(&tree, key, "17").unwrap().is_some());
+        assert!(answer(&tree, key, "42").unwrap().is_none());
+        assert!(matches!(status(&tree, key).unwrap(), Some(Status::Failed)));
+        assert!(status(&tree, key).unwrap().is_none());
+
+        // The right number admits it.
+        pending(&tree, key);
+        assert!(answer(&tree, key, " 42\n").unwrap().is_some());
+        assert!(matches!(
+            status(&tree, key).unwrap(),
+            Some(Status::Confirmed)
+        ));
+        assert!(status(&tree, key).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_approval() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree(CONFIRMATIONS_TREE).unwrap();
+        let key = 7u32.to_le_bytes();
+        assert!(record_decision(&tree, key, 10, true).unwrap().is_none());
+
+        insert(&tree, key, None, unix_time());
+        assert!(matches!(
+            status(&tree, key).unwrap(),
+            Some(Status::AwaitingApproval)
+        ));
+        // Only the user who joined the login group decides.
+        assert!(record_decision(&tree, key, 11, true).unwrap().is_none());
+        assert!(record_decision(&tree, key, 10, true).unwrap().is_some());
+        assert!(record_decision(&tree, key, 10, false).unwrap().is_none());
+        assert!(matches!(
+            status(&tree, key).unwrap(),
+            Some(Status::Confirmed)
+        ));
+
+        insert(&tree, key, None, unix_time());
+        assert!(record_decision(&tree, key, 10, false).unwrap().is_some());
+        assert!(matches!(status(&tree, key).unwrap(), Some(Status::Failed)));
+
+        // Number matching and expired approvals cannot be decided in the app.
+        pending(&tree, key);
+        assert!(record_decision(&tree, key, 10, true).unwrap().is_none());
+        insert(&tree, key, None, unix_time() - APPROVAL_TIMEOUT_IN_SECONDS);
+        assert!(record_decision(&tree, key, 10, true).unwrap().is_none());
+    }
+}
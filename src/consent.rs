@@ -0,0 +1,296 @@
+//! Consent: before a client learns anything about a user, the user sees
+//! which scopes it asks for and agrees once.
+//!
+//! Grants are remembered per identity (the user's canonical address) and
+//! client. Users list them with `/grants` and withdraw them with `/ungrant`.
+
+use anyhow::Result;
+use axum::response::Html;
+use deltachat::chat::{send_msg, Chattype};
+use deltachat::contact::Contact;
+use deltachat::message::{Message, Viewtype};
+use serde::{Deserialize, Serialize};
+
+use crate::{bot, canonical_addr, html, unix_time, AppState, OAuthConfig};
+
+/// Sled tree mapping `"<identity> <client_id>"` to a [`Grant`].
+const GRANTS_TREE: &str = "grants";
+
+/// Sled tree mapping the token of a shown consent page to its [`ConsentRequest`].
+const CONSENT_REQUESTS_TREE: &str = "consent_requests";
+
+const CONSENT_REQUEST_EXPIRY_IN_SECONDS: u64 = 10 * 60;
+
+/// The scopes the bot knows, with what they share, as shown on the consent page.
+const SCOPES: [(&str, &str); 4] = [
+    ("openid", "that you logged in"),
+    ("email", "your email address"),
+    ("profile", "your display name"),
+    ("groups", "your roles"),
+];
+
+/// All scopes, the default of [`OAuthConfig::scopes`].
+///
+/// Also the scopes of codes and tokens issued before scopes existed.
+pub(crate) fn all_scopes() -> Vec<String> {
+    SCOPES.iter().map(|(scope, _)| scope.to_string()).collect()
+}
+
+/// Scopes a user agreed to share with a client.
+#[derive(Debug, Serialize, Deserialize)]
+struct Grant {
+    scopes: Vec<String>,
+    created: u64,
+}
+
+/// A consent page shown to a user, until they agree.
+#[derive(Debug, Serialize, Deserialize)]
+struct ConsentRequest {
+    identity: String,
+    client_id: String,
+    scopes: Vec<String>,
+    expires: u64,
+}
+
+/// The scopes of `scope` that `client` may ask for; all of them without `scope`.
+pub(crate) fn requested_scopes(client: &OAuthConfig, scope: Option<&str>) -> Vec<String> {
+    let allowed = client.scopes.clone().unwrap_or_else(all_scopes);
+    let Some(scope) = scope else {
+        return allowed;
+    };
+    let mut scopes: Vec<String> = Vec::new();
+    for scope in scope.split_whitespace() {
+        if allowed.iter().any(|allowed| allowed == scope)
+            && !scopes.iter().any(|known| known == scope)
+        {
+            scopes.push(scope.to_string());
+        }
+    }
+    scopes
+}
+
+fn grant_key(identity: &str, client_id: &str) -> String {
+    format!("{identity} {client_id}")
+}
+
+/// Whether `identity` agreed to share all of `scopes` with `client_id`.
+pub(crate) fn is_granted(
+    db: &sled::Db,
+    identity: &str,
+    client_id: &str,
+    scopes: &[String],
+) -> Result<bool> {
+    let Some(data) = db
+        .open_tree(GRANTS_TREE)?
+        .get(grant_key(identity, client_id))?
+    else {
+        return Ok(false);
+    };
+    let grant: Grant = serde_json::from_slice(&data)?;
+    Ok(scopes.iter().all(|scope| grant.scopes.contains(scope)))
+}
+
+/// Remember the consent whose page carried `token`, if it belongs to
+/// `identity` and `client_id`.
+///
+/// Returns whether there was such a consent.
+pub(crate) fn redeem(db: &sled::Db, token: &str, identity: &str, client_id: &str) -> Result<bool> {
+    let request = db
+        .open_tree(CONSENT_REQUESTS_TREE)?
+        .remove(token)?
+        .map(|data| serde_json::from_slice::<ConsentRequest>(&data))
+        .transpose()?
+        .filter(|request| {
+            request.identity == identity
+                && request.client_id == client_id
+                && request.expires > unix_time()
+        });
+    let Some(request) = request else {
+        return Ok(false);
+    };
+    let grant = Grant {
+        scopes: request.scopes,
+        created: unix_time(),
+    };
+    db.open_tree(GRANTS_TREE)?
+        .insert(grant_key(identity, client_id), serde_json::to_vec(&grant)?)?;
+    log::info!("{identity} granted {:?} to {client_id}", grant.scopes);
+    Ok(true)
+}
+
+/// The consent page asking `identity` to share `scopes` with `client`.
+///
+/// `query` are the parameters of the request to `action`, e.g. `/authorize`,
+/// which the page repeats with its answer in `consent`.
+pub(crate) fn page(
+    db: &sled::Db,
+    action: &str,
+    client: &OAuthConfig,
+    identity: &str,
+    scopes: &[String],
+    query: &[(String, String)],
+) -> Result<Html<String>> {
+    let token = uuid::Uuid::new_v4().simple().to_string();
+    let request = ConsentRequest {
+        identity: identity.to_string(),
+        client_id: client.client_id.clone(),
+        scopes: scopes.to_vec(),
+        expires: unix_time().saturating_add(CONSENT_REQUEST_EXPIRY_IN_SECONDS),
+    };
+    let requests = db.open_tree(CONSENT_REQUESTS_TREE)?;
+    prune_expired(&requests)?;
+    requests.insert(&token, serde_json::to_vec(&request)?)?;
+
+    let name = client.name.as_deref().unwrap_or(&client.client_id);
+    let mut hidden = String::new();
+    for (key, value) in query.iter().filter(|(key, _)| key != "consent") {
+        hidden += &format!(
+            r#"<input type="hidden" name="{}" value="{}">"#,
+            html::escape(key),
+            html::escape(value)
+        );
+    }
+    let shared: String = SCOPES
+        .iter()
+        .filter(|(scope, _)| scopes.iter().any(|requested| requested == scope))
+        .map(|(_, description)| format!("<li>{description}</li>"))
+        .collect();
+    let body = format!(
+        r#"<p>{} wants to know as {}:</p>
+      <ul>{shared}</ul>
+      <form action="{action}" method="get">{hidden}<button name="consent" value="{token}">Allow</button></form>
+      <form action="{action}" method="get">{hidden}<button name="consent" value="deny">Deny</button></form>"#,
+        html::escape(name),
+        html::escape(identity)
+    );
+    Ok(html::page("Share your data?", &body))
+}
+
+/// Answer `/grants` and `/ungrant <client|all>` in `msg`, sent in a chat of
+/// `chattype`; other messages are ignored.
+///
+/// Only 1:1 chats get an answer, as the grants show which services the user uses.
+pub(crate) async fn handle_message(
+    state: &AppState,
+    msg: &Message,
+    chattype: Chattype,
+) -> Result<()> {
+    let text = msg.get_text();
+    let mut args = text.split_whitespace();
+    let command = args.next();
+    if command != Some("/grants") && command != Some("/ungrant") {
+        return Ok(());
+    }
+    let contact = Contact::get_by_id(&state.dc_context, msg.get_from_id()).await?;
+    let identity = canonical_addr(state, &contact)?;
+    let reply = match (command, args.next()) {
+        _ if chattype != Chattype::Single => bot::PRIVATE_COMMAND.to_string(),
+        (Some("/ungrant"), Some(client)) => match ungrant(&state.db, &identity, client)? {
+            0 => {
+                format!("You did not grant anything to {client}. Send /grants to list your grants.")
+            }
+            1 => "Withdrew 1 grant.".to_string(),
+            withdrawn => format!("Withdrew {withdrawn} grants."),
+        },
+        _ => list_text(state, &identity)?,
+    };
+    let mut answer = Message::new(Viewtype::Text);
+    answer.set_text(reply);
+    answer.set_quote(&state.dc_context, Some(msg)).await?;
+    send_msg(&state.dc_context, msg.get_chat_id(), &mut answer).await?;
+    Ok(())
+}
+
+/// The grants of `identity`, as client id and scopes.
+pub(crate) fn grants_of(db: &sled::Db, identity: &str) -> Result<Vec<(String, Vec<String>)>> {
+    let mut grants = Vec::new();
+    for entry in db
+        .open_tree(GRANTS_TREE)?
+        .scan_prefix(format!("{identity} "))
+    {
+        let (key, data) = entry?;
+        let key = String::from_utf8(key.to_vec())?;
+        let client_id = key
+            .strip_prefix(&format!("{identity} "))
+            .unwrap_or_default();
+        let grant: Grant = serde_json::from_slice(&data)?;
+        grants.push((client_id.to_string(), grant.scopes));
+    }
+    Ok(grants)
+}
+
+fn list_text(state: &AppState, identity: &str) -> Result<String> {
+    let grants = grants_of(&state.db, identity)?;
+    if grants.is_empty() {
+        return Ok("You did not grant any client access to your data.".to_string());
+    }
+    let mut lines: Vec<String> = grants
+        .into_iter()
+        .map(|(client_id, scopes)| {
+            let name = state
+                .config
+                .client(&client_id)
+                .and_then(|client| client.name.as_deref())
+                .unwrap_or(&client_id);
+            format!("{name} ({client_id}): {}", scopes.join(", "))
+        })
+        .collect();
+    lines.push("\nSend /ungrant <client> or /ungrant all to withdraw them.".to_string());
+    Ok(format!("Your grants:\n{}", lines.join("\n")))
+}
+
+/// Withdraw the grant of `identity` to `client_id`, or all of them for `"all"`.
+pub(crate) fn ungrant(db: &sled::Db, identity: &str, client_id: &str) -> Result<usize> {
+    let tree = db.open_tree(GRANTS_TREE)?;
+    let mut withdrawn: usize = 0;
+    for (granted, _) in grants_of(db, identity)? {
+        if client_id == "all" || granted == client_id {
+            tree.remove(grant_key(identity, &granted))?;
+            withdrawn = withdrawn.saturating_add(1);
+        }
+    }
+    Ok(withdrawn)
+}
+
+/// Move the grants of `identity` to `new_identity`, after a change of the canonical address.
+pub(crate) fn move_grants(db: &sled::Db, identity: &str, new_identity: &str) -> Result<()> {
+    let tree = db.open_tree(GRANTS_TREE)?;
+    for (client_id, _) in grants_of(db, identity)? {
+        if let Some(grant) = tree.remove(grant_key(identity, &client_id))? {
+            tree.insert(grant_key(new_identity, &client_id), grant)?;
+        }
+    }
+    Ok(())
+}
+
+/// Drop consent requests whose expiry has passed.
+fn prune_expired(requests: &sled::Tree) -> Result<()> {
+    let now = unix_time();
+    for entry in requests {
+        let (key, data) = entry?;
+        let expired = serde_json::from_slice::<ConsentRequest>(&data)
+            .map(|request| request.expires <= now)
+            .unwrap_or(true);
+        if expired {
+            requests.remove(key)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requested() {
+        let mut client = OAuthConfig::default();
+        assert_eq!(requested_scopes(&client, None), all_scopes());
+        assert_eq!(
+            requested_scopes(&client, Some("openid email email unknown")),
+            ["openid", "email"]
+        );
+        client.scopes = Some(vec!["openid".to_string()]);
+        assert_eq!(requested_scopes(&client, Some("openid email")), ["openid"]);
+    }
+}
@@ -0,0 +1,347 @@
+//! Trusted browsers: an opt-in long-lived cookie that lets `/authorize`
+//! skip the QR code in a browser the user logged in with before.
+//!
+//! Users list and revoke their trusted browsers by writing `/devices`
+//! and `/revoke` to the bot.
+
+use anyhow::Result;
+use axum::http::{header, HeaderMap};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use deltachat::chat::{send_msg, Chattype};
+use deltachat::contact::{Contact, ContactId};
+use deltachat::message::{Message, Viewtype};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{blocks, bot, client_ip, unix_time, AppState};
+
+/// Sled tree mapping a device cookie value to its [`Device`].
+const DEVICES_TREE: &str = "devices";
+
+/// Name of the cookie holding the device token.
+const DEVICE_COOKIE: &str = "loginbot_device";
+
+const DEVICE_EXPIRY_IN_SECONDS: u64 = 30 * 24 * 60 * 60;
+
+/// A browser the user chose to trust.
+#[derive(Debug, Serialize, Deserialize)]
+struct Device {
+    /// Short id users refer to in `/revoke`.
+    id: String,
+    contact_id: u32,
+    /// The user's key when the browser was trusted; other keys invalidate it.
+    fingerprint: String,
+    user_agent: String,
+    /// Empty unless [`crate::BotConfig::trusted_proxy`] is set.
+    ip: String,
+    created: u64,
+    last_used: u64,
+    expires: u64,
+}
+
+/// Trust the browser that sent `headers` for `contact`.
+///
+/// Returns `jar` with the device cookie added; unchanged if `contact` has no key.
+pub(crate) fn trust(
+    state: &AppState,
+    jar: CookieJar,
+    contact: &Contact,
+    headers: &HeaderMap,
+) -> Result<CookieJar> {
+    let Some(fingerprint) = contact.fingerprint().map(|fp| fp.hex()) else {
+        return Ok(jar);
+    };
+    let token = uuid::Uuid::new_v4().simple().to_string();
+    let mut id = uuid::Uuid::new_v4().simple().to_string();
+    id.truncate(6);
+    let now = unix_time();
+    let device = Device {
+        id,
+        contact_id: contact.get_id().to_u32(),
+        fingerprint,
+        user_agent: headers
+            .get(header::USER_AGENT)
+            .and_then(|agent| agent.to_str().ok())
+            .unwrap_or("unknown browser")
+            .to_string(),
+        ip: client_ip(state, headers, None).unwrap_or_default(),
+        created: now,
+        last_used: now,
+        expires: now.saturating_add(DEVICE_EXPIRY_IN_SECONDS),
+    };
+    let tree = state.db.open_tree(DEVICES_TREE)?;
+    prune_expired(&tree)?;
+    tree.insert(&token, serde_json::to_vec(&device)?)?;
+    log::info!("{} trusted a new browser", contact.get_addr());
+    let secure = state
+        .config
+        .public_url
+        .as_deref()
+        .is_some_and(|url| url.starts_with("https:"));
+    let cookie = Cookie::build((DEVICE_COOKIE, token))
+        .path("/")
+        .http_only(true)
+        .secure(secure)
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::seconds(DEVICE_EXPIRY_IN_SECONDS as i64));
+    Ok(jar.add(cookie))
+}
+
+/// The user whose trusted browser sent the device cookie in `jar`, if still valid,
+/// and the time they logged in when trusting it.
+pub(crate) async fn trusted_contact(
+    state: &AppState,
+    jar: &CookieJar,
+) -> Result<Option<(Contact, u64)>> {
+    let Some(token) = jar
+        .get(DEVICE_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+    else {
+        return Ok(None);
+    };
+    let tree = state.db.open_tree(DEVICES_TREE)?;
+    let Some(mut device) = valid_device(&tree, &token)? else {
+        return Ok(None);
+    };
+    let contact = Contact::get_by_id(&state.dc_context, ContactId::new(device.contact_id)).await?;
+    let fingerprint = contact.fingerprint().map(|fp| fp.hex());
+    if !keeps_key(&tree, &token, &device, fingerprint.as_deref())? {
+        log::info!(
+            "forgetting a trusted browser of {}: the key changed",
+            contact.get_addr()
+        );
+        return Ok(None);
+    }
+    if blocks::is_blocked(&state.db, contact.get_addr(), fingerprint.as_deref())? {
+        return Ok(None);
+    }
+    device.last_used = unix_time();
+    tree.insert(&token, serde_json::to_vec(&device)?)?;
+    Ok(Some((contact, device.created)))
+}
+
+/// The device of `token`, removing it if it expired.
+fn valid_device(tree: &sled::Tree, token: &str) -> Result<Option<Device>> {
+    let Some(data) = tree.get(token)? else {
+        return Ok(None);
+    };
+    let device: Device = serde_json::from_slice(&data)?;
+    if device.expires <= unix_time() {
+        tree.remove(token)?;
+        return Ok(None);
+    }
+    Ok(Some(device))
+}
+
+/// Whether the user of `device` still has the key `fingerprint`; the device is removed if not.
+fn keeps_key(
+    tree: &sled::Tree,
+    token: &str,
+    device: &Device,
+    fingerprint: Option<&str>,
+) -> Result<bool> {
+    if fingerprint == Some(device.fingerprint.as_str()) {
+        return Ok(true);
+    }
+    tree.remove(token)?;
+    Ok(false)
+}
+
+/// Stop trusting the browser that sent `jar`, e.g. on logout.
+///
+/// Returns `jar` with the device cookie removed.
+pub(crate) fn forget(state: &AppState, jar: CookieJar) -> Result<CookieJar> {
+    if let Some(cookie) = jar.get(DEVICE_COOKIE) {
+        state.db.open_tree(DEVICES_TREE)?.remove(cookie.value())?;
+    }
+    Ok(jar.remove(Cookie::build(DEVICE_COOKIE).path("/")))
+}
+
+/// Answer `/devices` and `/revoke <id|all>` in `msg`, sent in a chat of
+/// `chattype`; other messages are ignored.
+///
+/// Only 1:1 chats get an answer, as the list shows browsers and IP addresses.
+pub(crate) async fn handle_message(
+    state: &AppState,
+    msg: &Message,
+    chattype: Chattype,
+) -> Result<()> {
+    let text = msg.get_text();
+    let mut args = text.split_whitespace();
+    let contact_id = msg.get_from_id();
+    let reply = match (args.next(), args.next()) {
+        (Some("/devices" | "/revoke"), _) if chattype != Chattype::Single => {
+            bot::PRIVATE_COMMAND.to_string()
+        }
+        (Some("/devices"), _) => list_text(state, contact_id)?,
+        (Some("/revoke"), Some(id)) => match revoke(&state.db, contact_id.to_u32(), id)? {
+            0 => format!("No trusted browser {id}. Send /devices to list them."),
+            1 => "Revoked 1 trusted browser.".to_string(),
+            revoked => format!("Revoked {revoked} trusted browsers."),
+        },
+        _ => return Ok(()),
+    };
+    let mut answer = Message::new(Viewtype::Text);
+    answer.set_text(reply);
+    answer.set_quote(&state.dc_context, Some(msg)).await?;
+    send_msg(&state.dc_context, msg.get_chat_id(), &mut answer).await?;
+    Ok(())
+}
+
+impl Device {
+    /// The IP address to show, empty unless it comes from a trusted proxy.
+    fn shown_ip(&self, state: &AppState) -> &str {
+        match state.config.trusted_proxy {
+            true => &self.ip,
+            false => "",
+        }
+    }
+}
+
+/// The trusted browsers of `contact_id` for the chat.
+fn list_text(state: &AppState, contact_id: ContactId) -> Result<String> {
+    let mut lines = Vec::new();
+    for entry in &state.db.open_tree(DEVICES_TREE)? {
+        let (_, data) = entry?;
+        let device: Device = serde_json::from_slice(&data)?;
+        if device.contact_id == contact_id.to_u32() && device.expires > unix_time() {
+            let from = match device.shown_ip(state) {
+                "" => String::new(),
+                ip => format!(" from {ip}"),
+            };
+            lines.push(format!(
+                "{}: {}{from}, trusted {}, last used {}",
+                device.id,
+                device.user_agent,
+                days_ago(device.created),
+                days_ago(device.last_used)
+            ));
+        }
+    }
+    if lines.is_empty() {
+        return Ok("You have no trusted browsers.".to_string());
+    }
+    lines.push("\nSend /revoke <id> or /revoke all to stop trusting browsers.".to_string());
+    Ok(format!("Trusted browsers:\n{}", lines.join("\n")))
+}
+
+/// The trusted browsers of `contact_id`, as shown in the approval app.
+pub(crate) fn summaries(state: &AppState, contact_id: ContactId) -> Result<Vec<Value>> {
+    let mut devices = Vec::new();
+    for entry in &state.db.open_tree(DEVICES_TREE)? {
+        let (_, data) = entry?;
+        let device: Device = serde_json::from_slice(&data)?;
+        if device.contact_id == contact_id.to_u32() && device.expires > unix_time() {
+            devices.push(json!({
+                "id": device.id,
+                "user_agent": device.user_agent,
+                "ip": device.shown_ip(state),
+                "created": device.created,
+                "last_used": device.last_used,
+            }));
+        }
+    }
+    Ok(devices)
+}
+
+/// Revoke the trusted browser `id` of `contact_id`, or all of them for `"all"`.
+pub(crate) fn revoke(db: &sled::Db, contact_id: u32, id: &str) -> Result<usize> {
+    let tree = db.open_tree(DEVICES_TREE)?;
+    let mut revoked: usize = 0;
+    for entry in &tree {
+        let (token, data) = entry?;
+        let device: Device = serde_json::from_slice(&data)?;
+        if device.contact_id == contact_id && (id == "all" || device.id == id) {
+            tree.remove(token)?;
+            revoked = revoked.saturating_add(1);
+        }
+    }
+    Ok(revoked)
+}
+
+/// `time` as today, yesterday or a number of days ago.
+pub(crate) fn days_ago(time: u64) -> String {
+    match unix_time().saturating_sub(time) / (24 * 60 * 60) {
+        0 => "today".to_string(),
+        1 => "yesterday".to_string(),
+        days => format!("{days} days ago"),
+    }
+}
+
+/// Drop devices whose expiry has passed.
+fn prune_expired(tree: &sled::Tree) -> Result<()> {
+    let now = unix_time();
+    for entry in tree {
+        let (key, data) = entry?;
+        let expired = serde_json::from_slice::<Device>(&data)
+            .map(|device| device.expires <= now)
+            .unwrap_or(true);
+        if expired {
+            tree.remove(key)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(tree: &sled::Tree, token: &str, id: &str, contact_id: u32, expires: u64) {
+        let device = Device {
+            id: id.to_string(),
+            contact_id,
+            fingerprint: "AAAA".to_string(),
+            user_agent: "Firefox".to_string(),
+            ip: String::new(),
+            created: 0,
+            last_used: 0,
+            expires,
+        };
+        tree.insert(token, serde_json::to_vec(&device).unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_expiry() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree(DEVICES_TREE).unwrap();
+        add(&tree, "valid", "a1", 10, unix_time() + 60);
+        add(&tree, "expired", "b2", 10, unix_time() - 1);
+        assert!(valid_device(&tree, "valid").unwrap().is_some());
+        assert!(valid_device(&tree, "expired").unwrap().is_none());
+        assert!(!tree.contains_key("expired").unwrap());
+        assert!(valid_device(&tree, "unknown").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_revoke() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree(DEVICES_TREE).unwrap();
+        let expires = unix_time() + 60;
+        add(&tree, "first", "a1", 10, expires);
+        add(&tree, "second", "b2", 10, expires);
+        add(&tree, "other", "c3", 11, expires);
+
+        // Users only revoke their own browsers.
+        assert_eq!(revoke(&db, 10, "c3").unwrap(), 0);
+        assert_eq!(revoke(&db, 10, "a1").unwrap(), 1);
+        assert!(!tree.contains_key("first").unwrap());
+        assert!(tree.contains_key("second").unwrap());
+        assert_eq!(revoke(&db, 10, "all").unwrap(), 1);
+        assert!(tree.contains_key("other").unwrap());
+    }
+
+    #[test]
+    fn test_key_change() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree(DEVICES_TREE).unwrap();
+        add(&tree, "token", "a1", 10, unix_time() + 60);
+        let device = valid_device(&tree, "token").unwrap().unwrap();
+        assert!(keeps_key(&tree, "token", &device, Some("AAAA")).unwrap());
+        assert!(tree.contains_key("token").unwrap());
+        assert!(!keeps_key(&tree, "token", &device, Some("BBBB")).unwrap());
+        assert!(!tree.contains_key("token").unwrap());
+        assert!(!keeps_key(&tree, "token", &device, None).unwrap());
+    }
+}
@@ -0,0 +1,223 @@
+//! DiscourseConnect provider: Discourse logs users in with the bot natively,
+//! without the OAuth2 plugin.
+//!
+//! Discourse sends the browser to `/discourse_connect?client_id=<client>`
+//! with an `sso` payload signed with the client secret. After the usual QR
+//! code login, the browser goes back to the payload's `return_sso_url` with
+//! the user's details, signed the same way.
+
+use anyhow::Result;
+use axum::{
+    extract::{Query, RawQuery, State},
+    http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::cookie::CookieJar;
+use data_encoding::{BASE64, HEXLOWER};
+use deltachat::contact::{Contact, ContactId};
+use serde::Deserialize;
+use tower_sessions::Session;
+
+use crate::{
+    canonical_addr, complete_protocol_login, consent, current_flow, devices, html, roles,
+    secrets_match, subjects, AppError, AppState, LoginOutcome, OAuthConfig, ProtocolLogin,
+};
+
+/// Query parameters of `/discourse_connect`.
+#[derive(Debug, Deserialize)]
+pub(crate) struct DiscourseConnectQuery {
+    /// The client, which has to enable `discourse_connect`.
+    client_id: String,
+    /// Base64 encoded request parameters, `nonce` and `return_sso_url`.
+    sso: String,
+    /// Hex HMAC-SHA256 of `sso`, keyed with the client secret.
+    sig: String,
+    /// The login flow the login page completed.
+    flow: Option<String>,
+    #[serde(default)]
+    remember: bool,
+    /// The answer of the consent page.
+    consent: Option<String>,
+}
+
+/// `GET /discourse_connect`: log the user in and send them back to Discourse.
+pub(crate) async fn get_discourse_connect(
+    Query(query): Query<DiscourseConnectQuery>,
+    RawQuery(raw_query): RawQuery,
+    State(state): State<AppState>,
+    session: Session,
+    jar: CookieJar,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let Some(client) = state
+        .config
+        .client(&query.client_id)
+        .filter(|client| client.discourse_connect)
+    else {
+        log::info!("/discourse_connect Invalid client_id: {}", query.client_id);
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+    let Some(request) = verify(&query.sso, &query.sig, &client.client_secret) else {
+        log::info!("/discourse_connect rejected a payload with an invalid signature");
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    };
+    let param = |name: &str| {
+        request
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+    let (Some(nonce), Some(return_sso_url)) = (param("nonce"), param("return_sso_url")) else {
+        log::info!("/discourse_connect payload lacks nonce or return_sso_url");
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+    if return_sso_url != client.redirect_uri {
+        log::info!("/discourse_connect Invalid return_sso_url: {return_sso_url}");
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+
+    let flow = current_flow(&state, &session, query.flow.clone())
+        .await?
+        .filter(|flow| flow.client_id == client.client_id && flow.contact_id.is_some());
+    let login = match &flow {
+        Some(flow) => Some(
+            Contact::get_by_id(
+                &state.dc_context,
+                ContactId::new(flow.contact_id.unwrap_or_default()),
+            )
+            .await?,
+        ),
+        None => devices::trusted_contact(&state, &jar)
+            .await?
+            .map(|(contact, _)| contact),
+    };
+    let Some(contact) = login else {
+        if param("prompt") == Some("none") {
+            log::info!("/discourse_connect cannot log in silently, returning failed");
+            let payload = [("nonce", nonce.to_string()), ("failed", "true".to_string())];
+            return Ok(redirect_back(client, return_sso_url, &payload)?);
+        }
+        log::info!("/discourse_connect showing login screen");
+        return Ok(Html::from(state.login_html).into_response());
+    };
+    let scopes = consent::requested_scopes(client, None);
+    let login = ProtocolLogin {
+        endpoint: "/discourse_connect",
+        client: Some(client),
+        access: &client.access,
+        name: client.name.as_deref().unwrap_or(&client.client_id),
+        flow,
+        scopes: &scopes,
+        consent: query.consent.as_deref(),
+        force_consent: false,
+        silent: false,
+        raw_query,
+        remember: query.remember,
+    };
+    let jar =
+        match complete_protocol_login(&state, &session, jar, &headers, &contact, login).await? {
+            LoginOutcome::Completed(jar) => jar,
+            LoginOutcome::Page(page) => return Ok(page),
+            LoginOutcome::ConsentDenied | LoginOutcome::ConsentRequired => {
+                return Ok(html::message_page(
+                    "Login cancelled",
+                    "You did not agree to share your data.",
+                )
+                .into_response())
+            }
+        };
+
+    let identity = canonical_addr(&state, &contact)?;
+    let scope = |name: &str| scopes.iter().any(|scope| scope == name);
+    let mut payload = vec![
+        ("nonce", nonce.to_string()),
+        ("external_id", subjects::of(&state.db, &identity)?),
+        ("email", identity.clone()),
+        ("require_activation", "false".to_string()),
+    ];
+    if scope("profile") {
+        let name = contact.get_name().to_string();
+        let username = match name.is_empty() {
+            true => identity.split('@').next().unwrap_or_default().to_string(),
+            false => name.clone(),
+        };
+        payload.push(("username", username));
+        payload.push(("name", name));
+    }
+    if scope("groups") {
+        let groups = roles::roles_of(&state, contact.get_id()).await?;
+        payload.push(("add_groups", groups.join(",")));
+    }
+    Ok((jar, redirect_back(client, return_sso_url, &payload)?).into_response())
+}
+
+/// The parameters of the DiscourseConnect payload `sso`, if `sig` is its
+/// signature with `secret`.
+fn verify(sso: &str, sig: &str, secret: &str) -> Option<Vec<(String, String)>> {
+    let expected = HEXLOWER.encode(&hmac_sha256::HMAC::mac(sso.as_bytes(), secret.as_bytes()));
+    if !secrets_match(&expected, &sig.to_lowercase()) {
+        return None;
+    }
+    // Discourse wraps the base64 lines.
+    let sso: String = sso.chars().filter(|c| !c.is_whitespace()).collect();
+    let decoded = BASE64.decode(sso.as_bytes()).ok()?;
+    Some(url::form_urlencoded::parse(&decoded).into_owned().collect())
+}
+
+/// The `sso` payload of `params` and its `sig` with `secret`.
+fn sign(params: &[(&str, String)], secret: &str) -> (String, String) {
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(params)
+        .finish();
+    let sso = BASE64.encode(query.as_bytes());
+    let sig = HEXLOWER.encode(&hmac_sha256::HMAC::mac(sso.as_bytes(), secret.as_bytes()));
+    (sso, sig)
+}
+
+/// Send the browser to `return_sso_url` with the signed `payload`.
+fn redirect_back(
+    client: &OAuthConfig,
+    return_sso_url: &str,
+    payload: &[(&str, String)],
+) -> Result<Response> {
+    let (sso, sig) = sign(payload, &client.client_secret);
+    let mut url = url::Url::parse(return_sso_url)?;
+    url.query_pairs_mut()
+        .append_pair("sso", &sso)
+        .append_pair("sig", &sig);
+    Ok(Redirect::to(url.as_str()).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_discourse_example() {
+        let params = verify(
+            "bm9uY2U9Y2I2ODI1MWVlZmI1MjExZTU4YzAwZmYxMzk1ZjBjMGI=\n",
+            "2828aa29899722b35a2f191d34ef9b3ce695e0e6eeec47deb46d588d70c7cb56",
+            "d836444a9e4084d5b224a60c208dce14",
+        )
+        .unwrap();
+        assert_eq!(
+            params,
+            [(
+                "nonce".to_string(),
+                "cb68251eefb5211e58c00ff1395f0c0b".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let payload = [
+            ("nonce", "abc".to_string()),
+            ("email", "alice@example.org".to_string()),
+        ];
+        let (sso, sig) = sign(&payload, "secret");
+        let params = verify(&sso, &sig, "secret").unwrap();
+        assert_eq!(params.len(), 2);
+        assert!(verify(&sso, &sig, "other secret").is_none());
+    }
+}
@@ -0,0 +1,298 @@
+//! Fallback login by email for users without Delta Chat at hand.
+//!
+//! The bot emails a one-time link to the address the user entered. Logins
+//! completed this way are marked with `amr: ["email"]`, because they only
+//! prove access to the mailbox, not to the user's Delta Chat key.
+
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Form, Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use deltachat::chat::{send_text_msg, ChatId};
+use deltachat::contact::{Contact, ContactId, Origin};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tower_sessions::Session;
+
+use crate::flow::Flow;
+use crate::registration::RegistrationMode;
+use crate::{
+    blocks, client_ip, current_flow, flow_client, html, normalize_addr, policy, return_link, stats,
+    throttle, unix_time, AppError, AppState,
+};
+
+/// Sled tree mapping an emailed token to its [`EmailLink`].
+const EMAIL_LINKS_TREE: &str = "email_links";
+
+const EMAIL_LINK_EXPIRY_IN_SECONDS: u64 = 15 * 60;
+
+/// A login link emailed to a user, valid once.
+#[derive(Debug, Serialize, Deserialize)]
+struct EmailLink {
+    flow_id: String,
+    contact_id: u32,
+    expires: u64,
+    /// Whether the contact was created for this link, to be deleted again
+    /// with its chat if the link expires unused.
+    #[serde(default)]
+    created_contact: bool,
+}
+
+/// Form posted by the login page to request a login link by email.
+#[derive(Debug, Deserialize)]
+pub(crate) struct EmailForm {
+    addr: String,
+    flow: Option<String>,
+}
+
+/// `POST /emailLogin`: email a login link for the current flow to `addr`.
+pub(crate) async fn post_email_login(
+    State(state): State<AppState>,
+    session: Session,
+    headers: HeaderMap,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    Form(form): Form<EmailForm>,
+) -> Result<Response, AppError> {
+    let error = |status: StatusCode, error: &str| {
+        Ok((status, Json(json!({ "error": error }))).into_response())
+    };
+    let Some(mut flow) = current_flow(&state, &session, form.flow).await? else {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "the login expired, please reload the page",
+        );
+    };
+    let client = flow_client(&state, &flow);
+    if !client.email_fallback || flow.securejoin_required || state.config.public_url.is_none() {
+        return error(StatusCode::NOT_FOUND, "login by email is not available");
+    }
+    if flow.email_sent {
+        return error(StatusCode::BAD_REQUEST, "a login link was already sent");
+    }
+    let Some(addr) = normalize_addr(&form.addr) else {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "please enter a valid email address",
+        );
+    };
+    let ip = client_ip(&state, &headers, peer.map(|peer| peer.0));
+    let within_limits = throttle::allow(&state.db, &throttle::EMAIL_PER_ADDR, &addr)?
+        && match &ip {
+            Some(ip) => throttle::allow(&state.db, &throttle::EMAIL_PER_IP, ip)?,
+            None => true,
+        };
+    if !within_limits {
+        log::info!(
+            "/emailLogin throttled {addr} from {}",
+            ip.as_deref().unwrap_or("unknown")
+        );
+        return error(
+            StatusCode::TOO_MANY_REQUESTS,
+            "too many login links requested, please try again later",
+        );
+    }
+    let fingerprint = policy::known_fingerprint(&state.db, &addr)?;
+    let denial =
+        if blocks::is_blocked_for(&state.db, &client.client_id, &addr, fingerprint.as_deref())? {
+            Some("you are blocked".to_string())
+        } else if state.config.registration != RegistrationMode::Open
+            && !state
+                .db
+                .open_tree("addr_fingerprints")?
+                .contains_key(&addr)?
+        {
+            Some("log in with Delta Chat once before using email".to_string())
+        } else {
+            client.access.check(&state.db, &addr, None)?
+        };
+    if let Some(reason) = denial {
+        log::info!("/emailLogin denied {addr}: {reason}");
+        return error(StatusCode::FORBIDDEN, &format!("login denied: {reason}"));
+    }
+
+    let links = state.db.open_tree(EMAIL_LINKS_TREE)?;
+    prune_expired(&state, &links).await;
+    let existing = Contact::lookup_id_by_addr(&state.dc_context, &addr, Origin::Unknown).await?;
+    let contact_id = match existing {
+        Some(contact_id) => contact_id,
+        None => Contact::create(&state.dc_context, "", &addr).await?,
+    };
+    let token = uuid::Uuid::new_v4().simple().to_string();
+    let link = EmailLink {
+        flow_id: flow.id.clone(),
+        contact_id: contact_id.to_u32(),
+        expires: unix_time().saturating_add(EMAIL_LINK_EXPIRY_IN_SECONDS),
+        created_contact: existing.is_none(),
+    };
+    links.insert(&token, serde_json::to_vec(&link)?)?;
+    flow.email_sent = true;
+    flow.save(&state.db)?;
+
+    let public_url = state.config.public_url.as_deref().unwrap_or_default();
+    let name = client.name.as_deref().unwrap_or(&client.client_id);
+    let chat_id = ChatId::create_for_contact(&state.dc_context, contact_id).await?;
+    let text = format!(
+        "Open this link within {} minutes to log in to {name}:\n\n{}/emailLogin/{token}\n\n\
+        If you did not ask for this, ignore this email.",
+        EMAIL_LINK_EXPIRY_IN_SECONDS / 60,
+        public_url.trim_end_matches('/')
+    );
+    send_text_msg(&state.dc_context, chat_id, text).await?;
+    log::info!("/emailLogin sent a login link to {addr}");
+    Ok((StatusCode::OK, Json(json!({ "sent": true }))).into_response())
+}
+
+/// `GET /emailLogin/:token`: complete the flow an emailed link belongs to.
+pub(crate) async fn get_email_login(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<Response, AppError> {
+    let links = state.db.open_tree(EMAIL_LINKS_TREE)?;
+    let link = links
+        .remove(&token)?
+        .map(|data| serde_json::from_slice::<EmailLink>(&data))
+        .transpose()?
+        .filter(|link| link.expires > unix_time());
+    prune_expired(&state, &links).await;
+    let flow = match &link {
+        Some(link) => Flow::load(&state.db, &link.flow_id)?,
+        None => None,
+    };
+    let (Some(link), Some(mut flow)) = (link, flow) else {
+        log::info!("/emailLogin got an unknown or expired link");
+        return Ok((
+            StatusCode::NOT_FOUND,
+            html::message_page(
+                "Link expired",
+                "This login link is unknown, already used or expired. Please start the login again.",
+            ),
+        )
+            .into_response());
+    };
+    let contact = Contact::get_by_id(&state.dc_context, ContactId::new(link.contact_id)).await?;
+    let fingerprint = policy::known_fingerprint(&state.db, contact.get_addr())?;
+    if blocks::is_blocked_for(
+        &state.db,
+        &flow.client_id,
+        contact.get_addr(),
+        fingerprint.as_deref(),
+    )? {
+        return Ok((
+            StatusCode::FORBIDDEN,
+            html::message_page("Login denied", "Sorry, you are blocked."),
+        )
+            .into_response());
+    }
+    flow.contact_id = Some(link.contact_id);
+    flow.email_login = true;
+    flow.sent = true;
+    flow.auth_time = unix_time();
+    flow.save(&state.db)?;
+    stats::count(&state.db, stats::LOGINS)?;
+    log::info!("/emailLogin confirmed {}", contact.get_addr());
+
+    let mut body = "<p>Your email address is confirmed. \
+        Return to the window you started the login in.</p>"
+        .to_string();
+    if let Some(link) = return_link(&state, &flow)? {
+        body += &format!(
+            r#"<a class="manual-link" href="{}">Continue here instead</a>"#,
+            html::escape(&link)
+        );
+    }
+    Ok(html::page("Logged in", &body).into_response())
+}
+
+/// Drop email links whose expiry has passed, and the contacts and chats
+/// created for them, so that addresses typed into the form do not pile up.
+///
+/// Errors are logged: they must not keep users from logging in.
+async fn prune_expired(state: &AppState, links: &sled::Tree) {
+    let unused = match take_expired(links) {
+        Ok(unused) => unused,
+        Err(err) => {
+            log::warn!("failed to prune email links: {err:#}");
+            return;
+        }
+    };
+    for contact_id in unused {
+        let contact_id = ContactId::new(contact_id);
+        let res = async {
+            if let Some(chat_id) = ChatId::lookup_by_contact(&state.dc_context, contact_id).await? {
+                chat_id.delete(&state.dc_context).await?;
+            }
+            Contact::delete(&state.dc_context, contact_id).await
+        };
+        if let Err(err) = res.await {
+            log::warn!("failed to delete unused contact {contact_id}: {err:#}");
+        }
+    }
+}
+
+/// Remove the expired links from `links`; returns the contacts created for
+/// them that no pending link uses.
+fn take_expired(links: &sled::Tree) -> anyhow::Result<Vec<u32>> {
+    let now = unix_time();
+    let mut unused = Vec::new();
+    let mut pending = Vec::new();
+    for entry in links {
+        let (key, data) = entry?;
+        match serde_json::from_slice::<EmailLink>(&data) {
+            Ok(link) if link.expires > now => pending.push(link.contact_id),
+            Ok(link) => {
+                links.remove(key)?;
+                if link.created_contact {
+                    unused.push(link.contact_id);
+                }
+            }
+            Err(_) => {
+                links.remove(key)?;
+            }
+        }
+    }
+    unused.retain(|contact_id| !pending.contains(contact_id));
+    unused.sort_unstable();
+    unused.dedup();
+    Ok(unused)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(links: &sled::Tree, token: &str, contact_id: u32, expires: u64, created: bool) {
+        let link = EmailLink {
+            flow_id: "flow".to_string(),
+            contact_id,
+            expires,
+            created_contact: created,
+        };
+        links
+            .insert(token, serde_json::to_vec(&link).unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_take_expired() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let links = db.open_tree(EMAIL_LINKS_TREE).unwrap();
+        let now = unix_time();
+        insert(&links, "a", 10, now - 1, true);
+        insert(&links, "b", 10, now - 1, true);
+        // Contacts that existed before are kept.
+        insert(&links, "c", 11, now - 1, false);
+        // So are contacts a pending link still needs.
+        insert(&links, "d", 12, now - 1, true);
+        insert(&links, "e", 12, now + 60, false);
+        insert(&links, "f", 13, now + 60, true);
+
+        assert_eq!(take_expired(&links).unwrap(), [10]);
+        let left: Vec<_> = links.iter().keys().map(Result::unwrap).collect();
+        assert_eq!(left, [&b"e"[..], &b"f"[..]]);
+        assert!(take_expired(&links).unwrap().is_empty());
+    }
+}
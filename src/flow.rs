@@ -0,0 +1,150 @@
+//! Login flows: the server-side state of one login attempt.
+//!
+//! Each flow is persisted in sled under a random id that the login page
+//! carries in URLs and polling requests, so that logins also work without
+//! cookies and can be finished on another device. The session cookie only
+//! remembers the id as a convenience.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::unix_time;
+
+/// Sled tree mapping a flow id to its [`Flow`].
+const FLOWS_TREE: &str = "flows";
+
+const FLOW_EXPIRY_IN_SECONDS: u64 = 15 * 60;
+
+/// State of one login attempt.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Flow {
+    /// Random id of the flow, the key in [`FLOWS_TREE`].
+    #[serde(skip)]
+    pub id: String,
+    /// The relying party the user logs in to.
+    pub client_id: String,
+    /// Chat id of the login group whose invite QR code the browser shows,
+    /// or of the chat a magic login link was requested in.
+    pub group_id: u32,
+    /// Whether `/checkStatus` has finished admitting or denying the member.
+    #[serde(default)]
+    pub sent: bool,
+    /// The admitted user.
+    pub contact_id: Option<u32>,
+    /// Why the login was denied.
+    pub denied: Option<String>,
+    /// Whether the client's key policy has been checked.
+    #[serde(default)]
+    pub key_checked: bool,
+    /// Unix time after which the flow is dropped.
+    pub expires: u64,
+    /// Site the login page was loaded from, shown to the user in the chat.
+    #[serde(default)]
+    pub origin: String,
+    /// The relying party's OAuth2 `state` of the `/authorize` request.
+    pub oauth_state: Option<String>,
+    /// Whether a login link was emailed for this flow.
+    #[serde(default)]
+    pub email_sent: bool,
+    /// Whether the user logged in with the emailed link.
+    #[serde(default)]
+    pub email_login: bool,
+    /// Unix time at which the user was authenticated.
+    #[serde(default)]
+    pub auth_time: u64,
+    /// The relying party's OpenID Connect `nonce`, for the link back to the browser.
+    #[serde(default)]
+    pub nonce: Option<String>,
+    /// Whether the bot added the user named by `login_hint` to the login
+    /// group instead of waiting for a QR code scan.
+    #[serde(default)]
+    pub hinted: bool,
+    /// Whether the relying party's `acr_values` rule out email logins.
+    #[serde(default)]
+    pub securejoin_required: bool,
+}
+
+impl Flow {
+    /// Start a flow for `client_id` with login group `group_id` and persist it.
+    pub fn create(db: &sled::Db, client_id: &str, group_id: u32) -> Result<Flow> {
+        let tree = db.open_tree(FLOWS_TREE)?;
+        prune_expired(&tree)?;
+        let flow = Flow {
+            id: uuid::Uuid::new_v4().simple().to_string(),
+            client_id: client_id.to_string(),
+            group_id,
+            sent: false,
+            contact_id: None,
+            denied: None,
+            key_checked: false,
+            expires: unix_time().saturating_add(FLOW_EXPIRY_IN_SECONDS),
+            origin: String::new(),
+            oauth_state: None,
+            email_sent: false,
+            email_login: false,
+            auth_time: 0,
+            nonce: None,
+            hinted: false,
+            securejoin_required: false,
+        };
+        flow.save(db)?;
+        Ok(flow)
+    }
+
+    /// The flow with the given `id`, unless it is unknown or expired.
+    pub fn load(db: &sled::Db, id: &str) -> Result<Option<Flow>> {
+        let Some(data) = db.open_tree(FLOWS_TREE)?.get(id)? else {
+            return Ok(None);
+        };
+        let flow = Flow {
+            id: id.to_string(),
+            ..serde_json::from_slice(&data)?
+        };
+        Ok(Some(flow).filter(|flow| flow.expires > unix_time()))
+    }
+
+    /// The unfinished flow whose login group is `group_id`, if any.
+    pub fn find_unfinished(db: &sled::Db, group_id: u32) -> Result<Option<Flow>> {
+        for entry in &db.open_tree(FLOWS_TREE)? {
+            let (id, data) = entry?;
+            let Ok(flow) = serde_json::from_slice::<Flow>(&data) else {
+                continue;
+            };
+            if flow.group_id == group_id && !flow.sent && flow.expires > unix_time() {
+                return Ok(Some(Flow {
+                    id: String::from_utf8(id.to_vec())?,
+                    ..flow
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Persist changes to the flow.
+    pub fn save(&self, db: &sled::Db) -> Result<()> {
+        db.open_tree(FLOWS_TREE)?
+            .insert(&self.id, serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+
+    /// Delete the flow once it has been used.
+    pub fn remove(&self, db: &sled::Db) -> Result<()> {
+        db.open_tree(FLOWS_TREE)?.remove(&self.id)?;
+        Ok(())
+    }
+}
+
+/// Drop flows whose expiry has passed.
+fn prune_expired(tree: &sled::Tree) -> Result<()> {
+    let now = unix_time();
+    for entry in tree {
+        let (key, data) = entry?;
+        let expired = serde_json::from_slice::<Flow>(&data)
+            .map(|flow| flow.expires <= now)
+            .unwrap_or(true);
+        if expired {
+            tree.remove(key)?;
+        }
+    }
+    Ok(())
+}
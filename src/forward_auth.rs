@@ -0,0 +1,423 @@
+//! Forward authentication: a reverse proxy asks `/auth/verify` whether a
+//! request may pass, like nginx `auth_request` or the forward auth of
+//! Traefik and Caddy, so the bot protects web apps without an OAuth client.
+//!
+//! Users without a valid session cookie are sent to `/auth/start`, which
+//! runs the usual QR code login, sets the cookie and sends them back.
+
+use anyhow::Result;
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use deltachat::contact::{Contact, ContactId};
+use serde::{Deserialize, Serialize};
+use tower_sessions::Session;
+use url::Url;
+
+use crate::flow::Flow;
+use crate::{
+    blocks, canonical_addr, complete_protocol_login, devices, html, roles, unix_time, AccessRules,
+    AppError, AppState, LoginOutcome, ProtocolLogin,
+};
+
+/// Sled tree mapping a session cookie value to its [`AuthSession`].
+const SESSIONS_TREE: &str = "forward_auth_sessions";
+
+/// Name of the cookie holding the session token.
+const SESSION_COOKIE: &str = "loginbot_auth";
+
+const SESSION_EXPIRY_IN_SECONDS: u64 = 12 * 60 * 60;
+
+/// Configuration of `/auth/verify` and `/auth/start`.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct ForwardAuthConfig {
+    /// Domain of the session cookie, e.g. `"example.org"` to cover all
+    /// apps on its subdomains. Defaults to the bot's own host.
+    ///
+    /// Users are only sent back to hosts within this domain.
+    pub cookie_domain: Option<String>,
+    /// Who may log in to the protected apps.
+    #[serde(default)]
+    pub access: AccessRules,
+}
+
+/// A browser logged in to the protected apps.
+#[derive(Debug, Serialize, Deserialize)]
+struct AuthSession {
+    contact_id: u32,
+    /// The user's key at login; other keys end the session.
+    fingerprint: String,
+    expires: u64,
+}
+
+/// Query of `/auth/verify`.
+#[derive(Debug, Deserialize)]
+pub(crate) struct VerifyQuery {
+    /// The URL the user requested, if the proxy does not send it in headers.
+    rd: Option<String>,
+}
+
+/// Query of `/auth/start`.
+#[derive(Debug, Deserialize)]
+pub(crate) struct StartQuery {
+    /// Where to send the user after the login.
+    rd: String,
+    /// The login flow the login page completed.
+    flow: Option<String>,
+    #[serde(default)]
+    remember: bool,
+}
+
+/// `GET /auth/verify`: 200 with the user in `X-Auth-*` headers, or 401 with
+/// the login URL in `Location`.
+pub(crate) async fn get_verify(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    headers: HeaderMap,
+    Query(query): Query<VerifyQuery>,
+) -> Result<Response, AppError> {
+    if state.config.forward_auth.is_none() {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    if let Some(contact) = session_contact(&state, &jar).await? {
+        let mut response = StatusCode::OK.into_response();
+        let name = contact.get_name().to_string();
+        let identity = canonical_addr(&state, &contact)?;
+        let user = if name.is_empty() { &identity } else { &name };
+        let groups = roles::roles_of(&state, contact.get_id()).await?.join(",");
+        for (name, value) in [
+            ("x-auth-email", &identity),
+            ("x-auth-user", user),
+            ("x-auth-groups", &groups),
+        ] {
+            if let Ok(value) = HeaderValue::from_bytes(value.as_bytes()) {
+                response
+                    .headers_mut()
+                    .insert(HeaderName::from_static(name), value);
+            }
+        }
+        return Ok(response);
+    }
+
+    let requested = query.rd.or_else(|| forwarded_url(&headers));
+    let mut start = format!(
+        "{}/auth/start",
+        state
+            .config
+            .public_url
+            .as_deref()
+            .unwrap_or_default()
+            .trim_end_matches('/')
+    );
+    if let Some(requested) = requested {
+        start += "?";
+        start += &url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("rd", &requested)
+            .finish();
+    }
+    Ok((
+        StatusCode::UNAUTHORIZED,
+        [(header::LOCATION, start)],
+        "log in with Delta Chat first",
+    )
+        .into_response())
+}
+
+/// `GET /auth/start`: log the user in and send them back to `rd`.
+pub(crate) async fn get_start(
+    State(state): State<AppState>,
+    session: Session,
+    jar: CookieJar,
+    headers: HeaderMap,
+    Query(query): Query<StartQuery>,
+) -> Result<Response, AppError> {
+    let Some(config) = &state.config.forward_auth else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let target = Url::parse(&query.rd).ok().filter(|target| {
+        is_allowed_target(
+            target,
+            config.cookie_domain.as_deref(),
+            state.config.public_url.as_deref(),
+        )
+    });
+    let Some(target) = target else {
+        log::info!("/auth/start rejected rd {}", query.rd);
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            html::message_page("Login failed", "The page to return to is not allowed."),
+        )
+            .into_response());
+    };
+    if session_contact(&state, &jar).await?.is_some() {
+        return Ok(Redirect::to(target.as_str()).into_response());
+    }
+
+    let flow = match &query.flow {
+        Some(flow_id) => Flow::load(&state.db, flow_id)?.filter(|flow| flow.contact_id.is_some()),
+        None => None,
+    };
+    let contact = match &flow {
+        Some(flow) => Some(
+            Contact::get_by_id(
+                &state.dc_context,
+                ContactId::new(flow.contact_id.unwrap_or_default()),
+            )
+            .await?,
+        ),
+        None => devices::trusted_contact(&state, &jar)
+            .await?
+            .map(|(contact, _)| contact),
+    };
+    let Some(contact) = contact else {
+        log::info!("/auth/start showing login screen");
+        return Ok(Html::from(state.login_html).into_response());
+    };
+    let Some(fingerprint) = contact.fingerprint().map(|fp| fp.hex()) else {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    };
+    let login = ProtocolLogin {
+        endpoint: "/auth/start",
+        client: None,
+        access: &config.access,
+        name: target.host_str().unwrap_or_default(),
+        flow,
+        scopes: &[],
+        consent: None,
+        force_consent: false,
+        silent: false,
+        raw_query: None,
+        remember: query.remember,
+    };
+    let jar =
+        match complete_protocol_login(&state, &session, jar, &headers, &contact, login).await? {
+            LoginOutcome::Completed(jar) => jar,
+            LoginOutcome::Page(page) => return Ok(page),
+            // Without a client there is no consent to ask for.
+            LoginOutcome::ConsentDenied | LoginOutcome::ConsentRequired => {
+                return Ok(StatusCode::FORBIDDEN.into_response())
+            }
+        };
+
+    let token = uuid::Uuid::new_v4().simple().to_string();
+    let auth_session = AuthSession {
+        contact_id: contact.get_id().to_u32(),
+        fingerprint,
+        expires: unix_time().saturating_add(SESSION_EXPIRY_IN_SECONDS),
+    };
+    let sessions = state.db.open_tree(SESSIONS_TREE)?;
+    prune_expired(&sessions)?;
+    sessions.insert(&token, serde_json::to_vec(&auth_session)?)?;
+    let secure = state
+        .config
+        .public_url
+        .as_deref()
+        .is_some_and(|url| url.starts_with("https:"));
+    let mut cookie = Cookie::build((SESSION_COOKIE, token))
+        .path("/")
+        .http_only(true)
+        .secure(secure)
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::seconds(SESSION_EXPIRY_IN_SECONDS as i64));
+    if let Some(domain) = &config.cookie_domain {
+        cookie = cookie.domain(domain.trim_start_matches('.').to_string());
+    }
+    Ok((jar.add(cookie), Redirect::to(target.as_str())).into_response())
+}
+
+/// End the session of the browser that sent `jar` and, if given, every
+/// session of `identity`, e.g. on logout or account deletion.
+///
+/// Returns `jar` with the session cookie removed.
+pub(crate) fn forget(
+    state: &AppState,
+    jar: CookieJar,
+    identity: Option<&str>,
+) -> Result<CookieJar> {
+    let sessions = state.db.open_tree(SESSIONS_TREE)?;
+    if let Some(cookie) = jar.get(SESSION_COOKIE) {
+        sessions.remove(cookie.value())?;
+    }
+    if let Some(identity) = identity {
+        let ended = remove_sessions(&state.db, identity)?;
+        log::info!("ended {ended} forward auth sessions of {identity}");
+    }
+    let mut cookie = Cookie::build(SESSION_COOKIE).path("/");
+    if let Some(domain) = state
+        .config
+        .forward_auth
+        .as_ref()
+        .and_then(|config| config.cookie_domain.as_deref())
+    {
+        cookie = cookie.domain(domain.trim_start_matches('.').to_string());
+    }
+    Ok(jar.remove(cookie))
+}
+
+/// Remove the sessions whose key belongs to `identity`; returns how many.
+fn remove_sessions(db: &sled::Db, identity: &str) -> Result<usize> {
+    let sessions = db.open_tree(SESSIONS_TREE)?;
+    let identities = db.open_tree("identities")?;
+    let mut removed: usize = 0;
+    for entry in &sessions {
+        let (token, data) = entry?;
+        let Ok(auth_session) = serde_json::from_slice::<AuthSession>(&data) else {
+            continue;
+        };
+        if identities
+            .get(&auth_session.fingerprint)?
+            .is_some_and(|canonical| canonical.as_ref() == identity.as_bytes())
+        {
+            sessions.remove(token)?;
+            removed = removed.saturating_add(1);
+        }
+    }
+    Ok(removed)
+}
+
+/// The user of the session cookie in `jar`, if the session is still valid.
+pub(crate) async fn session_contact(state: &AppState, jar: &CookieJar) -> Result<Option<Contact>> {
+    let Some(token) = jar.get(SESSION_COOKIE).map(|cookie| cookie.value()) else {
+        return Ok(None);
+    };
+    let sessions = state.db.open_tree(SESSIONS_TREE)?;
+    let Some(data) = sessions.get(token)? else {
+        return Ok(None);
+    };
+    let auth_session: AuthSession = serde_json::from_slice(&data)?;
+    if auth_session.expires <= unix_time() {
+        sessions.remove(token)?;
+        return Ok(None);
+    }
+    let contact =
+        Contact::get_by_id(&state.dc_context, ContactId::new(auth_session.contact_id)).await?;
+    let fingerprint = contact.fingerprint().map(|fp| fp.hex());
+    if fingerprint.as_deref() != Some(auth_session.fingerprint.as_str())
+        || blocks::is_blocked(&state.db, contact.get_addr(), fingerprint.as_deref())?
+    {
+        sessions.remove(token)?;
+        return Ok(None);
+    }
+    Ok(Some(contact))
+}
+
+/// The URL the user requested, as told by the proxy: `X-Original-URL` of
+/// nginx or `X-Forwarded-Proto`, `-Host` and `-Uri` of Traefik and Caddy.
+fn forwarded_url(headers: &HeaderMap) -> Option<String> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    if let Some(url) = header("x-original-url") {
+        return Some(url.to_string());
+    }
+    Some(format!(
+        "{}://{}{}",
+        header("x-forwarded-proto").unwrap_or("https"),
+        header("x-forwarded-host")?,
+        header("x-forwarded-uri").unwrap_or("/")
+    ))
+}
+
+/// Whether `/auth/start` may send the browser to `target`: a host within
+/// `cookie_domain`, or the bot's own host without it.
+fn is_allowed_target(target: &Url, cookie_domain: Option<&str>, public_url: Option<&str>) -> bool {
+    if !matches!(target.scheme(), "http" | "https") {
+        return false;
+    }
+    let Some(host) = target.host_str() else {
+        return false;
+    };
+    match cookie_domain {
+        Some(domain) => {
+            let domain = domain.trim_start_matches('.');
+            host.eq_ignore_ascii_case(domain)
+                || host
+                    .to_ascii_lowercase()
+                    .ends_with(&format!(".{}", domain.to_ascii_lowercase()))
+        }
+        None => public_url
+            .and_then(|url| Url::parse(url).ok())
+            .is_some_and(|url| url.host_str() == Some(host)),
+    }
+}
+
+/// Drop sessions whose expiry has passed.
+fn prune_expired(sessions: &sled::Tree) -> Result<()> {
+    let now = unix_time();
+    for entry in sessions {
+        let (key, data) = entry?;
+        let expired = serde_json::from_slice::<AuthSession>(&data)
+            .map(|auth_session| auth_session.expires <= now)
+            .unwrap_or(true);
+        if expired {
+            sessions.remove(key)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_targets() {
+        let url = |url: &str| Url::parse(url).unwrap();
+        assert!(is_allowed_target(
+            &url("https://wiki.example.org/page"),
+            Some(".example.org"),
+            None
+        ));
+        assert!(is_allowed_target(
+            &url("https://example.org/"),
+            Some("example.org"),
+            None
+        ));
+        assert!(!is_allowed_target(
+            &url("https://evilexample.org/"),
+            Some("example.org"),
+            None
+        ));
+        assert!(!is_allowed_target(
+            &url("javascript:alert(1)"),
+            Some("example.org"),
+            None
+        ));
+        assert!(is_allowed_target(
+            &url("https://login.example.org/x"),
+            None,
+            Some("https://login.example.org")
+        ));
+        assert!(!is_allowed_target(
+            &url("https://wiki.example.org/"),
+            None,
+            None
+        ));
+    }
+
+    #[test]
+    fn test_remove_sessions() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let identities = db.open_tree("identities").unwrap();
+        identities.insert("AAAA", "alice@example.org").unwrap();
+        identities.insert("BBBB", "alice@example.org").unwrap();
+        identities.insert("CCCC", "bob@example.org").unwrap();
+        let sessions = db.open_tree(SESSIONS_TREE).unwrap();
+        for (token, fingerprint) in [("one", "AAAA"), ("two", "BBBB"), ("three", "CCCC")] {
+            let auth_session = AuthSession {
+                contact_id: 10,
+                fingerprint: fingerprint.to_string(),
+                expires: unix_time() + 60,
+            };
+            sessions
+                .insert(token, serde_json::to_vec(&auth_session).unwrap())
+                .unwrap();
+        }
+        assert_eq!(remove_sessions(&db, "alice@example.org").unwrap(), 2);
+        assert!(!sessions.contains_key("one").unwrap());
+        assert!(!sessions.contains_key("two").unwrap());
+        assert!(sessions.contains_key("three").unwrap());
+        assert_eq!(remove_sessions(&db, "alice@example.org").unwrap(), 0);
+    }
+}
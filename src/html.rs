@@ -0,0 +1,47 @@
+//! Small server-rendered pages sharing the look of `login.html`.
+
+use axum::response::Html;
+
+/// Escape `text` for use in HTML element content and attribute values.
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Wrap already escaped `body` into a full page titled `title`.
+pub(crate) fn page(title: &str, body: &str) -> Html<String> {
+    let title = escape(title);
+    Html(format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+  <head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <link rel="stylesheet" href="/styles.css">
+    <title>{title}</title>
+  </head>
+  <body class='login'>
+    <main>
+      <h1>{title}</h1>
+      {body}
+    </main>
+  </body>
+</html>
+"#
+    ))
+}
+
+/// A page showing a single plain-text `message`.
+pub(crate) fn message_page(title: &str, message: &str) -> Html<String> {
+    page(title, &format!("<p>{}</p>", escape(message)))
+}
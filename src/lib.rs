@@ -30,14 +30,46 @@
 //!
 //! Exposes [`build_router`] which wires up all HTTP handlers.
 
-use serde::Deserialize;
+mod access;
+mod account;
+mod admin;
+mod admin_api;
+mod approval;
+mod blocks;
+mod bot;
+mod cas;
+mod confirmation;
+mod consent;
+mod devices;
+mod discourse;
+mod email;
+mod flow;
+mod forward_auth;
+mod html;
+mod logout;
+mod magic;
+mod oidc;
+mod policy;
+mod registration;
+mod roles;
+mod saml;
+mod scim;
+mod stats;
+mod subjects;
+mod throttle;
+mod webfinger;
+mod webhooks;
 
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use anyhow::{Context as _, Error};
-use deltachat::chat::{create_group, get_chat_contacts, send_msg, ChatId};
-use deltachat::contact::{Contact, ContactId};
+use deltachat::chat::{add_contact_to_chat, create_group, get_chat_contacts, send_msg, ChatId};
+use deltachat::contact::{Contact, ContactId, Origin};
 use deltachat::context::Context;
 use deltachat::message::{Message, Viewtype};
 use deltachat::qr_code_generator::get_securejoin_qr_svg;
@@ -46,14 +78,18 @@ use serde_json::{json, Value};
 
 use axum::{
     body::Bytes,
-    extract::{Form, Query, State},
-    http::StatusCode,
+    extract::{ConnectInfo, Form, Query, RawQuery, State},
+    http::{header, HeaderMap, StatusCode},
     response::{Html, IntoResponse, Redirect, Response},
     routing::{get, head, post},
     Json, Router,
 };
 use axum_extra::{
-    headers::{authorization::Basic, Authorization, ContentType},
+    extract::cookie::CookieJar,
+    headers::{
+        authorization::{Basic, Bearer},
+        Authorization, ContentType,
+    },
     TypedHeader,
 };
 use mime::Mime;
@@ -61,7 +97,21 @@ use tower::ServiceBuilder;
 use tower_http::{services::ServeDir, trace::TraceLayer};
 use tower_sessions::{MemoryStore, Session, SessionManagerLayer};
 
+use crate::flow::Flow;
+use crate::policy::Decision;
+use crate::registration::Admission;
+
+pub use access::{AccessRule, AccessRules};
+pub use admin::{run_daily_stats, setup_admin_group, AdminConfig};
+pub use bot::handle_event;
+pub use confirmation::Confirmation;
 pub use deltachat;
+pub use forward_auth::ForwardAuthConfig;
+pub use policy::KeyPolicy;
+pub use registration::RegistrationMode;
+pub use saml::{SamlConfig, SamlKey};
+pub use webfinger::{WebFingerAnswer, WebFingerConfig};
+pub use webhooks::run_webhooks;
 
 /// Top-level configuration read from `config.toml`.
 #[derive(Deserialize, Clone, Debug)]
@@ -76,23 +126,106 @@ pub struct BotConfig {
     pub oauth_db: PathBuf,
     /// Socket address the HTTP server listens on (e.g. `"127.0.0.1:8080"`).
     pub listen_addr: SocketAddr,
+    /// URL under which users reach the bot (e.g. `"https://login.example.org"`).
+    ///
+    /// Needed for links the bot sends in chats.
+    pub public_url: Option<String>,
+    /// Whether a reverse proxy sets `X-Forwarded-For` and `X-Real-IP`.
+    ///
+    /// Only then are these used for rate limits and shown with trusted browsers.
+    #[serde(default)]
+    pub trusted_proxy: bool,
     /// OAuth2 client configuration (id, secret, redirect URI).
     pub oauth: OAuthConfig,
+    /// Further relying parties, configured like [`BotConfig::oauth`].
+    #[serde(default)]
+    pub clients: Vec<OAuthConfig>,
     /// Directory from which static files (login.html, …) are served.
     pub static_dir: Option<PathBuf>,
     /// Tracing log level string (e.g. `"info"`). Defaults to `WARN`.
     pub log_level: Option<String>,
+    /// Role names mapped to the chat id of a Delta Chat group the bot is in.
+    ///
+    /// Members of the group get the role in the `groups` claim.
+    #[serde(default)]
+    pub roles: BTreeMap<String, u32>,
+    /// How first-time users are admitted.
+    #[serde(default)]
+    pub registration: RegistrationMode,
+    /// Admin group settings.
+    #[serde(default)]
+    pub admin: AdminConfig,
+    /// Forward authentication for reverse proxies; disabled if unset.
+    pub forward_auth: Option<ForwardAuthConfig>,
+    /// Key of the SAML identity provider; SAML is disabled if unset.
+    pub saml: Option<SamlConfig>,
+    /// Issuer discovery by address at `/.well-known/webfinger`; disabled if unset.
+    pub webfinger: Option<WebFingerConfig>,
+}
+
+impl BotConfig {
+    /// Look up the relying party with the given `client_id`.
+    pub fn client(&self, client_id: &str) -> Option<&OAuthConfig> {
+        std::iter::once(&self.oauth)
+            .chain(&self.clients)
+            .find(|client| client.client_id == client_id)
+    }
 }
 
 /// OAuth2 client credentials and callback URI.
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Debug, Default)]
 pub struct OAuthConfig {
     /// OAuth2 `client_id` that must match the value sent by the relying party.
     pub client_id: String,
+    /// Name of the relying party shown to users; defaults to the `client_id`.
+    pub name: Option<String>,
     /// Shared secret used to authenticate the relying party on `/token`.
     pub client_secret: String,
     /// Redirect URI the bot will forward the auth code to.
     pub redirect_uri: String,
+    /// URL at which the relying party starts a login, enabling `/login` links.
+    pub initiate_login_uri: Option<String>,
+    /// How strictly the user's key is checked before a login is accepted.
+    #[serde(default)]
+    pub key_policy: KeyPolicy,
+    /// Who may log in to this client.
+    #[serde(default)]
+    pub access: AccessRules,
+    /// Whether the user has to confirm the login on the phone after scanning.
+    #[serde(default)]
+    pub confirmation: Confirmation,
+    /// Whether users may log in with a link sent to their email address instead.
+    #[serde(default)]
+    pub email_fallback: bool,
+    /// Whether users have to agree before the client learns about them.
+    #[serde(default)]
+    pub consent: bool,
+    /// Scopes the client may ask for: `openid`, `email`, `profile`, `groups`.
+    ///
+    /// Defaults to all of them.
+    pub scopes: Option<Vec<String>>,
+    /// URIs `/end_session` may send the browser to after logging out.
+    #[serde(default)]
+    pub post_logout_redirect_uris: Vec<String>,
+    /// URL receiving OpenID Connect back-channel logout tokens.
+    pub backchannel_logout_uri: Option<String>,
+    /// URL receiving webhooks about users, signed with the client secret.
+    pub webhook_url: Option<String>,
+    /// Secret with which the client signs the webhooks it sends to `/webhook`.
+    pub webhook_secret: Option<String>,
+    /// Whether the client logs users in with DiscourseConnect instead of
+    /// OAuth2, signing its requests with the client secret.
+    #[serde(default)]
+    pub discourse_connect: bool,
+    /// Entity id of the client as SAML service provider. Assertions are
+    /// posted to `redirect_uri`, its assertion consumer service.
+    pub saml_entity_id: Option<String>,
+    /// Service URLs that may log in with CAS as this client; a URL also
+    /// admits the URLs below it.
+    #[serde(default)]
+    pub cas_services: Vec<String>,
+    /// Bearer token of the client for the SCIM API; disabled if unset.
+    pub scim_token: Option<String>,
 }
 
 /// Query parameters expected on the `/authorize` endpoint.
@@ -104,6 +237,27 @@ pub struct AuthorizeQuery {
     pub redirect_uri: String,
     /// Opaque state value echoed back to the relying party.
     pub state: String,
+    /// Login flow finished by the login page; defaults to the session's flow.
+    pub flow: Option<String>,
+    /// Whether the user asked to trust this browser for future logins.
+    #[serde(default)]
+    pub remember: bool,
+    /// OpenID Connect `prompt`: space-separated `none`, `login` or `consent`.
+    pub prompt: Option<String>,
+    /// OpenID Connect `nonce`, echoed in the ID token.
+    pub nonce: Option<String>,
+    /// Maximum age in seconds of a login reused from a trusted browser.
+    pub max_age: Option<u64>,
+    /// Address of the user expected to log in.
+    ///
+    /// Known users are asked in Delta Chat to confirm the login, without a QR code.
+    pub login_hint: Option<String>,
+    /// Requested `acr` values; without `email`, login by email is not offered.
+    pub acr_values: Option<String>,
+    /// Space-separated scopes; defaults to all scopes of the client.
+    pub scope: Option<String>,
+    /// Answer of the consent page: its token to agree, or `deny`.
+    pub consent: Option<String>,
 }
 
 /// Form/query parameters expected on the `/token` endpoint.
@@ -113,7 +267,38 @@ pub struct TokenQuery {
     pub code: Option<String>,
 }
 
-// Short expiry: no logout button, so reuse would skip the QR scan.
+/// Value stored under an authorization code until `/token` redeems it.
+#[derive(Debug, Serialize, Deserialize)]
+struct AuthCode {
+    contact_id: u32,
+    client_id: String,
+    /// Whether the user logged in with an emailed link instead of Delta Chat.
+    #[serde(default)]
+    email_login: bool,
+    /// Unix time at which the user was authenticated.
+    #[serde(default)]
+    auth_time: u64,
+    nonce: Option<String>,
+    #[serde(default = "consent::all_scopes")]
+    scopes: Vec<String>,
+}
+
+/// Value stored under an access token issued by `/token`.
+#[derive(Debug, Serialize, Deserialize)]
+struct AccessToken {
+    contact_id: u32,
+    client_id: String,
+    #[serde(default)]
+    email_login: bool,
+    #[serde(default = "consent::all_scopes")]
+    scopes: Vec<String>,
+    /// Unix time after which `/userinfo` rejects the token.
+    expires: u64,
+}
+
+const ACCESS_TOKEN_EXPIRY_IN_SECONDS: u64 = 60 * 60;
+
+// Short expiry: reuse would skip the QR scan, and users rarely log out.
 const SESSION_EXPIRY_IN_SECONDS: u64 = 15 * 60;
 
 /// Shared state cloned into every Axum handler.
@@ -127,6 +312,8 @@ pub struct AppState {
     pub config: BotConfig,
     /// Contents of `login.html`, served when a user needs to scan the QR.
     pub login_html: String,
+    /// Key of the SAML identity provider, loaded from [`BotConfig::saml`] at startup.
+    pub saml_key: Option<Arc<SamlKey>>,
 }
 
 struct AppError(Error);
@@ -161,7 +348,31 @@ pub fn build_router(state: AppState, static_dir: PathBuf) -> Router {
         .route("/authorize", get(get_authorize))
         // OAuth2 token exchange: validates auth code and returns user info
         .route("/token", post(post_token))
-        .route("/webhook", post(post_webhook))
+        // Returns the same user info as /token for a bearer access token
+        .route("/userinfo", get(get_userinfo))
+        // Logs the browser out and notifies clients via back-channel logout
+        .route("/end_session", get(logout::get_end_session))
+        // Forward auth for reverse proxies: checks the session cookie, or logs in
+        .route("/auth/verify", get(forward_auth::get_verify))
+        .route("/auth/start", get(forward_auth::get_start))
+        // SAML identity provider: metadata and single sign-on
+        .route("/saml/metadata", get(saml::get_metadata))
+        .route("/saml/sso", get(saml::get_sso).post(saml::post_sso))
+        // CAS server: login with service tickets, validated by the service
+        .route("/cas/login", get(cas::get_login))
+        .route("/cas/serviceValidate", get(cas::get_service_validate))
+        .route("/cas/p3/serviceValidate", get(cas::get_service_validate))
+        // DiscourseConnect provider: the same login, answered with a signed payload
+        .route("/discourse_connect", get(discourse::get_discourse_connect))
+        // Issuer discovery for addresses on the configured domains
+        .route("/.well-known/webfinger", get(webfinger::get_webfinger))
+        // OpenID Connect discovery of the issuer's endpoints
+        .route(
+            "/.well-known/openid-configuration",
+            get(oidc::get_configuration),
+        )
+        // Events from relying parties, e.g. deleted or suspended users
+        .route("/webhook", post(webhooks::post_webhook))
         // Creates a DC group and returns the securejoin invite link
         .route("/requestQr", get(get_requestqr))
         // Returns the invite QR as SVG; HEAD checks if a group exists
@@ -169,33 +380,155 @@ pub fn build_router(state: AppState, static_dir: PathBuf) -> Router {
         .route("/requestQrSvg", head(head_requestqr_svg))
         // Polled by the login page to detect when the user joined the group
         .route("/checkStatus", get(get_checkstatus))
+        // Block list management, see the admin_api module
+        .nest("/admin", admin_api::router())
+        // SCIM provisioning of users and role groups, see the scim module
+        .nest("/scim/v2", scim::router())
+        // Fallback login with a one-time link sent by email
+        .route("/emailLogin", post(email::post_email_login))
+        .route("/emailLogin/:token", get(email::get_email_login))
+        // One-time login links sent by the bot in reply to /login
+        .route("/magic/:token", get(magic::get_magic))
+        // Self-service page showing what the bot knows about the user
+        .route(
+            "/account",
+            get(account::get_account).post(account::post_account),
+        )
+        // Registers a first-time user with an invite code
+        .route("/redeemInvite", post(post_invite))
         .nest_service("/", ServeDir::new(static_dir))
         .with_state(state)
         .layer(ServiceBuilder::new().layer(TraceLayer::new_for_http()))
         .layer(session_layer)
 }
 
+/// Query naming a login flow; without it, the flow in the session cookie is used.
+#[derive(Debug, Deserialize)]
+struct FlowQuery {
+    flow: Option<String>,
+}
+
+/// Query parameters of `/requestQr`, taken from the login page's `/authorize` URL.
+#[derive(Debug, Deserialize)]
+struct RequestQrQuery {
+    /// The relying party to log in to; defaults to [`BotConfig::oauth`].
+    client_id: Option<String>,
+    /// The relying party's OAuth2 `state`, for the link back to the browser.
+    state: Option<String>,
+    nonce: Option<String>,
+    login_hint: Option<String>,
+    acr_values: Option<String>,
+}
+
 async fn get_requestqr(
     State(state): State<AppState>,
     session: Session,
+    headers: HeaderMap,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    Query(query): Query<RequestQrQuery>,
 ) -> Result<(StatusCode, Json<Value>), AppError> {
+    if let Some(ip) = client_ip(&state, &headers, peer.map(|peer| peer.0)) {
+        if !throttle::allow(&state.db, &throttle::REQUEST_QR_PER_IP, &ip)? {
+            log::info!("/requestQr throttled {ip}");
+            return Ok((
+                StatusCode::TOO_MANY_REQUESTS,
+                Json(json!({ "error": "too many login attempts, please try again later" })),
+            ));
+        }
+    }
+    let client = query
+        .client_id
+        .and_then(|client_id| state.config.client(&client_id))
+        .unwrap_or(&state.config.oauth);
     let mut uuid = uuid::Uuid::new_v4().simple().to_string();
     uuid.truncate(5);
     let group = create_group(&state.dc_context, &format!("LoginBot group {uuid}")).await?;
-    // Reset per-login state so that a second login attempt from the same
-    // browser session starts fresh (old `sent` flag must not carry over).
-    session.insert("group_id", group.to_u32()).await?;
-    session.remove::<bool>("sent").await?;
-    session.remove::<u32>("contact_id").await?;
+    // Every call starts a fresh flow, so a second login attempt from the
+    // same browser does not inherit anything from the first one.
+    let mut flow = Flow::create(&state.db, &client.client_id, group.to_u32())?;
+    flow.origin = request_origin(&headers);
+    flow.oauth_state = query.state;
+    flow.nonce = query.nonce;
+    flow.securejoin_required = !oidc::acr_allows_email(query.acr_values.as_deref());
+    if let Some(login_hint) = &query.login_hint {
+        flow.hinted = add_hinted_user(&state, group, login_hint).await?;
+    }
+    flow.save(&state.db)?;
+    session.insert("flow_id", &flow.id).await?;
     Ok((
         StatusCode::OK,
-        Json(json!({ "link": get_securejoin_qr(&state.dc_context, Some(group)).await? })),
+        Json(json!({
+            "link": get_securejoin_qr(&state.dc_context, Some(group)).await?,
+            "flow": flow.id,
+            "hinted": flow.hinted,
+            "email_fallback": client.email_fallback
+                && !flow.securejoin_required
+                && state.config.public_url.is_some(),
+        })),
     ))
 }
 
-async fn head_requestqr_svg(session: Session) -> StatusCode {
-    if session
-        .get::<u32>("group_id")
+/// Add the user named by `login_hint` to login group `group_id`, so that they
+/// confirm the login in Delta Chat instead of scanning the QR code.
+///
+/// Only users who logged in before and still have the same key are added,
+/// and only a few times per hour, as anybody can name any user.
+/// Returns whether the user was added.
+async fn add_hinted_user(
+    state: &AppState,
+    group_id: ChatId,
+    login_hint: &str,
+) -> Result<bool, Error> {
+    let addr = login_hint.trim();
+    let Some(known) = policy::known_fingerprint(&state.db, addr)? else {
+        return Ok(false);
+    };
+    if !throttle::allow(&state.db, &throttle::HINT_PER_ADDR, &addr.to_lowercase())? {
+        log::info!("/requestQr throttled login_hint {addr}, showing the QR code");
+        return Ok(false);
+    }
+    let Some(contact_id) =
+        Contact::lookup_id_by_addr(&state.dc_context, addr, Origin::IncomingUnknownFrom).await?
+    else {
+        return Ok(false);
+    };
+    let contact = Contact::get_by_id(&state.dc_context, contact_id).await?;
+    let fingerprint = contact.fingerprint().map(|fp| fp.hex());
+    if fingerprint.as_deref() != Some(known.as_str())
+        || blocks::is_blocked(&state.db, contact.get_addr(), fingerprint.as_deref())?
+    {
+        return Ok(false);
+    }
+    add_contact_to_chat(&state.dc_context, group_id, contact_id).await?;
+    log::info!(
+        "/requestQr asking {} to confirm the login",
+        contact.get_addr()
+    );
+    Ok(true)
+}
+
+/// The login flow named by `flow_id`, or else the one in the session cookie.
+async fn current_flow(
+    state: &AppState,
+    session: &Session,
+    flow_id: Option<String>,
+) -> Result<Option<Flow>, Error> {
+    let flow_id = match flow_id {
+        Some(flow_id) => Some(flow_id),
+        None => session.get::<String>("flow_id").await?,
+    };
+    match flow_id {
+        Some(flow_id) => Flow::load(&state.db, &flow_id),
+        None => Ok(None),
+    }
+}
+
+async fn head_requestqr_svg(
+    State(state): State<AppState>,
+    session: Session,
+    Query(query): Query<FlowQuery>,
+) -> StatusCode {
+    if current_flow(&state, &session, query.flow)
         .await
         .ok()
         .flatten()
@@ -210,9 +543,10 @@ async fn head_requestqr_svg(session: Session) -> StatusCode {
 async fn get_requestqr_svg(
     State(state): State<AppState>,
     session: Session,
+    Query(query): Query<FlowQuery>,
 ) -> Result<(StatusCode, TypedHeader<ContentType>, Bytes), AppError> {
-    if let Some(group_id) = session.get::<u32>("group_id").await? {
-        let qr = get_securejoin_qr_svg(&state.dc_context, Some(ChatId::new(group_id))).await?;
+    if let Some(flow) = current_flow(&state, &session, query.flow).await? {
+        let qr = get_securejoin_qr_svg(&state.dc_context, Some(ChatId::new(flow.group_id))).await?;
         Ok((
             StatusCode::OK,
             TypedHeader(ContentType::from("image/svg+xml".parse::<Mime>()?)),
@@ -230,105 +564,600 @@ async fn get_requestqr_svg(
 async fn get_checkstatus(
     State(state): State<AppState>,
     session: Session,
+    Query(query): Query<FlowQuery>,
 ) -> Result<(StatusCode, Json<Value>), AppError> {
-    if let Some(group_id) = session.get::<u32>("group_id").await? {
-        let dc_context = &state.dc_context;
-        log::info!("/checkStatus Getting chat members for group {group_id}");
-        let chat_members = get_chat_contacts(dc_context, ChatId::new(group_id)).await?;
-        match chat_members.len() {
-            1 => Ok((StatusCode::OK, Json(json!({ "waiting": true })))),
-            2 => {
-                let member_id = chat_members
-                    .into_iter()
-                    .find(|&c| c != deltachat::contact::ContactId::SELF)
-                    .context("could not find user member")?
-                    .to_u32();
-
-                if !session.get::<bool>("sent").await?.unwrap_or(false) {
-                    let mut msg = Message::new(Viewtype::Text);
-                    msg.set_text("This chat is a vehicle to connect you with me, the loginbot. You can leave this chat and delete it now.".to_string());
-                    send_msg(dc_context, ChatId::new(group_id), &mut msg).await?;
-
-                    // Persist fingerprint → addr on first ever login for this key.
-                    // Subsequent logins with any address sharing the same key
-                    // will be resolved to this canonical addr in /token.
-                    let contact = Contact::get_by_id(dc_context, ContactId::new(member_id)).await?;
-                    if let Some(fp) = contact.fingerprint() {
-                        let id_tree = state.db.open_tree("identities")?;
-                        let fp_hex = fp.hex();
-                        if !id_tree.contains_key(&fp_hex)? {
-                            id_tree.insert(&fp_hex, contact.get_addr().as_bytes())?;
-                            log::info!(
-                                "/checkStatus registered canonical addr {} for fingerprint {fp_hex}",
-                                contact.get_addr()
-                            );
-                        } else {
-                            log::info!(
-                                "/checkStatus fingerprint {fp_hex} already mapped; canonical addr unchanged"
-                            );
-                        }
-                    }
+    let Some(flow) = current_flow(&state, &session, query.flow).await? else {
+        return Ok((
+            StatusCode::OK,
+            Json(
+                json!({ "error": "you need to start the login process first, via /requestQr".to_owned()}),
+            ),
+        ));
+    };
+    Ok((StatusCode::OK, Json(advance_flow(&state, &flow.id).await?)))
+}
+
+/// Serializes [`advance_flow`], which runs for both polling and chat events.
+static FLOW_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
 
-                    session.insert("contact_id", member_id).await?;
-                    session.insert("sent", true).await?;
+/// Admit or deny the user who joined the login group of flow `flow_id`.
+///
+/// Besides `/checkStatus`, this runs when the login group changes, so that
+/// logins progress while the login page is in the background, e.g. on the
+/// phone running Delta Chat. Returns the answer for `/checkStatus`.
+async fn advance_flow(state: &AppState, flow_id: &str) -> Result<Value, Error> {
+    let _guard = FLOW_LOCK.lock().await;
+    let Some(mut flow) = Flow::load(&state.db, flow_id)? else {
+        return Ok(json!({ "error": "the login expired, please reload the page" }));
+    };
+    // Flows completed by email have nobody in the login group.
+    if !flow.sent {
+        let Some(member_id) = joined_member(state, flow.group_id).await? else {
+            return Ok(json!({ "waiting": true }));
+        };
+        let contact = Contact::get_by_id(&state.dc_context, member_id).await?;
+        let admission = admit(state, &mut flow, &contact).await?;
+        flow.save(&state.db)?;
+        match admission {
+            Admission::Admitted => {
+                let client = flow_client(state, &flow);
+                let group_id = ChatId::new(flow.group_id);
+                // Nobody scanned the QR code of hinted logins, the user has to confirm.
+                let mode = match client.confirmation {
+                    Confirmation::None if flow.hinted => Confirmation::NumberMatching,
+                    mode => mode,
+                };
+                match confirmation::check(
+                    state,
+                    client,
+                    mode,
+                    flow.hinted,
+                    group_id,
+                    member_id,
+                    &flow.origin,
+                )
+                .await?
+                {
+                    confirmation::Status::Confirmed => {
+                        let return_link = return_link(state, &flow)?;
+                        complete_login(state, group_id, &contact, return_link).await?;
+                        flow.contact_id = Some(member_id.to_u32());
+                        flow.auth_time = unix_time();
+                    }
+                    confirmation::Status::Pending(code) => {
+                        return Ok(json!({ "confirm_code": code }));
+                    }
+                    confirmation::Status::AwaitingApproval => {
+                        return Ok(json!({ "approval_pending": true }));
+                    }
+                    confirmation::Status::Failed => {
+                        let reason = "the confirmation number did not match".to_string();
+                        report_denied(state, &contact, &reason).await?;
+                        flow.denied = Some(reason);
+                    }
                 }
-                Ok((StatusCode::OK, Json(json!({ "success": true }))))
             }
-            number_of_members => {
-                log::error!("/checkStatus This must not happen. There is/are {number_of_members} in the group {group_id}");
-                Err(AppError(Error::msg(format!(
-                    "Error! number of chat member {group_id} is not 1 or 2"
-                ))))
+            Admission::InviteRequired => {
+                return Ok(json!({ "invite_required": true }));
+            }
+            Admission::Pending => {
+                return Ok(json!({ "pending": true }));
+            }
+            Admission::Denied(reason) => {
+                log::info!(
+                    "/checkStatus denied login of {}: {reason}",
+                    contact.get_addr()
+                );
+                report_denied(state, &contact, &reason).await?;
+                flow.denied = Some(reason);
             }
         }
+        flow.sent = true;
+        flow.save(&state.db)?;
+    }
+    if let Some(reason) = flow.denied {
+        return Ok(json!({ "error": reason }));
+    }
+    Ok(json!({ "success": true }))
+}
+
+/// Advance the unfinished login flow whose login group is `chat_id`, if any.
+pub(crate) async fn advance_group_flow(state: &AppState, chat_id: ChatId) -> Result<(), Error> {
+    if let Some(flow) = Flow::find_unfinished(&state.db, chat_id.to_u32())? {
+        advance_flow(state, &flow.id).await?;
+    }
+    Ok(())
+}
+
+/// Link that finishes `flow`'s `/authorize` request in the browser that opens it.
+///
+/// Sent to the login group so that users on a phone can return to the browser.
+fn return_link(state: &AppState, flow: &Flow) -> Result<Option<String>, Error> {
+    let (Some(public_url), Some(oauth_state)) = (&state.config.public_url, &flow.oauth_state)
+    else {
+        return Ok(None);
+    };
+    let client = flow_client(state, flow);
+    let mut url = url::Url::parse(&format!("{}/authorize", public_url.trim_end_matches('/')))?;
+    url.query_pairs_mut()
+        .append_pair("client_id", &client.client_id)
+        .append_pair("redirect_uri", &client.redirect_uri)
+        .append_pair("state", oauth_state)
+        .append_pair("flow", &flow.id);
+    if let Some(nonce) = &flow.nonce {
+        url.query_pairs_mut().append_pair("nonce", nonce);
+    }
+    Ok(Some(url.into()))
+}
+
+/// The user who joined login group `group_id`, or `None` while nobody has joined yet.
+async fn joined_member(state: &AppState, group_id: u32) -> Result<Option<ContactId>, Error> {
+    log::info!("/checkStatus Getting chat members for group {group_id}");
+    let chat_members = get_chat_contacts(&state.dc_context, ChatId::new(group_id)).await?;
+    match chat_members.len() {
+        1 => Ok(None),
+        2 => Ok(Some(
+            chat_members
+                .into_iter()
+                .find(|&c| c != ContactId::SELF)
+                .context("could not find user member")?,
+        )),
+        number_of_members => {
+            log::error!("/checkStatus This must not happen. There is/are {number_of_members} in the group {group_id}");
+            admin::alert(
+                state,
+                &format!("number of chat members in login group {group_id} is {number_of_members}, not 1 or 2"),
+            )
+            .await;
+            Err(Error::msg(format!(
+                "Error! number of chat member {group_id} is not 1 or 2"
+            )))
+        }
+    }
+}
+
+/// Apply the block list, the flow client's key policy, then the registration mode.
+async fn admit(state: &AppState, flow: &mut Flow, contact: &Contact) -> Result<Admission, Error> {
+    let fingerprint = contact.fingerprint().map(|fp| fp.hex());
+    if blocks::is_blocked_for(
+        &state.db,
+        &flow.client_id,
+        contact.get_addr(),
+        fingerprint.as_deref(),
+    )? {
+        return Ok(Admission::Denied("you are blocked".to_string()));
+    }
+    // Checked once per login so that polling while pending does not repeat alerts.
+    if !flow.key_checked {
+        let client = flow_client(state, flow);
+        if let Decision::Deny(reason) =
+            policy::check_contact(state, client.key_policy, contact).await?
+        {
+            return Ok(Admission::Denied(reason));
+        }
+        flow.key_checked = true;
+    }
+    registration::admit(state, contact).await
+}
+
+/// The relying party `flow` logs in to.
+fn flow_client<'a>(state: &'a AppState, flow: &Flow) -> &'a OAuthConfig {
+    state
+        .config
+        .client(&flow.client_id)
+        .unwrap_or(&state.config.oauth)
+}
+
+/// The origin of the page a request was made from, as far as the browser tells.
+fn request_origin(headers: &HeaderMap) -> String {
+    headers
+        .get(header::REFERER)
+        .and_then(|referer| url::Url::parse(referer.to_str().ok()?).ok())
+        .map(|referer| referer.origin().ascii_serialization())
+        .or_else(|| {
+            let host = headers.get(header::HOST)?.to_str().ok()?;
+            Some(host.to_string())
+        })
+        .unwrap_or_else(|| "an unknown site".to_string())
+}
+
+/// The IP address of the browser: from `X-Forwarded-For` or `X-Real-IP`
+/// behind a [trusted proxy](BotConfig::trusted_proxy), else the peer's.
+fn client_ip(state: &AppState, headers: &HeaderMap, peer: Option<SocketAddr>) -> Option<String> {
+    if !state.config.trusted_proxy {
+        return peer.map(|peer| peer.ip().to_string());
+    }
+    headers
+        .get("x-forwarded-for")
+        .or_else(|| headers.get("x-real-ip"))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(|ip| ip.trim().to_string())
+}
+
+/// Count a denied login of `contact` and tell the admins about it.
+async fn report_denied(state: &AppState, contact: &Contact, reason: &str) -> Result<(), Error> {
+    stats::count(&state.db, stats::DENIED)?;
+    admin::alert(
+        state,
+        &format!("Denied login attempt of {}: {reason}", contact.get_addr()),
+    )
+    .await;
+    Ok(())
+}
+
+/// Greet the admitted `contact` in its login group and remember its key.
+///
+/// `return_link` leads back to the browser the login was started in.
+async fn complete_login(
+    state: &AppState,
+    group_id: ChatId,
+    contact: &Contact,
+    return_link: Option<String>,
+) -> Result<(), Error> {
+    let mut text = "This chat is a vehicle to connect you with me, the loginbot. You can leave this chat and delete it now.".to_string();
+    if let Some(link) = return_link {
+        text += &format!(
+            "\n\nIf you started the login on this device, continue in your browser: {link}"
+        );
+    }
+    let mut msg = Message::new(Viewtype::Text);
+    msg.set_text(text);
+    send_msg(&state.dc_context, group_id, &mut msg).await?;
+
+    // Persist fingerprint → addr on first ever login for this key.
+    policy::record_key(state, contact)?;
+    if let Some(fp) = contact.fingerprint() {
+        registration::register(state, &fp.hex(), contact.get_addr()).await?;
+    }
+    stats::count(&state.db, stats::LOGINS)?;
+    Ok(())
+}
+
+/// Form posted by the login page when `/checkStatus` asks for an invite code.
+#[derive(Debug, Deserialize)]
+struct InviteForm {
+    code: String,
+    flow: Option<String>,
+}
+
+async fn post_invite(
+    State(state): State<AppState>,
+    session: Session,
+    Form(form): Form<InviteForm>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let member_id = match current_flow(&state, &session, form.flow).await? {
+        Some(flow) => joined_member(&state, flow.group_id).await?,
+        None => None,
+    };
+    let Some(member_id) = member_id else {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "scan the QR code first" })),
+        ));
+    };
+    let contact = Contact::get_by_id(&state.dc_context, member_id).await?;
+    if registration::redeem_invite(&state, &contact, &form.code).await? {
+        Ok((StatusCode::OK, Json(json!({ "success": true }))))
     } else {
+        log::info!("/redeemInvite rejected an unknown invite code");
         Ok((
             StatusCode::OK,
-            Json(
-                json!({ "error": "you need to start the login process first, via /requestQr".to_owned()}),
-            ),
+            Json(json!({ "error": "unknown or already used invite code" })),
         ))
     }
 }
 
-#[allow(clippy::unused_async)]
-async fn post_webhook() -> &'static str {
-    "ola"
-}
-
 async fn get_authorize(
     Query(queries): Query<AuthorizeQuery>,
+    RawQuery(raw_query): RawQuery,
     State(state): State<AppState>,
     session: Session,
+    jar: CookieJar,
+    headers: HeaderMap,
 ) -> Result<Response, AppError> {
-    let config = &state.config;
-    if queries.client_id != config.oauth.client_id {
+    let Some(client) = state.config.client(&queries.client_id) else {
         log::info!("/authorize Invalid client_id: {}", queries.client_id);
         return Ok(StatusCode::BAD_REQUEST.into_response());
-    }
-    if queries.redirect_uri != config.oauth.redirect_uri {
+    };
+    if queries.redirect_uri != client.redirect_uri {
         log::info!("/authorize Invalid redirect_uri: {}", queries.redirect_uri);
         return Ok(StatusCode::BAD_REQUEST.into_response());
     }
+    let prompt = match oidc::Prompt::parse(queries.prompt.as_deref()) {
+        Ok(prompt) => prompt,
+        Err(description) => {
+            log::info!("/authorize rejected prompt {:?}", queries.prompt);
+            return Ok(error_redirect(&queries, "invalid_request", description)?);
+        }
+    };
     let auth_code: String = uuid::Uuid::new_v4().simple().to_string();
     let tree = state.db.open_tree("default")?;
-    if let Some(contact_id) = session.get::<u32>("contact_id").await? {
-        tree.insert(&auth_code, &contact_id.to_le_bytes())?;
-        log::info!("/authorize Redirected. Clearing session state.");
-        // Flush the whole session so the next login starts completely fresh.
-        // (group_id and sent must not carry over to a second login attempt.)
+    let flow = current_flow(&state, &session, queries.flow.clone())
+        .await?
+        .filter(|flow| flow.client_id == client.client_id && flow.contact_id.is_some());
+    let login = match &flow {
+        Some(flow) => Some((
+            Contact::get_by_id(
+                &state.dc_context,
+                ContactId::new(flow.contact_id.unwrap_or_default()),
+            )
+            .await?,
+            flow.auth_time,
+        )),
+        None if prompt.requires_interaction() => None,
+        // A trusted browser skips the QR code, unless the relying party
+        // expects another user or a more recent login.
+        None => devices::trusted_contact(&state, &jar)
+            .await?
+            .filter(|(contact, auth_time)| {
+                queries
+                    .login_hint
+                    .as_deref()
+                    .is_none_or(|hint| hint.trim().eq_ignore_ascii_case(contact.get_addr()))
+                    && oidc::within_max_age(*auth_time, queries.max_age)
+            }),
+    };
+    if let Some(flow) = flow.as_ref().filter(|flow| flow.email_login) {
+        if !oidc::acr_allows_email(queries.acr_values.as_deref()) {
+            log::info!("/authorize rejected an email login, the client requires securejoin");
+            flow.remove(&state.db)?;
+            session.flush().await?;
+            return Ok(error_redirect(
+                &queries,
+                "unmet_authentication_requirements",
+                "the login has to be confirmed in Delta Chat",
+            )?);
+        }
+    }
+    let Some((contact, auth_time)) = login else {
+        if prompt.none {
+            log::info!("/authorize cannot log in silently, returning login_required");
+            return Ok(error_redirect(
+                &queries,
+                "login_required",
+                "the user has to log in",
+            )?);
+        }
+        log::info!("/authorize showing login screen");
+        return Ok(Html::from(state.login_html).into_response());
+    };
+    let email_login = flow.as_ref().is_some_and(|flow| flow.email_login);
+    let scopes = consent::requested_scopes(client, queries.scope.as_deref());
+    let login = ProtocolLogin {
+        endpoint: "/authorize",
+        client: Some(client),
+        access: &client.access,
+        name: client.name.as_deref().unwrap_or(&client.client_id),
+        flow,
+        scopes: &scopes,
+        consent: queries.consent.as_deref(),
+        force_consent: prompt.consent,
+        silent: prompt.none,
+        raw_query,
+        remember: queries.remember,
+    };
+    let jar =
+        match complete_protocol_login(&state, &session, jar, &headers, &contact, login).await? {
+            LoginOutcome::Completed(jar) => jar,
+            LoginOutcome::Page(page) => return Ok(page),
+            LoginOutcome::ConsentDenied => {
+                return Ok(error_redirect(
+                    &queries,
+                    "access_denied",
+                    "the user did not agree",
+                )?)
+            }
+            LoginOutcome::ConsentRequired => {
+                return Ok(error_redirect(
+                    &queries,
+                    "consent_required",
+                    "the user has to agree first",
+                )?)
+            }
+        };
+    let code = AuthCode {
+        contact_id: contact.get_id().to_u32(),
+        client_id: client.client_id.clone(),
+        email_login,
+        auth_time,
+        nonce: queries.nonce.clone(),
+        scopes,
+    };
+    tree.insert(&auth_code, serde_json::to_vec(&code)?)?;
+
+    let mut url = url::Url::parse(&queries.redirect_uri).context("invalid redirect uri")?;
+    url.query_pairs_mut()
+        .append_pair("state", &queries.state)
+        .append_pair("code", &auth_code);
+
+    Ok((jar, Redirect::temporary(url.as_str())).into_response())
+}
+
+/// Send the browser back to the relying party with an OAuth2 `error`.
+fn error_redirect(
+    queries: &AuthorizeQuery,
+    error: &str,
+    description: &str,
+) -> Result<Response, Error> {
+    let mut url = url::Url::parse(&queries.redirect_uri).context("invalid redirect uri")?;
+    url.query_pairs_mut()
+        .append_pair("state", &queries.state)
+        .append_pair("error", error)
+        .append_pair("error_description", description);
+    Ok(Redirect::temporary(url.as_str()).into_response())
+}
+
+/// A login that `/authorize` or another protocol endpoint (DiscourseConnect,
+/// SAML, CAS, forward auth) completes with [`complete_protocol_login`].
+struct ProtocolLogin<'a> {
+    /// The endpoint, for logs and as target of the consent page.
+    endpoint: &'static str,
+    /// The relying party; forward auth protects apps without a client.
+    client: Option<&'a OAuthConfig>,
+    /// Who may log in: the client's rules or those of forward auth.
+    access: &'a AccessRules,
+    /// The relying party as shown in the login history.
+    name: &'a str,
+    /// The completed login flow, or `None` for a trusted browser.
+    flow: Option<Flow>,
+    /// The scopes the relying party asks for.
+    scopes: &'a [String],
+    /// The answer of the consent page: its token, or `"deny"`.
+    consent: Option<&'a str>,
+    /// Ask for consent even if the user agreed before (`prompt=consent`).
+    force_consent: bool,
+    /// Do not show the consent page (`prompt=none`).
+    silent: bool,
+    /// The query of the request, repeated by the consent page.
+    raw_query: Option<String>,
+    /// Whether the user asked to trust this browser.
+    remember: bool,
+}
+
+/// How a protocol endpoint goes on after [`complete_protocol_login`].
+enum LoginOutcome {
+    /// The user is logged in; the endpoint answers with `jar` and its
+    /// code, ticket or assertion.
+    Completed(CookieJar),
+    /// Show this page instead: the login was denied or needs consent.
+    Page(Response),
+    /// The user did not agree to share their data.
+    ConsentDenied,
+    /// The user has to agree first, but `silent` rules out the consent page.
+    ConsentRequired,
+}
+
+/// Check a login of `contact` against blocks, access rules and consent;
+/// if it may proceed, record it and end its flow and session.
+async fn complete_protocol_login(
+    state: &AppState,
+    session: &Session,
+    jar: CookieJar,
+    headers: &HeaderMap,
+    contact: &Contact,
+    login: ProtocolLogin<'_>,
+) -> Result<LoginOutcome, Error> {
+    let endpoint = login.endpoint;
+    let fingerprint = contact.fingerprint().map(|fp| fp.hex());
+    // Blocks may have been added since the flow admitted the user or the
+    // browser was trusted.
+    let blocked = match login.client {
+        Some(client) => blocks::is_blocked_for(
+            &state.db,
+            &client.client_id,
+            contact.get_addr(),
+            fingerprint.as_deref(),
+        )?,
+        None => blocks::is_blocked(&state.db, contact.get_addr(), fingerprint.as_deref())?,
+    };
+    let denied = match blocked {
+        true => Some("you are blocked".to_string()),
+        false => login
+            .access
+            .check(&state.db, contact.get_addr(), fingerprint.as_deref())?,
+    };
+    if let Some(reason) = denied {
+        log::info!(
+            "{endpoint} denied {} for {}: {reason}",
+            contact.get_addr(),
+            login.name
+        );
+        report_denied(state, contact, &reason).await?;
+        if let Some(flow) = login.flow {
+            if login.access.notify_denied {
+                let mut msg = Message::new(Viewtype::Text);
+                msg.set_text(format!("Your login was denied: {reason}."));
+                send_msg(&state.dc_context, ChatId::new(flow.group_id), &mut msg).await?;
+            }
+            flow.remove(&state.db)?;
+        }
         session.flush().await?;
+        return Ok(LoginOutcome::Page(
+            (
+                StatusCode::FORBIDDEN,
+                html::message_page("Login denied", &format!("Sorry, {reason}.")),
+            )
+                .into_response(),
+        ));
+    }
 
-        let mut url = url::Url::parse(&queries.redirect_uri).context("invalid redirect uri")?;
-        url.query_pairs_mut()
-            .append_pair("state", &queries.state)
-            .append_pair("code", &auth_code);
+    let identity = canonical_addr(state, contact)?;
+    if let Some(client) = login
+        .client
+        .filter(|client| client.consent || login.force_consent)
+    {
+        if login.consent == Some("deny") {
+            log::info!(
+                "{endpoint} {identity} denied consent to {}",
+                client.client_id
+            );
+            if let Some(flow) = &login.flow {
+                flow.remove(&state.db)?;
+            }
+            session.flush().await?;
+            return Ok(LoginOutcome::ConsentDenied);
+        }
+        let agreed = match login.consent {
+            Some(token) => consent::redeem(&state.db, token, &identity, &client.client_id)?,
+            None => false,
+        };
+        if !agreed
+            && (login.force_consent
+                || !consent::is_granted(&state.db, &identity, &client.client_id, login.scopes)?)
+        {
+            if login.silent {
+                return Ok(LoginOutcome::ConsentRequired);
+            }
+            let query: Vec<(String, String)> =
+                url::form_urlencoded::parse(login.raw_query.unwrap_or_default().as_bytes())
+                    .into_owned()
+                    .collect();
+            log::info!("{endpoint} showing consent page");
+            let page = consent::page(&state.db, endpoint, client, &identity, login.scopes, &query)?;
+            return Ok(LoginOutcome::Page(page.into_response()));
+        }
+    }
 
-        Ok(Redirect::temporary(url.as_str()).into_response())
-    } else {
-        log::info!("/authorize showing login screen");
-        Ok(Html::from(state.login_html).into_response())
+    let email_login = login.flow.as_ref().is_some_and(|flow| flow.email_login);
+    let origin = match &login.flow {
+        Some(flow) if flow.origin.is_empty() => "login link",
+        Some(flow) => &flow.origin,
+        None => "trusted browser",
+    };
+    let method = match email_login {
+        true => "email",
+        false => "securejoin",
+    };
+    approval::record_login(state, contact.get_id(), login.name, origin, method).await?;
+    if let Some(client) = login.client {
+        webhooks::fire(
+            state,
+            webhooks::Event::Login {
+                identity: identity.clone(),
+                client_id: client.client_id.clone(),
+            },
+        )?;
     }
+    log::info!("{endpoint} logged in {identity} to {}", login.name);
+    // The flow is used up; the next login starts completely fresh.
+    let jar = match login.flow {
+        Some(flow) => {
+            flow.remove(&state.db)?;
+            // An email login only proves access to the mailbox, not to the key
+            // that trusted browsers are bound to.
+            match login.remember && !email_login {
+                true => devices::trust(state, jar, contact, headers)?,
+                false => jar,
+            }
+        }
+        None => {
+            stats::count(&state.db, stats::LOGINS)?;
+            jar
+        }
+    };
+    session.flush().await?;
+    Ok(LoginOutcome::Completed(jar))
 }
 
 async fn post_token(
@@ -339,14 +1168,14 @@ async fn post_token(
     if let Some(code) = form.code {
         let client_id: &str = auth.username();
         let client_secret: &str = auth.password();
-        if client_id != state.config.oauth.client_id {
+        let Some(client) = state.config.client(client_id) else {
             log::info!("/token returned 401 because client_ids were inconsistent");
             return Ok((
                 StatusCode::UNAUTHORIZED,
                 Json(json!( { "error": "incorrect client secret" })),
             ));
-        }
-        if client_secret != state.config.oauth.client_secret {
+        };
+        if !secrets_match(client_secret, &client.client_secret) {
             log::info!("/token returned 401 because client_secrets were inconsistent");
             return Ok((
                 StatusCode::UNAUTHORIZED,
@@ -355,41 +1184,73 @@ async fn post_token(
         }
         let tree = state.db.open_tree("default")?;
         log::debug!("/token Opened default tree in sled");
-        if let Some(data) = tree.get(code)? {
-            let contact = Contact::get_by_id(
-                &state.dc_context,
-                ContactId::new(u32::from_le_bytes(data[..].try_into()?)),
-            )
-            .await?;
-            // Resolve canonical addr: if this contact's key fingerprint was
-            // seen before (possibly under a different address), return the
-            // address from the first successful login so that Discourse always
-            // identifies the user by one stable email.
-            let canonical_addr = if let Some(fp) = contact.fingerprint() {
-                let id_tree = state.db.open_tree("identities")?;
-                id_tree
-                    .get(fp.hex())?
-                    .and_then(|v| String::from_utf8(v.to_vec()).ok())
-                    .unwrap_or_else(|| contact.get_addr().to_string())
+        let auth_code = tree
+            .get(&code)?
+            .map(|data| serde_json::from_slice::<AuthCode>(&data))
+            .transpose()?
+            .filter(|auth_code| auth_code.client_id == client.client_id);
+        if let Some(auth_code) = auth_code {
+            // Codes are single-use.
+            tree.remove(&code)?;
+            let contact =
+                Contact::get_by_id(&state.dc_context, ContactId::new(auth_code.contact_id)).await?;
+            // The key may have changed or the user may have been blocked
+            // since /checkStatus accepted them.
+            let fingerprint = contact.fingerprint().map(|fp| fp.hex());
+            let decision = if blocks::is_blocked_for(
+                &state.db,
+                &client.client_id,
+                contact.get_addr(),
+                fingerprint.as_deref(),
+            )? {
+                Decision::Deny("you are blocked".to_string())
+            } else if auth_code.email_login {
+                // The client opted into email logins, which have no key.
+                Decision::Allow
             } else {
-                contact.get_addr().to_string()
+                policy::check_contact(&state, client.key_policy, &contact).await?
             };
-            log::info!(
-                "/token resolved addr: {} → {canonical_addr}",
-                contact.get_addr()
-            );
-            return Ok((
-                StatusCode::OK,
-                Json(json!({
-                    "access_token": uuid::Uuid::new_v4().to_string(),
-                    "token_type": "bearer",
-                    "expires_in": 1,
-                    "info": {
-                        "username": contact.get_name(),
-                        "email": canonical_addr,
-                    }
-                })),
-            ));
+            if let Decision::Deny(reason) = decision {
+                log::info!("/token denied login of {}: {reason}", contact.get_addr());
+                report_denied(&state, &contact, &reason).await?;
+                return Ok((
+                    StatusCode::FORBIDDEN,
+                    Json(json!({ "error": format!("access denied: {reason}") })),
+                ));
+            }
+            let info =
+                user_info(&state, &contact, auth_code.email_login, &auth_code.scopes).await?;
+            let access_token = uuid::Uuid::new_v4().to_string();
+            let tokens = state.db.open_tree("tokens")?;
+            prune_expired_tokens(&tokens)?;
+            let token = AccessToken {
+                contact_id: auth_code.contact_id,
+                client_id: client.client_id.clone(),
+                email_login: auth_code.email_login,
+                scopes: auth_code.scopes.clone(),
+                expires: unix_time().saturating_add(ACCESS_TOKEN_EXPIRY_IN_SECONDS),
+            };
+            tokens.insert(&access_token, serde_json::to_vec(&token)?)?;
+            let mut response = json!({
+                "access_token": access_token,
+                "token_type": "bearer",
+                "expires_in": ACCESS_TOKEN_EXPIRY_IN_SECONDS,
+                "scope": auth_code.scopes.join(" "),
+                "info": info,
+            });
+            if let Some(id_token) = oidc::id_token(
+                &state,
+                client,
+                &info,
+                auth_code.auth_time,
+                auth_code.email_login,
+                auth_code.nonce.as_deref(),
+            )? {
+                if let Some(response) = response.as_object_mut() {
+                    response.insert("id_token".to_string(), id_token.into());
+                }
+            }
+            return Ok((StatusCode::OK, Json(response)));
         }
         log::info!("/token Returning 401 because there is no auth header");
         return Ok((
@@ -403,3 +1264,159 @@ async fn post_token(
         Json(json!({ "error": "no code in form data nor string queries" })),
     ))
 }
+
+async fn get_userinfo(
+    State(state): State<AppState>,
+    TypedHeader(auth): TypedHeader<Authorization<Bearer>>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let tokens = state.db.open_tree("tokens")?;
+    let token = tokens
+        .get(auth.token())?
+        .map(|data| serde_json::from_slice::<AccessToken>(&data))
+        .transpose()?
+        .filter(|token| token.expires > unix_time());
+    let Some(token) = token else {
+        log::info!("/userinfo returned 401 because the access token is unknown or expired");
+        return Ok((
+            StatusCode::UNAUTHORIZED,
+            Json(json!({ "error": "invalid access token" })),
+        ));
+    };
+    let contact = Contact::get_by_id(&state.dc_context, ContactId::new(token.contact_id)).await?;
+    Ok((
+        StatusCode::OK,
+        Json(user_info(&state, &contact, token.email_login, &token.scopes).await?),
+    ))
+}
+
+/// Claims about `contact` returned by `/token` (as `info`) and `/userinfo`.
+///
+/// Only the claims of the granted `scopes` are included. `amr` tells relying
+/// parties whether the user proved their Delta Chat key or, with
+/// `email_login`, only access to their mailbox.
+async fn user_info(
+    state: &AppState,
+    contact: &Contact,
+    email_login: bool,
+    scopes: &[String],
+) -> Result<Value, Error> {
+    let scope = |name: &str| scopes.iter().any(|scope| scope == name);
+    let mut info = serde_json::Map::new();
+    let identity = canonical_addr(state, contact)?;
+    info.insert(
+        "sub".to_string(),
+        subjects::of(&state.db, &identity)?.into(),
+    );
+    if scope("profile") {
+        info.insert("username".to_string(), contact.get_name().into());
+    }
+    if scope("email") {
+        info.insert("email".to_string(), identity.into());
+    }
+    if scope("groups") {
+        info.insert(
+            "groups".to_string(),
+            json!(roles::roles_of(state, contact.get_id()).await?),
+        );
+    }
+    info.insert(
+        "amr".to_string(),
+        json!([if email_login { "email" } else { "securejoin" }]),
+    );
+    Ok(Value::Object(info))
+}
+
+/// The stable address identifying `contact`.
+///
+/// If this contact's key fingerprint was seen before (possibly under a
+/// different address), this is the address from the first successful login,
+/// so that Discourse always identifies the user by one stable email.
+pub(crate) fn canonical_addr(state: &AppState, contact: &Contact) -> Result<String, Error> {
+    let canonical_addr = if let Some(fp) = contact.fingerprint() {
+        let id_tree = state.db.open_tree("identities")?;
+        id_tree
+            .get(fp.hex())?
+            .and_then(|v| String::from_utf8(v.to_vec()).ok())
+            .unwrap_or_else(|| contact.get_addr().to_string())
+    } else {
+        contact.get_addr().to_string()
+    };
+    log::info!("resolved addr: {} → {canonical_addr}", contact.get_addr());
+    Ok(canonical_addr)
+}
+
+/// `addr` trimmed and lowercased, or `None` if it is not a valid email address.
+pub(crate) fn normalize_addr(addr: &str) -> Option<String> {
+    let addr = addr.trim().to_lowercase();
+    let (local, domain) = addr.split_once('@')?;
+    let valid = !local.is_empty()
+        && !domain.is_empty()
+        && !domain.contains('@')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !addr.contains(|c: char| c.is_whitespace() || c.is_control());
+    valid.then_some(addr)
+}
+
+/// Drop access tokens whose expiry has passed.
+fn prune_expired_tokens(tokens: &sled::Tree) -> Result<(), Error> {
+    let now = unix_time();
+    for entry in tokens {
+        let (key, data) = entry?;
+        let expired = serde_json::from_slice::<AccessToken>(&data)
+            .map(|token| token.expires <= now)
+            .unwrap_or(true);
+        if expired {
+            tokens.remove(key)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether the secrets `a` and `b` are equal, compared in constant time.
+pub(crate) fn secrets_match(a: &str, b: &str) -> bool {
+    use subtle::ConstantTimeEq;
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+/// Seconds since the Unix epoch.
+pub(crate) fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// `unix_time` in RFC 3339 format in UTC, as SAML and SCIM use it.
+pub(crate) fn utc_time(unix_time: u64) -> String {
+    let time = time::OffsetDateTime::from_unix_timestamp(i64::try_from(unix_time).unwrap_or(0))
+        .unwrap_or(time::OffsetDateTime::UNIX_EPOCH);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        time.year(),
+        u8::from(time.month()),
+        time.day(),
+        time.hour(),
+        time.minute(),
+        time.second()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_addr() {
+        assert_eq!(
+            normalize_addr(" Alice@Example.org\n").as_deref(),
+            Some("alice@example.org")
+        );
+        assert_eq!(normalize_addr("alice"), None);
+        assert_eq!(normalize_addr("@example.org"), None);
+        assert_eq!(normalize_addr("alice@"), None);
+        assert_eq!(normalize_addr("alice@@example.org"), None);
+        assert_eq!(normalize_addr("alice smith@example.org"), None);
+        assert_eq!(normalize_addr("alice@example.org."), None);
+    }
+}
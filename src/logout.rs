@@ -0,0 +1,259 @@
+//! Logout: `/end_session` for relying parties that log users out, and
+//! OpenID Connect back-channel logout towards the clients.
+//!
+//! When a user logs out, is blocked or deletes their account, every client
+//! with a `backchannel_logout_uri` receives a signed logout token.
+
+use anyhow::Result;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::cookie::CookieJar;
+use deltachat::contact::{Contact, ContactId};
+use serde::Deserialize;
+use tower_sessions::Session;
+
+use crate::{
+    canonical_addr, devices, forward_auth, html, oidc, secrets_match, subjects, AccessToken,
+    AppError, AppState, OAuthConfig,
+};
+
+/// Query parameters of `/end_session`.
+#[derive(Debug, Deserialize)]
+pub(crate) struct EndSessionQuery {
+    /// An ID token the bot issued, naming the client and the user.
+    id_token_hint: Option<String>,
+    /// The client asking for the logout, if there is no `id_token_hint`.
+    client_id: Option<String>,
+    /// Where to send the browser afterwards; must be listed for the client.
+    post_logout_redirect_uri: Option<String>,
+    /// Opaque value echoed back to `post_logout_redirect_uri`.
+    state: Option<String>,
+    /// Token of the confirmation page, see [`CONFIRM_SESSION_KEY`].
+    confirm: Option<String>,
+}
+
+/// Session key of the token the logout confirmation page sends back.
+const CONFIRM_SESSION_KEY: &str = "logout_confirm";
+
+/// `GET /end_session`: log the browser out and tell the clients.
+///
+/// Without a valid and unexpired `id_token_hint`, the user confirms the
+/// logout first, so that other sites cannot log them out.
+pub(crate) async fn get_end_session(
+    State(state): State<AppState>,
+    session: Session,
+    jar: CookieJar,
+    Query(query): Query<EndSessionQuery>,
+) -> Result<Response, AppError> {
+    let hint = query
+        .id_token_hint
+        .as_deref()
+        .and_then(|token| oidc::verify_id_token(&state, token));
+    if query.id_token_hint.is_some() && hint.is_none() {
+        log::info!("/end_session got an invalid id_token_hint");
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            html::message_page("Logout failed", "The id_token_hint is invalid."),
+        )
+            .into_response());
+    }
+    let client = match (&hint, &query.client_id) {
+        (Some((client, _)), _) => Some(*client),
+        (None, Some(client_id)) => state.config.client(client_id),
+        (None, None) => None,
+    };
+    if let Some(uri) = &query.post_logout_redirect_uri {
+        if !is_allowed_redirect(client, uri) {
+            log::info!("/end_session rejected post_logout_redirect_uri {uri}");
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                html::message_page(
+                    "Logout failed",
+                    "The post_logout_redirect_uri is not registered for this client.",
+                ),
+            )
+                .into_response());
+        }
+    }
+    let fresh_hint = hint
+        .as_ref()
+        .is_some_and(|(_, claims)| !oidc::is_expired(claims));
+    let confirmed = match (
+        &query.confirm,
+        session.remove::<String>(CONFIRM_SESSION_KEY).await?,
+    ) {
+        (Some(confirm), Some(expected)) => secrets_match(confirm, &expected),
+        _ => false,
+    };
+    if !fresh_hint && !confirmed {
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        session.insert(CONFIRM_SESSION_KEY, &token).await?;
+        return Ok(confirmation_page(&query, &token).into_response());
+    }
+
+    // The user is named by the hint, else known from a trusted browser.
+    let subject = hint
+        .as_ref()
+        .and_then(|(_, claims)| claims.get("sub")?.as_str());
+    let identity = match subject {
+        Some(subject) => subjects::identity_of(&state.db, subject)?,
+        None => match devices::trusted_contact(&state, &jar).await? {
+            Some((contact, _)) => Some(canonical_addr(&state, &contact)?),
+            None => match forward_auth::session_contact(&state, &jar).await? {
+                Some(contact) => Some(canonical_addr(&state, &contact)?),
+                None => None,
+            },
+        },
+    };
+    let jar = devices::forget(&state, jar)?;
+    let jar = forward_auth::forget(&state, jar, identity.as_deref())?;
+    session.flush().await?;
+    if let Some(identity) = &identity {
+        let revoked = revoke_tokens(&state, identity).await?;
+        log::info!("/end_session logged out {identity}, revoked {revoked} access tokens");
+        notify_clients(&state, identity)?;
+    }
+
+    let Some(uri) = query.post_logout_redirect_uri else {
+        return Ok((jar, html::message_page("Logged out", "You are logged out.")).into_response());
+    };
+    let mut url = url::Url::parse(&uri)?;
+    if let Some(oauth_state) = &query.state {
+        url.query_pairs_mut().append_pair("state", oauth_state);
+    }
+    Ok((jar, Redirect::to(url.as_str())).into_response())
+}
+
+/// Whether `/end_session` may send the browser to `uri` after logging out for `client`.
+fn is_allowed_redirect(client: Option<&OAuthConfig>, uri: &str) -> bool {
+    client.is_some_and(|client| {
+        client
+            .post_logout_redirect_uris
+            .iter()
+            .any(|allowed| allowed == uri)
+    })
+}
+
+/// The page asking the user to confirm the logout of `query`, with the
+/// session's `token` in the form.
+fn confirmation_page(query: &EndSessionQuery, token: &str) -> Html<String> {
+    let mut fields = String::new();
+    for (name, value) in [
+        ("id_token_hint", &query.id_token_hint),
+        ("client_id", &query.client_id),
+        ("post_logout_redirect_uri", &query.post_logout_redirect_uri),
+        ("state", &query.state),
+        ("confirm", &Some(token.to_string())),
+    ] {
+        if let Some(value) = value {
+            fields += &format!(
+                r#"<input type="hidden" name="{name}" value="{}">"#,
+                html::escape(value)
+            );
+        }
+    }
+    html::page(
+        "Log out?",
+        &format!(
+            r#"<p>Do you want to log out?</p><form method="get" action="/end_session">{fields}<button type="submit">Log out</button></form>"#
+        ),
+    )
+}
+
+/// Remove the access tokens of `identity`. Returns how many.
+async fn revoke_tokens(state: &AppState, identity: &str) -> Result<usize> {
+    let tokens = state.db.open_tree("tokens")?;
+    let mut revoked: usize = 0;
+    for entry in &tokens {
+        let (key, data) = entry?;
+        let Ok(token) = serde_json::from_slice::<AccessToken>(&data) else {
+            continue;
+        };
+        let contact =
+            Contact::get_by_id(&state.dc_context, ContactId::new(token.contact_id)).await?;
+        if canonical_addr(state, &contact)? == identity {
+            tokens.remove(key)?;
+            revoked = revoked.saturating_add(1);
+        }
+    }
+    Ok(revoked)
+}
+
+/// Send a logout token for `identity` to every client with a `backchannel_logout_uri`.
+///
+/// The requests run in the background; failures are only logged.
+pub(crate) fn notify_clients(state: &AppState, identity: &str) -> Result<()> {
+    let clients = std::iter::once(&state.config.oauth).chain(&state.config.clients);
+    for client in clients {
+        let Some(uri) = client.backchannel_logout_uri.clone() else {
+            continue;
+        };
+        let Some(token) = oidc::logout_token(state, client, identity)? else {
+            log::warn!("back-channel logout needs public_url, not notifying {uri}");
+            continue;
+        };
+        tokio::spawn(async move {
+            let result = reqwest::Client::new()
+                .post(&uri)
+                .form(&[("logout_token", token)])
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(err) = result {
+                log::error!("back-channel logout to {uri} failed: {err}");
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_allowed_redirect() {
+        let client = OAuthConfig {
+            post_logout_redirect_uris: vec!["https://forum.example.org/logged-out".to_string()],
+            ..Default::default()
+        };
+        assert!(is_allowed_redirect(
+            Some(&client),
+            "https://forum.example.org/logged-out"
+        ));
+        // Only exact matches are allowed.
+        assert!(!is_allowed_redirect(
+            Some(&client),
+            "https://forum.example.org/logged-out?next=https://evil.example"
+        ));
+        assert!(!is_allowed_redirect(
+            Some(&client),
+            "https://forum.example.org/"
+        ));
+        assert!(!is_allowed_redirect(Some(&client), "https://evil.example/"));
+        // Without a known client, no redirect is allowed.
+        assert!(!is_allowed_redirect(
+            None,
+            "https://forum.example.org/logged-out"
+        ));
+    }
+
+    #[test]
+    fn test_confirmation_page() {
+        let query = EndSessionQuery {
+            id_token_hint: None,
+            client_id: Some("forum".to_string()),
+            post_logout_redirect_uri: Some("https://forum.example.org/?a=1&b=\"2\"".to_string()),
+            state: None,
+            confirm: None,
+        };
+        let page = confirmation_page(&query, "token123").0;
+        assert!(page.contains(r#"name="client_id" value="forum""#));
+        assert!(page.contains(r#"name="confirm" value="token123""#));
+        assert!(!page.contains(r#"name="state""#));
+        assert!(!page.contains(r#"b="2""#));
+    }
+}
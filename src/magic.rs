@@ -0,0 +1,331 @@
+//! Magic login links: users who chat with the bot send `/login <client>`
+//! and get a one-time URL that logs their browser in without a QR code.
+//!
+//! Opening the link starts a completed [`Flow`] in the browser's session and
+//! sends the browser to the client's `initiate_login_uri`, so that the
+//! relying party starts a regular `/authorize` request with its own `state`.
+
+use anyhow::Result;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+};
+use deltachat::chat::{send_msg, Chattype};
+use deltachat::contact::{Contact, ContactId};
+use deltachat::message::{Message, Viewtype};
+use serde::{Deserialize, Serialize};
+use tower_sessions::Session;
+
+use crate::flow::Flow;
+use crate::policy::{self, Decision};
+use crate::registration::{self, Admission};
+use crate::{blocks, bot, html, stats, unix_time, AppError, AppState, OAuthConfig};
+
+/// Sled tree mapping a link token to its [`MagicLink`].
+const MAGIC_LINKS_TREE: &str = "magic_links";
+
+const MAGIC_LINK_EXPIRY_IN_SECONDS: u64 = 10 * 60;
+
+/// A login link sent to a user, valid once.
+#[derive(Debug, Serialize, Deserialize)]
+struct MagicLink {
+    contact_id: u32,
+    /// The user's key when the link was requested; the link fails for other keys.
+    fingerprint: String,
+    client_id: String,
+    /// The chat the link was requested in.
+    chat_id: u32,
+    expires: u64,
+}
+
+/// A `/login` command.
+#[derive(Debug, PartialEq, Eq)]
+enum Command<'a> {
+    /// Send a link for the client named by the argument, if any.
+    Link(Option<&'a str>),
+    /// Refuse: the command came from a group, whose other members could
+    /// open the link.
+    NotPrivate,
+}
+
+/// The `/login [client]` command in `text`, sent in a chat of `chattype`.
+fn parse_command(chattype: Chattype, text: &str) -> Option<Command<'_>> {
+    let mut args = text.split_whitespace();
+    if args.next() != Some("/login") {
+        return None;
+    }
+    Some(match chattype {
+        Chattype::Single => Command::Link(args.next()),
+        _ => Command::NotPrivate,
+    })
+}
+
+/// Answer a `/login [client]` command in `msg`, sent in a chat of
+/// `chattype`; other messages are ignored.
+pub(crate) async fn handle_message(
+    state: &AppState,
+    msg: &Message,
+    chattype: Chattype,
+) -> Result<()> {
+    let text = msg.get_text();
+    let reply = match parse_command(chattype, &text) {
+        None => return Ok(()),
+        Some(Command::NotPrivate) => bot::PRIVATE_COMMAND.to_string(),
+        Some(Command::Link(client)) => match login_link(state, msg, client).await {
+            Ok(reply) => reply,
+            Err(err) => {
+                log::error!("failed to create a login link: {err:#}");
+                "Sorry, something went wrong.".to_string()
+            }
+        },
+    };
+    let mut answer = Message::new(Viewtype::Text);
+    answer.set_text(reply);
+    answer.set_quote(&state.dc_context, Some(msg)).await?;
+    send_msg(&state.dc_context, msg.get_chat_id(), &mut answer).await?;
+    Ok(())
+}
+
+/// Create a login link for the sender of `msg` and describe it for the chat.
+async fn login_link(state: &AppState, msg: &Message, client: Option<&str>) -> Result<String> {
+    let Some(public_url) = &state.config.public_url else {
+        return Ok("Login links are not enabled on this bot.".to_string());
+    };
+    let client = match client {
+        Some(client) => find_client(state, client),
+        None if state.config.clients.is_empty() => Some(&state.config.oauth),
+        None => None,
+    };
+    let Some(client) = client.filter(|client| client.initiate_login_uri.is_some()) else {
+        return Ok(format!(
+            "Usage: /login <client>, where client is one of: {}",
+            client_names(state).join(", ")
+        ));
+    };
+    let contact = Contact::get_by_id(&state.dc_context, msg.get_from_id()).await?;
+    let Some(fingerprint) = contact.fingerprint().map(|fp| fp.hex()) else {
+        return Ok("Login links need an end-to-end encrypted chat.".to_string());
+    };
+    if blocks::is_blocked_for(
+        &state.db,
+        &client.client_id,
+        contact.get_addr(),
+        Some(&fingerprint),
+    )? {
+        return Ok("Sorry, you are blocked.".to_string());
+    }
+    if let Decision::Deny(reason) =
+        policy::check_contact(state, client.key_policy, &contact).await?
+    {
+        return Ok(format!("Sorry, {reason}."));
+    }
+    match registration::admit(state, &contact).await? {
+        Admission::Admitted => {}
+        Admission::Pending => return Ok("Your registration awaits approval.".to_string()),
+        Admission::InviteRequired => {
+            return Ok("Please log in once by scanning the QR code first.".to_string())
+        }
+        Admission::Denied(reason) => return Ok(format!("Sorry, {reason}.")),
+    }
+    let link = MagicLink {
+        contact_id: contact.get_id().to_u32(),
+        fingerprint,
+        client_id: client.client_id.clone(),
+        chat_id: msg.get_chat_id().to_u32(),
+        expires: unix_time().saturating_add(MAGIC_LINK_EXPIRY_IN_SECONDS),
+    };
+    let token = store(&state.db, &link)?;
+    log::info!(
+        "sent a login link for {} to {}",
+        client.client_id,
+        contact.get_addr()
+    );
+    Ok(format!(
+        "Open this link within {} minutes to log in to {}. It works once; do not share it.\n\n{}/magic/{token}",
+        MAGIC_LINK_EXPIRY_IN_SECONDS / 60,
+        client_name(client),
+        public_url.trim_end_matches('/')
+    ))
+}
+
+/// The client whose `client_id` or `name` is `key`.
+fn find_client<'a>(state: &'a AppState, key: &str) -> Option<&'a OAuthConfig> {
+    std::iter::once(&state.config.oauth)
+        .chain(&state.config.clients)
+        .find(|client| client.client_id == key || client.name.as_deref() == Some(key))
+}
+
+fn client_name(client: &OAuthConfig) -> &str {
+    client.name.as_deref().unwrap_or(&client.client_id)
+}
+
+/// Names of the clients that support login links.
+fn client_names(state: &AppState) -> Vec<&str> {
+    std::iter::once(&state.config.oauth)
+        .chain(&state.config.clients)
+        .filter(|client| client.initiate_login_uri.is_some())
+        .map(client_name)
+        .collect()
+}
+
+/// `GET /magic/:token`: redeem a login link.
+pub(crate) async fn get_magic(
+    State(state): State<AppState>,
+    session: Session,
+    Path(token): Path<String>,
+) -> Result<Response, AppError> {
+    let link = take(&state.db, &token)?;
+    let client = link.as_ref().and_then(|link| {
+        state
+            .config
+            .client(&link.client_id)
+            .filter(|client| client.initiate_login_uri.is_some())
+    });
+    let (Some(link), Some(client)) = (link, client) else {
+        log::info!("/magic got an unknown or expired link");
+        return Ok((
+            StatusCode::NOT_FOUND,
+            html::message_page(
+                "Link expired",
+                "This login link is unknown, already used or expired. Send /login to the bot for a new one.",
+            ),
+        )
+            .into_response());
+    };
+    let contact = Contact::get_by_id(&state.dc_context, ContactId::new(link.contact_id)).await?;
+    let fingerprint = contact.fingerprint().map(|fp| fp.hex());
+    if !link.is_for(fingerprint.as_deref())
+        || blocks::is_blocked_for(
+            &state.db,
+            &client.client_id,
+            contact.get_addr(),
+            fingerprint.as_deref(),
+        )?
+    {
+        log::info!("/magic denied the link of {}", contact.get_addr());
+        return Ok((
+            StatusCode::FORBIDDEN,
+            html::message_page("Login denied", "Sorry, this login link is no longer valid."),
+        )
+            .into_response());
+    }
+    let mut flow = Flow::create(&state.db, &client.client_id, link.chat_id)?;
+    flow.contact_id = Some(link.contact_id);
+    flow.key_checked = true;
+    flow.sent = true;
+    flow.auth_time = unix_time();
+    flow.save(&state.db)?;
+    session.insert("flow_id", &flow.id).await?;
+    stats::count(&state.db, stats::LOGINS)?;
+    let initiate_login_uri = client.initiate_login_uri.as_deref().unwrap_or_default();
+    Ok(Redirect::temporary(initiate_login_uri).into_response())
+}
+
+/// Store `link` and return its token.
+fn store(db: &sled::Db, link: &MagicLink) -> Result<String> {
+    let token = uuid::Uuid::new_v4().simple().to_string();
+    let links = db.open_tree(MAGIC_LINKS_TREE)?;
+    prune_expired(&links)?;
+    links.insert(&token, serde_json::to_vec(link)?)?;
+    Ok(token)
+}
+
+/// Remove the link of `token`, returning it unless it expired.
+fn take(db: &sled::Db, token: &str) -> Result<Option<MagicLink>> {
+    Ok(db
+        .open_tree(MAGIC_LINKS_TREE)?
+        .remove(token)?
+        .map(|data| serde_json::from_slice::<MagicLink>(&data))
+        .transpose()?
+        .filter(|link| link.expires > unix_time()))
+}
+
+impl MagicLink {
+    /// Whether the link may log in the user who now has the key `fingerprint`.
+    fn is_for(&self, fingerprint: Option<&str>) -> bool {
+        fingerprint == Some(self.fingerprint.as_str())
+    }
+}
+
+/// Drop login links whose expiry has passed.
+fn prune_expired(links: &sled::Tree) -> Result<()> {
+    let now = unix_time();
+    for entry in links {
+        let (key, data) = entry?;
+        let expired = serde_json::from_slice::<MagicLink>(&data)
+            .map(|link| link.expires <= now)
+            .unwrap_or(true);
+        if expired {
+            links.remove(key)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(expires: u64) -> MagicLink {
+        MagicLink {
+            contact_id: 10,
+            fingerprint: "AAAA".to_string(),
+            client_id: "example".to_string(),
+            chat_id: 12,
+            expires,
+        }
+    }
+
+    #[test]
+    fn test_only_private_chats() {
+        assert_eq!(
+            parse_command(Chattype::Single, "/login wiki"),
+            Some(Command::Link(Some("wiki")))
+        );
+        assert_eq!(
+            parse_command(Chattype::Single, "/login"),
+            Some(Command::Link(None))
+        );
+        // Other members of a group could open the link.
+        assert_eq!(
+            parse_command(Chattype::Group, "/login wiki"),
+            Some(Command::NotPrivate)
+        );
+        assert_eq!(parse_command(Chattype::Group, "hello"), None);
+        assert_eq!(parse_command(Chattype::Single, "/loginwiki"), None);
+    }
+
+    #[test]
+    fn test_single_use() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let token = store(&db, &link(unix_time() + 60)).unwrap();
+        assert!(take(&db, "unknown").unwrap().is_none());
+        let redeemed = take(&db, &token).unwrap().unwrap();
+        assert_eq!(redeemed.contact_id, 10);
+        assert_eq!(redeemed.client_id, "example");
+        assert!(take(&db, &token).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let expired = store(&db, &link(unix_time() - 1)).unwrap();
+        assert!(take(&db, &expired).unwrap().is_none());
+
+        // Storing a new link prunes expired ones.
+        let expired = store(&db, &link(unix_time() - 1)).unwrap();
+        let valid = store(&db, &link(unix_time() + 60)).unwrap();
+        let links = db.open_tree(MAGIC_LINKS_TREE).unwrap();
+        assert!(!links.contains_key(&expired).unwrap());
+        assert!(links.contains_key(&valid).unwrap());
+    }
+
+    #[test]
+    fn test_fingerprint_binding() {
+        let link = link(unix_time() + 60);
+        assert!(link.is_for(Some("AAAA")));
+        assert!(!link.is_for(Some("BBBB")));
+        assert!(!link.is_for(None));
+    }
+}
@@ -1,28 +1,44 @@
+mod cli;
 mod shutdown_signal;
 
 use std::env::{args, current_dir};
 use std::fs::read;
 use std::path::PathBuf;
 use std::str::from_utf8;
+use std::sync::Arc;
 
 use anyhow::Context as _;
 use deltachat::config::Config;
 use deltachat::context::ContextBuilder;
 use deltachat::EventType;
-use deltachat_loginbot::{build_router, AppState, BotConfig};
+use deltachat_loginbot::{
+    build_router, handle_event, run_daily_stats, run_webhooks, setup_admin_group, AppState,
+    BotConfig, SamlKey,
+};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = args().skip(1).collect();
+    if args
+        .first()
+        .is_some_and(|arg| arg == "--help" || arg == "-h")
+    {
+        println!("{}", cli::USAGE);
+        return Ok(());
+    }
     let botconfig: BotConfig;
     {
         let mut config_file_path = current_dir()
             .context("Cannot get current directory")?
             .join("config.toml");
-        if let Some(arg) = args().nth(1) {
+        if let Some(arg) = args.first() {
             config_file_path = PathBuf::from(arg);
         }
         botconfig = toml::from_str(from_utf8(&read(config_file_path)?)?)?;
     }
+    if let Some(command) = args.get(1..).filter(|command| !command.is_empty()) {
+        return cli::run(&botconfig, command).await;
+    }
     let level = botconfig
         .log_level
         .as_deref()
@@ -34,9 +50,25 @@ async fn main() -> anyhow::Result<()> {
         .open()
         .await
         .context("Creating context failed")?;
+    let static_dir = botconfig
+        .static_dir
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("./static"));
+    let state: AppState = AppState {
+        db,
+        dc_context: ctx.clone(),
+        config: botconfig.clone(),
+        login_html: String::from_utf8(read(static_dir.join("login.html"))?)?,
+        saml_key: match &botconfig.saml {
+            Some(saml) => Some(Arc::new(SamlKey::load(saml)?)),
+            None => None,
+        },
+    };
     let dc_events = ctx.get_event_emitter();
+    let event_state = state.clone();
     let dc_event_task = tokio::spawn(async move {
         while let Some(event) = dc_events.recv().await {
+            handle_event(&event_state, &event.typ).await;
             match event.typ {
                 EventType::Error(message) => log::error!("{}", message),
                 EventType::Warning(message) => log::warn!("{}", message),
@@ -45,17 +77,7 @@ async fn main() -> anyhow::Result<()> {
             }
         }
     });
-    let static_dir = botconfig
-        .static_dir
-        .clone()
-        .unwrap_or_else(|| PathBuf::from("./static"));
-    let state: AppState = AppState {
-        db,
-        dc_context: ctx.clone(),
-        config: botconfig.clone(),
-        login_html: String::from_utf8(read(static_dir.join("login.html"))?)?,
-    };
-    let backend = build_router(state, static_dir);
+    let backend = build_router(state.clone(), static_dir);
 
     if !ctx.get_config_bool(Config::Configured).await? {
         log::info!("Configure deltachat context");
@@ -73,12 +95,22 @@ async fn main() -> anyhow::Result<()> {
     }
     // connect to email server
     ctx.start_io().await;
+    setup_admin_group(&state)
+        .await
+        .context("setting up the admin group failed")?;
+    let stats_task = tokio::spawn(run_daily_stats(state.clone()));
+    let webhooks_task = tokio::spawn(run_webhooks(state.clone()));
     let listener = tokio::net::TcpListener::bind(botconfig.listen_addr).await?;
-    axum::serve(listener, backend)
-        .with_graceful_shutdown(shutdown_signal::shutdown_signal())
-        .await?;
+    axum::serve(
+        listener,
+        backend.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal::shutdown_signal())
+    .await?;
     log::info!("Shutting Down");
     ctx.stop_io().await;
     dc_event_task.abort();
+    stats_task.abort();
+    webhooks_task.abort();
     Ok(())
 }
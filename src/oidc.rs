@@ -0,0 +1,326 @@
+//! OpenID Connect: request parameters of `/authorize`, the ID token of
+//! `/token`, back-channel logout tokens and the discovery document at
+//! `/.well-known/openid-configuration`.
+//!
+//! Tokens are signed with HS256 using the client's secret, so relying
+//! parties can verify them without fetching keys from the bot.
+
+use anyhow::Result;
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use data_encoding::BASE64URL_NOPAD;
+use serde_json::{json, Value};
+
+use crate::{
+    consent, secrets_match, subjects, unix_time, AppState, OAuthConfig,
+    ACCESS_TOKEN_EXPIRY_IN_SECONDS,
+};
+
+/// `acr` of logins that proved the user's Delta Chat key.
+const ACR_SECUREJOIN: &str = "securejoin";
+
+/// `acr` of logins that only proved access to the user's mailbox.
+const ACR_EMAIL: &str = "email";
+
+/// The parsed `prompt` parameter of `/authorize`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Prompt {
+    /// Log in without user interaction or fail with `login_required`.
+    pub none: bool,
+    /// Ignore trusted browsers and log in with Delta Chat again.
+    pub login: bool,
+    /// Show the consent page even if the user agreed before.
+    pub consent: bool,
+}
+
+impl Prompt {
+    /// Parse the space-separated `prompt` values.
+    ///
+    /// Errors describe the problem for an `invalid_request` redirect.
+    pub fn parse(prompt: Option<&str>) -> Result<Prompt, &'static str> {
+        let mut parsed = Prompt::default();
+        for value in prompt.unwrap_or_default().split_whitespace() {
+            match value {
+                "none" => parsed.none = true,
+                "login" => parsed.login = true,
+                "consent" => parsed.consent = true,
+                _ => return Err("unsupported prompt value"),
+            }
+        }
+        if parsed.none && (parsed.login || parsed.consent) {
+            return Err("prompt=none cannot be combined with other values");
+        }
+        Ok(parsed)
+    }
+
+    /// Whether a previous login, e.g. in a trusted browser, may not be reused.
+    pub fn requires_interaction(&self) -> bool {
+        self.login
+    }
+}
+
+/// The `acr` of a login, see [`acr_allows_email`].
+pub(crate) fn acr(email_login: bool) -> &'static str {
+    if email_login {
+        ACR_EMAIL
+    } else {
+        ACR_SECUREJOIN
+    }
+}
+
+/// Whether the `acr_values` requested by the relying party admit email logins.
+pub(crate) fn acr_allows_email(acr_values: Option<&str>) -> bool {
+    acr_values.is_none_or(|values| values.split_whitespace().any(|value| value == ACR_EMAIL))
+}
+
+/// Whether a login at `auth_time` is recent enough for `max_age` seconds.
+pub(crate) fn within_max_age(auth_time: u64, max_age: Option<u64>) -> bool {
+    max_age.is_none_or(|max_age| unix_time().saturating_sub(auth_time) <= max_age)
+}
+
+/// The ID token for a login of the user whose claims returned by `/token`
+/// are `info`, including the `sub`.
+///
+/// Returns `None` without `public_url`, which is the token's issuer.
+pub(crate) fn id_token(
+    state: &AppState,
+    client: &OAuthConfig,
+    info: &Value,
+    auth_time: u64,
+    email_login: bool,
+    nonce: Option<&str>,
+) -> Result<Option<String>> {
+    let Some(public_url) = &state.config.public_url else {
+        return Ok(None);
+    };
+    let now = unix_time();
+    let mut claims = json!({
+        "iss": public_url.trim_end_matches('/'),
+        "sub": info["sub"],
+        "aud": client.client_id,
+        "iat": now,
+        "exp": now.saturating_add(ACCESS_TOKEN_EXPIRY_IN_SECONDS),
+        "auth_time": auth_time,
+        "acr": acr(email_login),
+        "amr": info["amr"],
+    });
+    if let Some(claims) = claims.as_object_mut() {
+        if let Some(email) = info.get("email") {
+            claims.insert("email".to_string(), email.clone());
+        }
+        if let Some(nonce) = nonce {
+            claims.insert("nonce".to_string(), nonce.into());
+        }
+    }
+    Ok(Some(sign(&claims, "JWT", &client.client_secret)?))
+}
+
+/// The logout token telling `client` that `identity` logged out.
+///
+/// Returns `None` without `public_url`, which is the token's issuer.
+pub(crate) fn logout_token(
+    state: &AppState,
+    client: &OAuthConfig,
+    identity: &str,
+) -> Result<Option<String>> {
+    let Some(public_url) = &state.config.public_url else {
+        return Ok(None);
+    };
+    let subject = subjects::of(&state.db, identity)?;
+    let claims = logout_claims(public_url, &client.client_id, &subject);
+    Ok(Some(sign(&claims, "logout+jwt", &client.client_secret)?))
+}
+
+/// The claims of a logout token of the issuer `public_url` for `client_id`.
+fn logout_claims(public_url: &str, client_id: &str, subject: &str) -> Value {
+    json!({
+        "iss": public_url.trim_end_matches('/'),
+        "sub": subject,
+        "aud": client_id,
+        "iat": unix_time(),
+        "jti": uuid::Uuid::new_v4().simple().to_string(),
+        "events": { "http://schemas.openid.net/event/backchannel-logout": {} },
+    })
+}
+
+/// The client an ID token issued by the bot was for, and its claims.
+///
+/// Expired tokens are accepted, as `id_token_hint` allows.
+pub(crate) fn verify_id_token<'a>(
+    state: &'a AppState,
+    token: &str,
+) -> Option<(&'a OAuthConfig, Value)> {
+    let claims = unverified_claims(token)?;
+    let client = state.config.client(claims.get("aud")?.as_str()?)?;
+    is_signed_by(token, &client.client_secret).then_some((client, claims))
+}
+
+/// Whether an ID token issued by the bot expired.
+pub(crate) fn is_expired(claims: &Value) -> bool {
+    claims
+        .get("exp")
+        .and_then(Value::as_u64)
+        .is_none_or(|exp| exp <= unix_time())
+}
+
+/// The claims of the JWT `token`, without checking its signature.
+fn unverified_claims(token: &str) -> Option<Value> {
+    let payload = token.split('.').nth(1)?;
+    serde_json::from_slice(&BASE64URL_NOPAD.decode(payload.as_bytes()).ok()?).ok()
+}
+
+/// Whether the JWT `token` is signed with HS256 and `secret`.
+fn is_signed_by(token: &str, secret: &str) -> bool {
+    let mut parts = token.split('.');
+    let (Some(header), Some(payload), Some(signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return false;
+    };
+    let expected =
+        hmac_sha256::HMAC::mac(format!("{header}.{payload}").as_bytes(), secret.as_bytes());
+    secrets_match(&BASE64URL_NOPAD.encode(&expected), signature)
+}
+
+/// `GET /.well-known/openid-configuration`: the endpoints and features of
+/// the issuer `public_url`; 404 without `public_url`.
+pub(crate) async fn get_configuration(State(state): State<AppState>) -> Response {
+    let Some(public_url) = &state.config.public_url else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    (
+        [(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")],
+        Json(configuration(public_url)),
+    )
+        .into_response()
+}
+
+/// The discovery document of the issuer `public_url`.
+///
+/// There is no `jwks_uri`, as ID tokens are signed with the client secret.
+fn configuration(public_url: &str) -> Value {
+    let issuer = public_url.trim_end_matches('/');
+    json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{issuer}/authorize"),
+        "token_endpoint": format!("{issuer}/token"),
+        "userinfo_endpoint": format!("{issuer}/userinfo"),
+        "end_session_endpoint": format!("{issuer}/end_session"),
+        "scopes_supported": consent::all_scopes(),
+        "response_types_supported": ["code"],
+        "grant_types_supported": ["authorization_code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["HS256"],
+        "token_endpoint_auth_methods_supported": ["client_secret_basic"],
+        "prompt_values_supported": ["none", "login", "consent"],
+        "acr_values_supported": [ACR_SECUREJOIN, ACR_EMAIL],
+        "claims_supported": [
+            "sub", "iss", "aud", "auth_time", "acr", "amr", "nonce", "email", "username", "groups"
+        ],
+        "backchannel_logout_supported": true,
+    })
+}
+
+/// Sign `claims` as a JWT of type `typ` with HS256 and `secret`.
+fn sign(claims: &Value, typ: &str, secret: &str) -> Result<String> {
+    let header = json!({ "alg": "HS256", "typ": typ });
+    let signing_input = format!(
+        "{}.{}",
+        BASE64URL_NOPAD.encode(&serde_json::to_vec(&header)?),
+        BASE64URL_NOPAD.encode(&serde_json::to_vec(claims)?)
+    );
+    let signature = hmac_sha256::HMAC::mac(signing_input.as_bytes(), secret.as_bytes());
+    Ok(format!(
+        "{signing_input}.{}",
+        BASE64URL_NOPAD.encode(&signature)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prompt() {
+        assert_eq!(Prompt::parse(None), Ok(Prompt::default()));
+        let login = Prompt::parse(Some("login consent")).unwrap();
+        assert!(login.login && login.consent && !login.none);
+        assert!(login.requires_interaction());
+        assert!(!Prompt::parse(Some("consent"))
+            .unwrap()
+            .requires_interaction());
+        assert!(Prompt::parse(Some("none login")).is_err());
+        assert!(Prompt::parse(Some("select_account")).is_err());
+    }
+
+    #[test]
+    fn test_logout_token() {
+        let claims = logout_claims("https://login.example.org/", "forum", "0123abcd");
+        let token = sign(&claims, "logout+jwt", "secret").unwrap();
+        assert!(is_signed_by(&token, "secret"));
+        assert!(!is_signed_by(&token, "other secret"));
+
+        let header: Value = serde_json::from_slice(
+            &BASE64URL_NOPAD
+                .decode(token.split('.').next().unwrap().as_bytes())
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(header, json!({ "alg": "HS256", "typ": "logout+jwt" }));
+        let claims = unverified_claims(&token).unwrap();
+        assert_eq!(claims["iss"], "https://login.example.org");
+        assert_eq!(claims["sub"], "0123abcd");
+        assert_eq!(claims["aud"], "forum");
+        assert!(claims["iat"].as_u64().unwrap() <= unix_time());
+        assert_eq!(claims["jti"].as_str().unwrap().len(), 32);
+        assert_eq!(
+            claims["events"],
+            json!({ "http://schemas.openid.net/event/backchannel-logout": {} })
+        );
+        // Logout tokens must not be mistaken for ID tokens.
+        assert!(claims.get("nonce").is_none());
+        assert!(is_expired(&claims));
+
+        // Tampering with the claims breaks the signature.
+        let mut parts: Vec<&str> = token.split('.').collect();
+        let forged = BASE64URL_NOPAD.encode(br#"{"sub":"someone else","aud":"forum"}"#);
+        parts[1] = &forged;
+        assert!(!is_signed_by(&parts.join("."), "secret"));
+    }
+
+    #[test]
+    fn test_is_expired() {
+        assert!(!is_expired(&json!({ "exp": unix_time() + 60 })));
+        assert!(is_expired(&json!({ "exp": unix_time() - 1 })));
+        assert!(is_expired(&json!({})));
+    }
+
+    #[test]
+    fn test_configuration() {
+        let configuration = configuration("https://login.example.org/");
+        assert_eq!(configuration["issuer"], "https://login.example.org");
+        assert_eq!(
+            configuration["authorization_endpoint"],
+            "https://login.example.org/authorize"
+        );
+        assert_eq!(
+            configuration["end_session_endpoint"],
+            "https://login.example.org/end_session"
+        );
+        assert_eq!(
+            configuration["scopes_supported"],
+            json!(["openid", "email", "profile", "groups"])
+        );
+    }
+
+    #[test]
+    fn test_acr_values() {
+        assert!(acr_allows_email(None));
+        assert!(acr_allows_email(Some("securejoin email")));
+        assert!(!acr_allows_email(Some("securejoin")));
+    }
+}
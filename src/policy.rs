@@ -0,0 +1,186 @@
+//! Key policy: decides whether a joined contact may log in, based on
+//! securejoin verification and the fingerprint previously seen for its address.
+
+use anyhow::Result;
+use deltachat::contact::Contact;
+use serde::Deserialize;
+
+use crate::{admin, AppState};
+
+/// Sled tree mapping a lowercased address to the last accepted key fingerprint.
+const ADDR_FINGERPRINTS_TREE: &str = "addr_fingerprints";
+
+/// How strictly a relying party wants the user's key to be checked.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyPolicy {
+    /// Accept every contact; key changes only raise an admin alert.
+    #[default]
+    Warn,
+    /// Only accept contacts that were verified through securejoin.
+    RequireVerified,
+    /// Reject contacts whose address is known under a different fingerprint.
+    RejectKeyChange,
+}
+
+/// Outcome of [`check_contact`].
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Decision {
+    Allow,
+    /// The login must not proceed; the string is shown to the user.
+    Deny(String),
+}
+
+/// Evaluate `policy` for `contact`.
+///
+/// Key changes are reported via [`admin::alert`] regardless of the policy. Nothing is
+/// persisted here; call [`record_key`] once the login has been accepted.
+pub(crate) async fn check_contact(
+    state: &AppState,
+    policy: KeyPolicy,
+    contact: &Contact,
+) -> Result<Decision> {
+    let verified = match policy {
+        KeyPolicy::RequireVerified => contact.is_verified(&state.dc_context).await?,
+        _ => false,
+    };
+    let fingerprint = contact.fingerprint().map(|fp| fp.hex());
+    let addr = contact.get_addr().to_lowercase();
+    let known = known_fingerprint(&state.db, &addr)?;
+    if let (Some(known), Some(fingerprint)) = (&known, &fingerprint) {
+        if known != fingerprint {
+            admin::alert(
+                state,
+                &format!("key change for {addr}: known as {known}, now presents {fingerprint}"),
+            )
+            .await;
+        }
+    }
+    Ok(decide(
+        policy,
+        &addr,
+        verified,
+        fingerprint.as_deref(),
+        known.as_deref(),
+    ))
+}
+
+/// The decision of `policy` for `addr` presenting the key `fingerprint`,
+/// which last logged in with the key `known`.
+///
+/// `verified` only matters for [`KeyPolicy::RequireVerified`].
+fn decide(
+    policy: KeyPolicy,
+    addr: &str,
+    verified: bool,
+    fingerprint: Option<&str>,
+    known: Option<&str>,
+) -> Decision {
+    if policy == KeyPolicy::RequireVerified && !verified {
+        return Decision::Deny(format!("{addr} is not verified via securejoin"));
+    }
+    let Some(fingerprint) = fingerprint else {
+        return match policy {
+            KeyPolicy::Warn => Decision::Allow,
+            _ => Decision::Deny(format!("{addr} has no key")),
+        };
+    };
+    match known {
+        Some(known) if known != fingerprint && policy == KeyPolicy::RejectKeyChange => {
+            Decision::Deny(format!("the key of {addr} changed since the last login"))
+        }
+        _ => Decision::Allow,
+    }
+}
+
+/// Remember the fingerprint `contact` logged in with, so later key changes are noticed.
+pub(crate) fn record_key(state: &AppState, contact: &Contact) -> Result<()> {
+    if let Some(fp) = contact.fingerprint() {
+        record_fingerprint(&state.db, contact.get_addr(), &fp.hex())?;
+    }
+    Ok(())
+}
+
+/// Remember that `addr` logged in with the key `fingerprint`, replacing its previous key.
+fn record_fingerprint(db: &sled::Db, addr: &str, fingerprint: &str) -> Result<()> {
+    let tree = db.open_tree(ADDR_FINGERPRINTS_TREE)?;
+    tree.insert(addr.to_lowercase(), fingerprint.as_bytes())?;
+    Ok(())
+}
+
+/// The fingerprint `addr` last logged in with, if it ever did.
+pub(crate) fn known_fingerprint(db: &sled::Db, addr: &str) -> Result<Option<String>> {
+    let tree = db.open_tree(ADDR_FINGERPRINTS_TREE)?;
+    Ok(tree
+        .get(addr.to_lowercase())?
+        .and_then(|v| String::from_utf8(v.to_vec()).ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: &str = "alice@example.org";
+
+    #[test]
+    fn test_warn() {
+        let decide = |fingerprint, known| decide(KeyPolicy::Warn, ADDR, false, fingerprint, known);
+        assert_eq!(decide(None, None), Decision::Allow);
+        assert_eq!(decide(Some("AAAA"), None), Decision::Allow);
+        assert_eq!(decide(Some("BBBB"), Some("AAAA")), Decision::Allow);
+    }
+
+    #[test]
+    fn test_require_verified() {
+        let policy = KeyPolicy::RequireVerified;
+        assert!(matches!(
+            decide(policy, ADDR, false, Some("AAAA"), None),
+            Decision::Deny(_)
+        ));
+        assert_eq!(
+            decide(policy, ADDR, true, Some("AAAA"), None),
+            Decision::Allow
+        );
+        assert!(matches!(
+            decide(policy, ADDR, true, None, None),
+            Decision::Deny(_)
+        ));
+    }
+
+    #[test]
+    fn test_reject_key_change() {
+        let policy = KeyPolicy::RejectKeyChange;
+        assert_eq!(
+            decide(policy, ADDR, false, Some("AAAA"), None),
+            Decision::Allow
+        );
+        assert_eq!(
+            decide(policy, ADDR, false, Some("AAAA"), Some("AAAA")),
+            Decision::Allow
+        );
+        assert_eq!(
+            decide(policy, ADDR, false, Some("BBBB"), Some("AAAA")),
+            Decision::Deny(format!("the key of {ADDR} changed since the last login"))
+        );
+        assert!(matches!(
+            decide(policy, ADDR, false, None, Some("AAAA")),
+            Decision::Deny(_)
+        ));
+    }
+
+    #[test]
+    fn test_record_fingerprint() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        assert_eq!(known_fingerprint(&db, ADDR).unwrap(), None);
+        record_fingerprint(&db, "Alice@Example.org", "AAAA").unwrap();
+        assert_eq!(
+            known_fingerprint(&db, ADDR).unwrap().as_deref(),
+            Some("AAAA")
+        );
+        record_fingerprint(&db, ADDR, "BBBB").unwrap();
+        assert_eq!(
+            known_fingerprint(&db, ADDR).unwrap().as_deref(),
+            Some("BBBB")
+        );
+    }
+}
@@ -0,0 +1,338 @@
+//! Registration of first-time users: open, by invite code, or by admin approval.
+
+use anyhow::Result;
+use deltachat::contact::{Contact, ContactId};
+use deltachat::message::MsgId;
+use serde::{Deserialize, Serialize};
+
+use crate::webhooks::{self, Event};
+use crate::{admin, stats, AppState};
+
+/// Sled tree of unused invite codes.
+const INVITES_TREE: &str = "invites";
+/// Sled tree mapping a fingerprint to its [`PendingRegistration`].
+const PENDING_TREE: &str = "pending";
+/// Sled tree mapping the admin group message announcing a registration to its fingerprint.
+const PENDING_MSGS_TREE: &str = "pending_msgs";
+
+/// How first-time users (unknown fingerprints) are admitted.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationMode {
+    /// Everyone is registered on first login.
+    #[default]
+    Open,
+    /// New users must enter an invite code created by an admin.
+    Invite,
+    /// New users wait until an admin approves them in the admin group.
+    Approval,
+}
+
+/// Outcome of [`admit`].
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Admission {
+    /// The user is registered (or registration is open).
+    Admitted,
+    /// The browser has to ask for an invite code.
+    InviteRequired,
+    /// An admin has not decided yet.
+    Pending,
+    /// The login must not proceed; the string is shown to the user.
+    Denied(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingRegistration {
+    addr: String,
+    rejected: bool,
+}
+
+/// Decide whether `contact` may log in under the configured [`RegistrationMode`].
+///
+/// In approval mode, the first call for an unknown key announces it in the admin group.
+pub(crate) async fn admit(state: &AppState, contact: &Contact) -> Result<Admission> {
+    let fp = contact.fingerprint().map(|fp| fp.hex());
+    if let Some(admission) = known_admission(
+        &state.db,
+        state.config.registration,
+        contact.get_addr(),
+        fp.as_deref(),
+    )? {
+        return Ok(admission);
+    }
+    // Only unknown keys in approval mode get here.
+    let fp = fp.unwrap_or_default();
+    let text = format!(
+        "New registration: {} <{}>\nFingerprint: {fp}\n\n\
+         Reply \"approve\" or \"reject\" to this message, or react with 👍 or 👎.",
+        contact.get_display_name(),
+        contact.get_addr()
+    );
+    let Some(msg_id) = admin::post(state, &text).await? else {
+        log::error!("registration = \"approval\" requires [admin] fingerprints");
+        return Ok(Admission::Denied("registration is closed".to_string()));
+    };
+    add_pending(&state.db, &fp, contact.get_addr(), msg_id)?;
+    log::info!("registration of {} awaits approval", contact.get_addr());
+    Ok(Admission::Pending)
+}
+
+/// The admission of `addr` with key `fingerprint` under `mode`, or `None`
+/// if the key is unknown and has to be announced to the admins.
+fn known_admission(
+    db: &sled::Db,
+    mode: RegistrationMode,
+    addr: &str,
+    fingerprint: Option<&str>,
+) -> Result<Option<Admission>> {
+    if mode == RegistrationMode::Open {
+        return Ok(Some(Admission::Admitted));
+    }
+    let Some(fp) = fingerprint else {
+        return Ok(Some(Admission::Denied(format!(
+            "{addr} has no key and cannot register"
+        ))));
+    };
+    if db.open_tree("identities")?.contains_key(fp)? {
+        return Ok(Some(Admission::Admitted));
+    }
+    if mode == RegistrationMode::Invite {
+        return Ok(Some(Admission::InviteRequired));
+    }
+    let Some(data) = db.open_tree(PENDING_TREE)?.get(fp)? else {
+        return Ok(None);
+    };
+    let registration: PendingRegistration = serde_json::from_slice(&data)?;
+    Ok(Some(match registration.rejected {
+        true => Admission::Denied("your registration was rejected".to_string()),
+        false => Admission::Pending,
+    }))
+}
+
+/// Remember the registration of `addr` with key `fp`, announced by the admin group message `msg_id`.
+fn add_pending(db: &sled::Db, fp: &str, addr: &str, msg_id: MsgId) -> Result<()> {
+    let registration = PendingRegistration {
+        addr: addr.to_string(),
+        rejected: false,
+    };
+    db.open_tree(PENDING_TREE)?
+        .insert(fp, serde_json::to_vec(&registration)?)?;
+    db.open_tree(PENDING_MSGS_TREE)?
+        .insert(msg_id.to_u32().to_le_bytes(), fp.as_bytes())?;
+    Ok(())
+}
+
+/// Record the decision on the registration announced by `msg_id`.
+///
+/// Approved registrations are no longer pending; rejected ones stay so that
+/// the key remains rejected. Returns the fingerprint and registration, or
+/// `None` if `msg_id` announced none.
+fn resolve_pending(
+    db: &sled::Db,
+    msg_id: MsgId,
+    approve: bool,
+) -> Result<Option<(String, PendingRegistration)>> {
+    let msgs = db.open_tree(PENDING_MSGS_TREE)?;
+    let Some(fp) = msgs.get(msg_id.to_u32().to_le_bytes())? else {
+        return Ok(None);
+    };
+    let fp = String::from_utf8(fp.to_vec())?;
+    let pending = db.open_tree(PENDING_TREE)?;
+    let Some(data) = pending.get(&fp)? else {
+        return Ok(None);
+    };
+    let mut registration: PendingRegistration = serde_json::from_slice(&data)?;
+    if approve {
+        pending.remove(&fp)?;
+        msgs.remove(msg_id.to_u32().to_le_bytes())?;
+    } else {
+        registration.rejected = true;
+        pending.insert(&fp, serde_json::to_vec(&registration)?)?;
+    }
+    Ok(Some((fp, registration)))
+}
+
+/// Apply an admin's decision on the registration announced by `msg_id`.
+pub(crate) async fn decide(
+    state: &AppState,
+    msg_id: MsgId,
+    approve: bool,
+    admin_id: ContactId,
+) -> Result<()> {
+    let Some((fp, registration)) = resolve_pending(&state.db, msg_id, approve)? else {
+        return Ok(());
+    };
+    let admin = Contact::get_by_id(&state.dc_context, admin_id).await?;
+    if approve {
+        register(state, &fp, &registration.addr).await?;
+    }
+    let verb = if approve { "approved" } else { "rejected" };
+    log::info!(
+        "{} {verb} the registration of {}",
+        admin.get_addr(),
+        registration.addr
+    );
+    admin::post(
+        state,
+        &format!(
+            "{} {verb} the registration of {}.",
+            admin.get_display_name(),
+            registration.addr
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Create a single-use invite code.
+pub(crate) fn create_invite(db: &sled::Db) -> Result<String> {
+    let mut code = uuid::Uuid::new_v4().simple().to_string();
+    code.truncate(10);
+    db.open_tree(INVITES_TREE)?
+        .insert(&code, crate::unix_time().to_le_bytes().as_slice())?;
+    Ok(code)
+}
+
+/// Consume invite `code`. Returns `false` if it is unknown or already used.
+fn take_invite(db: &sled::Db, code: &str) -> Result<bool> {
+    Ok(db.open_tree(INVITES_TREE)?.remove(code.trim())?.is_some())
+}
+
+/// Consume invite `code` and register `contact` with it.
+///
+/// Returns `false` if the code is unknown or already used.
+pub(crate) async fn redeem_invite(state: &AppState, contact: &Contact, code: &str) -> Result<bool> {
+    let Some(fp) = contact.fingerprint() else {
+        return Ok(false);
+    };
+    if !take_invite(&state.db, code)? {
+        return Ok(false);
+    }
+    register(state, &fp.hex(), contact.get_addr()).await?;
+    log::info!("{} registered with an invite code", contact.get_addr());
+    Ok(true)
+}
+
+/// Map `fp` to its canonical `addr`, unless it is already registered.
+///
+/// Subsequent logins with any address sharing the same key
+/// will be resolved to this canonical addr in /token.
+pub(crate) async fn register(state: &AppState, fp: &str, addr: &str) -> Result<()> {
+    let id_tree = state.db.open_tree("identities")?;
+    if id_tree.contains_key(fp)? {
+        log::info!("fingerprint {fp} already mapped; canonical addr unchanged");
+        return Ok(());
+    }
+    let mut linked = false;
+    for entry in &id_tree {
+        let (_, canonical) = entry?;
+        linked |= canonical.as_ref() == addr.as_bytes();
+    }
+    id_tree.insert(fp, addr.as_bytes())?;
+    if linked {
+        log::info!("linked fingerprint {fp} to canonical addr {addr}");
+        webhooks::fire(
+            state,
+            Event::KeyLinked {
+                identity: addr.to_string(),
+                fingerprint: fp.to_string(),
+            },
+        )?;
+        return Ok(());
+    }
+    log::info!("registered canonical addr {addr} for fingerprint {fp}");
+    webhooks::fire(
+        state,
+        Event::Registered {
+            identity: addr.to_string(),
+        },
+    )?;
+    stats::count(&state.db, stats::REGISTRATIONS)?;
+    admin::alert(state, &format!("New user registered: {addr}")).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: &str = "alice@example.org";
+    const FP: &str = "AAAA";
+
+    fn db() -> sled::Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    fn admission(db: &sled::Db, mode: RegistrationMode) -> Option<Admission> {
+        known_admission(db, mode, ADDR, Some(FP)).unwrap()
+    }
+
+    #[test]
+    fn test_open() {
+        let db = db();
+        assert_eq!(
+            admission(&db, RegistrationMode::Open),
+            Some(Admission::Admitted)
+        );
+        assert_eq!(
+            known_admission(&db, RegistrationMode::Open, ADDR, None).unwrap(),
+            Some(Admission::Admitted)
+        );
+    }
+
+    #[test]
+    fn test_invite() {
+        let db = db();
+        assert_eq!(
+            admission(&db, RegistrationMode::Invite),
+            Some(Admission::InviteRequired)
+        );
+        assert!(matches!(
+            known_admission(&db, RegistrationMode::Invite, ADDR, None).unwrap(),
+            Some(Admission::Denied(_))
+        ));
+        let code = create_invite(&db).unwrap();
+        assert!(!take_invite(&db, "unknown").unwrap());
+        assert!(take_invite(&db, &format!(" {code}\n")).unwrap());
+        assert!(!take_invite(&db, &code).unwrap());
+        db.open_tree("identities")
+            .unwrap()
+            .insert(FP, ADDR)
+            .unwrap();
+        assert_eq!(
+            admission(&db, RegistrationMode::Invite),
+            Some(Admission::Admitted)
+        );
+    }
+
+    #[test]
+    fn test_approval() {
+        let db = db();
+        let mode = RegistrationMode::Approval;
+        assert_eq!(admission(&db, mode), None);
+        add_pending(&db, FP, ADDR, MsgId::new(10)).unwrap();
+        assert_eq!(admission(&db, mode), Some(Admission::Pending));
+        assert!(resolve_pending(&db, MsgId::new(11), true)
+            .unwrap()
+            .is_none());
+
+        let (fp, registration) = resolve_pending(&db, MsgId::new(10), false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(fp, FP);
+        assert!(registration.rejected);
+        assert!(matches!(admission(&db, mode), Some(Admission::Denied(_))));
+
+        let (fp, registration) = resolve_pending(&db, MsgId::new(10), true).unwrap().unwrap();
+        assert_eq!((fp.as_str(), registration.addr.as_str()), (FP, ADDR));
+        assert!(resolve_pending(&db, MsgId::new(10), true)
+            .unwrap()
+            .is_none());
+        assert_eq!(admission(&db, mode), None);
+        db.open_tree("identities")
+            .unwrap()
+            .insert(FP, ADDR)
+            .unwrap();
+        assert_eq!(admission(&db, mode), Some(Admission::Admitted));
+    }
+}
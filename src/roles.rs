@@ -0,0 +1,41 @@
+//! Roles derived from membership in Delta Chat groups the bot is part of.
+
+use anyhow::Result;
+use deltachat::chat::{get_chat_contacts, ChatId};
+use deltachat::contact::ContactId;
+
+use crate::AppState;
+
+/// Names of the configured roles whose group contains `contact_id`, sorted.
+///
+/// A group that cannot be read is logged and skipped so that a stale
+/// `[roles]` entry does not break every login.
+pub(crate) async fn roles_of(state: &AppState, contact_id: ContactId) -> Result<Vec<String>> {
+    let mut roles = Vec::new();
+    for (role, &chat_id) in &state.config.roles {
+        match get_chat_contacts(&state.dc_context, ChatId::new(chat_id)).await {
+            Ok(members) if members.contains(&contact_id) => roles.push(role.clone()),
+            Ok(_) => {}
+            Err(err) => log::warn!("cannot read members of role {role} (chat {chat_id}): {err:#}"),
+        }
+    }
+    Ok(roles)
+}
+
+/// The members of the group of `role`; empty for unknown roles and groups
+/// that cannot be read.
+pub(crate) async fn members_of(state: &AppState, role: &str) -> Result<Vec<ContactId>> {
+    let Some(&chat_id) = state.config.roles.get(role) else {
+        return Ok(Vec::new());
+    };
+    match get_chat_contacts(&state.dc_context, ChatId::new(chat_id)).await {
+        Ok(members) => Ok(members
+            .into_iter()
+            .filter(|member| *member != ContactId::SELF)
+            .collect()),
+        Err(err) => {
+            log::warn!("cannot read members of role {role} (chat {chat_id}): {err:#}");
+            Ok(Vec::new())
+        }
+    }
+}
@@ -0,0 +1,547 @@
+//! SCIM 2.0 provisioning API under `/scim/v2`, so that relying parties can
+//! pull users and groups before their first login.
+//!
+//! Users are the identities of the `identities` tree with the addresses
+//! that logged in with their keys, groups are the `[roles]`. Clients
+//! authenticate with their `scim_token` and see the users their access
+//! rules admit. The only change they can make is to deactivate a user,
+//! which blocks the user's keys for logins to that client.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::Result;
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use deltachat::contact::{Contact, Origin};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    approval, blocks, canonical_addr, roles, secrets_match, utc_time, AppError, AppState,
+    OAuthConfig,
+};
+
+const SCHEMA_USER: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+const SCHEMA_GROUP: &str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+const SCHEMA_LIST: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+const SCHEMA_ERROR: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+
+/// Most resources returned in one page.
+const MAX_PAGE_SIZE: usize = 100;
+
+/// Routes of the SCIM API, to be nested under `/scim/v2`.
+pub(crate) fn router() -> Router<AppState> {
+    Router::new()
+        .route("/ServiceProviderConfig", get(get_service_provider_config))
+        .route("/Users", get(get_users))
+        .route("/Users/:id", get(get_user).patch(patch_user))
+        .route("/Groups", get(get_groups))
+        .route("/Groups/:id", get(get_group))
+}
+
+type ScimResult = Result<Response, AppError>;
+
+/// Query of list requests.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ListQuery {
+    /// `<attribute> eq "<value>"`, the only filter supported.
+    filter: Option<String>,
+    /// 1-based index of the first resource returned.
+    start_index: Option<usize>,
+    count: Option<usize>,
+}
+
+/// The keys and addresses of one identity.
+#[derive(Debug, Default)]
+struct Identity {
+    fingerprints: Vec<String>,
+    addresses: Vec<String>,
+}
+
+fn scim_json(status: StatusCode, value: Value) -> Response {
+    (
+        status,
+        [(header::CONTENT_TYPE, "application/scim+json")],
+        value.to_string(),
+    )
+        .into_response()
+}
+
+fn error(status: StatusCode, scim_type: Option<&str>, detail: &str) -> Response {
+    let mut body = json!({
+        "schemas": [SCHEMA_ERROR],
+        "status": status.as_u16().to_string(),
+        "detail": detail,
+    });
+    if let (Some(scim_type), Some(body)) = (scim_type, body.as_object_mut()) {
+        body.insert("scimType".to_string(), scim_type.into());
+    }
+    scim_json(status, body)
+}
+
+/// The client whose `scim_token` the request carries.
+fn authorize(
+    state: &AppState,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Option<&OAuthConfig> {
+    let TypedHeader(auth) = auth?;
+    std::iter::once(&state.config.oauth)
+        .chain(&state.config.clients)
+        .find(|client| {
+            client
+                .scim_token
+                .as_deref()
+                .is_some_and(|token| secrets_match(token, auth.token()))
+        })
+}
+
+fn unauthorized() -> Response {
+    log::info!("SCIM API returned 401 because the token was missing or wrong");
+    error(StatusCode::UNAUTHORIZED, None, "invalid SCIM token")
+}
+
+/// Parse a `filter` of the form `<attribute> eq "<value>"`.
+///
+/// Returns the lowercased attribute and the value.
+fn parse_filter(filter: &str) -> Option<(String, String)> {
+    let (attribute, rest) = filter.trim().split_once(char::is_whitespace)?;
+    let (operator, value) = rest.trim_start().split_once(char::is_whitespace)?;
+    if !operator.eq_ignore_ascii_case("eq") {
+        return None;
+    }
+    let value = value.trim().strip_prefix('"')?.strip_suffix('"')?;
+    Some((attribute.to_ascii_lowercase(), value.replace("\\\"", "\"")))
+}
+
+/// The page of `items` starting at the 1-based `start_index`.
+fn page<T>(items: Vec<T>, start_index: Option<usize>, count: Option<usize>) -> Vec<T> {
+    let count = count.unwrap_or(MAX_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    items
+        .into_iter()
+        .skip(start_index.unwrap_or(1).saturating_sub(1))
+        .take(count)
+        .collect()
+}
+
+fn list_response(total: usize, start_index: Option<usize>, resources: Vec<Value>) -> Response {
+    scim_json(
+        StatusCode::OK,
+        json!({
+            "schemas": [SCHEMA_LIST],
+            "totalResults": total,
+            "startIndex": start_index.unwrap_or(1).max(1),
+            "itemsPerPage": resources.len(),
+            "Resources": resources,
+        }),
+    )
+}
+
+/// URL of the resource `id` of type `resource`, `Users` or `Groups`.
+fn location(state: &AppState, resource: &str, id: &str) -> String {
+    format!(
+        "{}/scim/v2/{resource}/{}",
+        state
+            .config
+            .public_url
+            .as_deref()
+            .unwrap_or_default()
+            .trim_end_matches('/'),
+        url::form_urlencoded::byte_serialize(id.as_bytes()).collect::<String>()
+    )
+}
+
+/// Every identity `client` may see, by canonical address.
+fn identities(state: &AppState, client: &OAuthConfig) -> Result<BTreeMap<String, Identity>> {
+    let mut identities: BTreeMap<String, Identity> = BTreeMap::new();
+    let mut owners = BTreeMap::new();
+    for entry in &state.db.open_tree("identities")? {
+        let (fp, canonical) = entry?;
+        let identity = String::from_utf8_lossy(&canonical).to_lowercase();
+        let fp = String::from_utf8(fp.to_vec())?;
+        owners.insert(fp.clone(), identity.clone());
+        identities
+            .entry(identity)
+            .or_default()
+            .fingerprints
+            .push(fp);
+    }
+    for entry in &state.db.open_tree("addr_fingerprints")? {
+        let (addr, fp) = entry?;
+        let owner = owners.get(String::from_utf8_lossy(&fp).as_ref());
+        if let Some(identity) = owner.and_then(|owner| identities.get_mut(owner)) {
+            identity.addresses.push(String::from_utf8(addr.to_vec())?);
+        }
+    }
+    let mut visible = BTreeMap::new();
+    for (addr, identity) in identities {
+        let fingerprint = identity.fingerprints.first().map(String::as_str);
+        if client
+            .access
+            .check(&state.db, &addr, fingerprint)?
+            .is_none()
+        {
+            visible.insert(addr, identity);
+        }
+    }
+    Ok(visible)
+}
+
+/// The SCIM user `addr`, as `client` sees it.
+async fn user_json(
+    state: &AppState,
+    client: &OAuthConfig,
+    addr: &str,
+    identity: &Identity,
+) -> Result<Value> {
+    let mut name = String::new();
+    let mut groups = BTreeSet::new();
+    let mut login_times = Vec::new();
+    for address in &identity.addresses {
+        let Some(contact_id) =
+            Contact::lookup_id_by_addr(&state.dc_context, address, Origin::IncomingUnknownFrom)
+                .await?
+        else {
+            continue;
+        };
+        if name.is_empty() {
+            name = Contact::get_by_id(&state.dc_context, contact_id)
+                .await?
+                .get_name()
+                .to_string();
+        }
+        groups.extend(roles::roles_of(state, contact_id).await?);
+        login_times.extend(
+            approval::history(&state.db, contact_id)?
+                .iter()
+                .map(|login| login.time),
+        );
+    }
+    // Blocks of other clients do not keep the user from logging in to this one.
+    let mut active = !blocks::is_blocked_for(&state.db, &client.client_id, addr, None)?;
+    for fp in &identity.fingerprints {
+        if blocks::is_blocked_for(&state.db, &client.client_id, addr, Some(fp))? {
+            active = false;
+        }
+    }
+    let mut emails = vec![json!({ "value": addr, "primary": true })];
+    for address in &identity.addresses {
+        if !address.eq_ignore_ascii_case(addr) {
+            emails.push(json!({ "value": address }));
+        }
+    }
+    let groups: Vec<Value> = groups
+        .iter()
+        .map(|role| {
+            json!({ "value": role, "display": role, "$ref": location(state, "Groups", role) })
+        })
+        .collect();
+    let mut meta = json!({
+        "resourceType": "User",
+        "location": location(state, "Users", addr),
+    });
+    if let (Some(first), Some(last), Some(meta)) = (
+        login_times.iter().min(),
+        login_times.iter().max(),
+        meta.as_object_mut(),
+    ) {
+        meta.insert("created".to_string(), utc_time(*first).into());
+        meta.insert("lastModified".to_string(), utc_time(*last).into());
+    }
+    let mut user = json!({
+        "schemas": [SCHEMA_USER],
+        "id": addr,
+        "externalId": addr,
+        "userName": addr,
+        "active": active,
+        "emails": emails,
+        "groups": groups,
+        "meta": meta,
+    });
+    if let (false, Some(user)) = (name.is_empty(), user.as_object_mut()) {
+        user.insert("displayName".to_string(), name.into());
+    }
+    Ok(user)
+}
+
+/// The SCIM group of `role`, with the members `identities` contains.
+async fn group_json(
+    state: &AppState,
+    role: &str,
+    identities: &BTreeMap<String, Identity>,
+) -> Result<Value> {
+    let mut members = Vec::new();
+    for contact_id in roles::members_of(state, role).await? {
+        let contact = Contact::get_by_id(&state.dc_context, contact_id).await?;
+        let addr = canonical_addr(state, &contact)?.to_lowercase();
+        if identities.contains_key(&addr) {
+            members.push(json!({
+                "value": addr,
+                "display": contact.get_name(),
+                "$ref": location(state, "Users", &addr),
+            }));
+        }
+    }
+    Ok(json!({
+        "schemas": [SCHEMA_GROUP],
+        "id": role,
+        "displayName": role,
+        "members": members,
+        "meta": {
+            "resourceType": "Group",
+            "location": location(state, "Groups", role),
+        },
+    }))
+}
+
+/// `GET /scim/v2/ServiceProviderConfig`: what this SCIM server supports.
+async fn get_service_provider_config(
+    State(state): State<AppState>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+) -> ScimResult {
+    if authorize(&state, auth).is_none() {
+        return Ok(unauthorized());
+    }
+    Ok(scim_json(
+        StatusCode::OK,
+        json!({
+            "schemas": ["urn:ietf:params:scim:schemas:core:2.0:ServiceProviderConfig"],
+            "patch": { "supported": true },
+            "bulk": { "supported": false, "maxOperations": 0, "maxPayloadSize": 0 },
+            "filter": { "supported": true, "maxResults": MAX_PAGE_SIZE },
+            "changePassword": { "supported": false },
+            "sort": { "supported": false },
+            "etag": { "supported": false },
+            "authenticationSchemes": [{
+                "type": "oauthbearertoken",
+                "name": "Bearer token",
+                "description": "The scim_token of the client",
+            }],
+        }),
+    ))
+}
+
+/// `GET /scim/v2/Users`
+async fn get_users(
+    State(state): State<AppState>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Query(query): Query<ListQuery>,
+) -> ScimResult {
+    let Some(client) = authorize(&state, auth) else {
+        return Ok(unauthorized());
+    };
+    let filter = match query.filter.as_deref().map(parse_filter) {
+        None => None,
+        Some(Some(filter))
+            if ["username", "id", "externalid", "emails", "emails.value"]
+                .contains(&filter.0.as_str()) =>
+        {
+            Some(filter)
+        }
+        Some(_) => {
+            return Ok(error(
+                StatusCode::BAD_REQUEST,
+                Some("invalidFilter"),
+                "only userName, id, externalId and emails can be filtered with eq",
+            ))
+        }
+    };
+    let matching: Vec<(String, Identity)> = identities(&state, client)?
+        .into_iter()
+        .filter(|(addr, identity)| {
+            let Some((attribute, value)) = &filter else {
+                return true;
+            };
+            addr.eq_ignore_ascii_case(value)
+                || (attribute.starts_with("emails")
+                    && identity
+                        .addresses
+                        .iter()
+                        .any(|address| address.eq_ignore_ascii_case(value)))
+        })
+        .collect();
+    let total = matching.len();
+    let mut resources = Vec::new();
+    for (addr, identity) in page(matching, query.start_index, query.count) {
+        resources.push(user_json(&state, client, &addr, &identity).await?);
+    }
+    Ok(list_response(total, query.start_index, resources))
+}
+
+/// `GET /scim/v2/Users/:id`
+async fn get_user(
+    State(state): State<AppState>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Path(id): Path<String>,
+) -> ScimResult {
+    let Some(client) = authorize(&state, auth) else {
+        return Ok(unauthorized());
+    };
+    let id = id.to_lowercase();
+    match identities(&state, client)?.get(&id) {
+        Some(identity) => Ok(scim_json(
+            StatusCode::OK,
+            user_json(&state, client, &id, identity).await?,
+        )),
+        None => Ok(error(StatusCode::NOT_FOUND, None, "unknown user")),
+    }
+}
+
+/// `PATCH /scim/v2/Users/:id`: set `active`, blocking the user's keys for
+/// the client or lifting the blocks it created.
+async fn patch_user(
+    State(state): State<AppState>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Path(id): Path<String>,
+    Json(patch): Json<Value>,
+) -> ScimResult {
+    let Some(client) = authorize(&state, auth) else {
+        return Ok(unauthorized());
+    };
+    let id = id.to_lowercase();
+    let Some(identity) = identities(&state, client)?.remove(&id) else {
+        return Ok(error(StatusCode::NOT_FOUND, None, "unknown user"));
+    };
+    let mut active = None;
+    for operation in patch
+        .get("Operations")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        let op = operation
+            .get("op")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let value = match operation.get("path").and_then(Value::as_str) {
+            Some(path) if path.eq_ignore_ascii_case("active") => operation.get("value"),
+            None => operation.get("value").and_then(|value| value.get("active")),
+            Some(_) => None,
+        };
+        match (
+            op.eq_ignore_ascii_case("replace"),
+            value.and_then(Value::as_bool),
+        ) {
+            (true, Some(value)) => active = Some(value),
+            _ => {
+                return Ok(error(
+                    StatusCode::BAD_REQUEST,
+                    Some("mutability"),
+                    "only active can be replaced",
+                ))
+            }
+        }
+    }
+    match active {
+        Some(false) => {
+            for fp in &identity.fingerprints {
+                let reason = format!("deactivated by {} via SCIM", client.client_id);
+                blocks::block_for_client(&state, &client.client_id, fp, Some(reason)).await?;
+            }
+            log::info!("SCIM client {} deactivated {id}", client.client_id);
+        }
+        Some(true) => {
+            for fp in &identity.fingerprints {
+                blocks::unblock_for_client(&state.db, &client.client_id, fp)?;
+            }
+            log::info!("SCIM client {} activated {id}", client.client_id);
+        }
+        None => {}
+    }
+    Ok(scim_json(
+        StatusCode::OK,
+        user_json(&state, client, &id, &identity).await?,
+    ))
+}
+
+/// `GET /scim/v2/Groups`
+async fn get_groups(
+    State(state): State<AppState>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Query(query): Query<ListQuery>,
+) -> ScimResult {
+    let Some(client) = authorize(&state, auth) else {
+        return Ok(unauthorized());
+    };
+    let filter = match query.filter.as_deref().map(parse_filter) {
+        None => None,
+        Some(Some((attribute, value))) if attribute == "displayname" || attribute == "id" => {
+            Some(value)
+        }
+        Some(_) => {
+            return Ok(error(
+                StatusCode::BAD_REQUEST,
+                Some("invalidFilter"),
+                "only displayName and id can be filtered with eq",
+            ))
+        }
+    };
+    let roles: Vec<&String> = state
+        .config
+        .roles
+        .keys()
+        .filter(|role| filter.as_ref().is_none_or(|value| *role == value))
+        .collect();
+    let total = roles.len();
+    let identities = identities(&state, client)?;
+    let mut resources = Vec::new();
+    for role in page(roles, query.start_index, query.count) {
+        resources.push(group_json(&state, role, &identities).await?);
+    }
+    Ok(list_response(total, query.start_index, resources))
+}
+
+/// `GET /scim/v2/Groups/:id`
+async fn get_group(
+    State(state): State<AppState>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Path(id): Path<String>,
+) -> ScimResult {
+    let Some(client) = authorize(&state, auth) else {
+        return Ok(unauthorized());
+    };
+    if !state.config.roles.contains_key(&id) {
+        return Ok(error(StatusCode::NOT_FOUND, None, "unknown group"));
+    }
+    let identities = identities(&state, client)?;
+    Ok(scim_json(
+        StatusCode::OK,
+        group_json(&state, &id, &identities).await?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filters() {
+        assert_eq!(
+            parse_filter(r#"userName eq "alice@example.org""#),
+            Some(("username".to_string(), "alice@example.org".to_string()))
+        );
+        assert_eq!(
+            parse_filter(r#"emails.value EQ "bob@example.org""#),
+            Some(("emails.value".to_string(), "bob@example.org".to_string()))
+        );
+        assert_eq!(parse_filter(r#"userName co "alice""#), None);
+        assert_eq!(parse_filter("userName eq alice"), None);
+    }
+
+    #[test]
+    fn test_pages() {
+        let items: Vec<u32> = (1..=5).collect();
+        assert_eq!(page(items.clone(), None, Some(2)), [1, 2]);
+        assert_eq!(page(items.clone(), Some(4), None), [4, 5]);
+        assert_eq!(page(items, Some(0), Some(1)), [1]);
+    }
+}
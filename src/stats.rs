@@ -0,0 +1,45 @@
+//! Counters for the daily statistics posted to the admin group.
+
+use anyhow::Result;
+
+/// Sled tree mapping a counter name to its little-endian `u64` value.
+const STATS_TREE: &str = "stats";
+
+/// Successful logins.
+pub(crate) const LOGINS: &str = "logins";
+/// First-time registrations.
+pub(crate) const REGISTRATIONS: &str = "registrations";
+/// Denied or blocked login attempts.
+pub(crate) const DENIED: &str = "denied";
+
+const COUNTERS: [&str; 3] = [LOGINS, REGISTRATIONS, DENIED];
+
+/// Increment `counter` by one.
+pub(crate) fn count(db: &sled::Db, counter: &str) -> Result<()> {
+    db.open_tree(STATS_TREE)?.update_and_fetch(counter, |old| {
+        let old = old
+            .and_then(|old| old.try_into().ok())
+            .map(u64::from_le_bytes)
+            .unwrap_or_default();
+        Some(old.saturating_add(1).to_le_bytes().to_vec())
+    })?;
+    Ok(())
+}
+
+/// Current value of every counter, optionally resetting them to zero.
+pub(crate) fn snapshot(db: &sled::Db, reset: bool) -> Result<Vec<(&'static str, u64)>> {
+    let tree = db.open_tree(STATS_TREE)?;
+    let mut values = Vec::new();
+    for counter in COUNTERS {
+        let value = match reset {
+            true => tree.remove(counter)?,
+            false => tree.get(counter)?,
+        };
+        let value = value
+            .and_then(|value| value.as_ref().try_into().ok())
+            .map(u64::from_le_bytes)
+            .unwrap_or_default();
+        values.push((counter, value));
+    }
+    Ok(values)
+}
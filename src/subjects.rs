@@ -0,0 +1,82 @@
+//! Stable opaque subjects: how clients tell users apart.
+//!
+//! A subject is a random id per identity, used as OpenID Connect `sub`,
+//! SAML `persistent` NameID and Discourse `external_id`. Unlike the
+//! canonical address, it neither reveals the address nor changes with it:
+//! it follows the identity when its address is changed.
+
+use anyhow::Result;
+
+/// Sled tree mapping a canonical address to its subject.
+const SUBJECTS_TREE: &str = "subjects";
+
+/// The subject of `identity`, created on first use.
+pub(crate) fn of(db: &sled::Db, identity: &str) -> Result<String> {
+    let tree = db.open_tree(SUBJECTS_TREE)?;
+    let subject = uuid::Uuid::new_v4().simple().to_string();
+    let stored =
+        match tree.compare_and_swap(identity, None as Option<&[u8]>, Some(subject.as_bytes()))? {
+            Ok(()) => return Ok(subject),
+            Err(err) => err.current,
+        };
+    Ok(String::from_utf8_lossy(stored.as_deref().unwrap_or_default()).into_owned())
+}
+
+/// The identity whose subject is `subject`.
+pub(crate) fn identity_of(db: &sled::Db, subject: &str) -> Result<Option<String>> {
+    for entry in &db.open_tree(SUBJECTS_TREE)? {
+        let (identity, stored) = entry?;
+        if stored.as_ref() == subject.as_bytes() {
+            return Ok(Some(String::from_utf8_lossy(&identity).into_owned()));
+        }
+    }
+    Ok(None)
+}
+
+/// Let the subject of `identity` follow it to its new address `new_identity`.
+///
+/// If `new_identity` already has a subject, it keeps it.
+pub(crate) fn move_to(db: &sled::Db, identity: &str, new_identity: &str) -> Result<()> {
+    let tree = db.open_tree(SUBJECTS_TREE)?;
+    if let Some(subject) = tree.remove(identity)? {
+        tree.compare_and_swap(new_identity, None as Option<&[u8]>, Some(subject))?
+            .ok();
+    }
+    Ok(())
+}
+
+/// Forget the subject of `identity`, e.g. when the account is deleted.
+pub(crate) fn forget(db: &sled::Db, identity: &str) -> Result<()> {
+    db.open_tree(SUBJECTS_TREE)?.remove(identity)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subjects() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let alice = of(&db, "alice@example.org").unwrap();
+        assert_eq!(of(&db, "alice@example.org").unwrap(), alice);
+        assert!(!alice.contains("alice"));
+        let bob = of(&db, "bob@example.org").unwrap();
+        assert_ne!(alice, bob);
+        assert_eq!(
+            identity_of(&db, &alice).unwrap().as_deref(),
+            Some("alice@example.org")
+        );
+        assert_eq!(identity_of(&db, "unknown").unwrap(), None);
+
+        // The subject follows the identity to its new address ...
+        move_to(&db, "alice@example.org", "alice@example.com").unwrap();
+        assert_eq!(of(&db, "alice@example.com").unwrap(), alice);
+        // ... unless that address has its own.
+        move_to(&db, "alice@example.com", "bob@example.org").unwrap();
+        assert_eq!(of(&db, "bob@example.org").unwrap(), bob);
+
+        forget(&db, "bob@example.org").unwrap();
+        assert_ne!(of(&db, "bob@example.org").unwrap(), bob);
+    }
+}
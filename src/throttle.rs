@@ -0,0 +1,134 @@
+//! Rate limits for endpoints that make the bot do work for anonymous
+//! browsers: starting login flows, asking hinted users to confirm and
+//! sending login links by email.
+//!
+//! Each limit allows a number of attempts per key (an address or an IP
+//! address) within a fixed window.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::unix_time;
+
+/// Sled tree mapping `<limit>:<key>` to its [`Window`].
+const THROTTLE_TREE: &str = "throttle";
+
+/// How often something may happen per key.
+#[derive(Debug)]
+pub(crate) struct Limit {
+    /// Prefix of the keys, so that limits do not share counters.
+    name: &'static str,
+    attempts: u32,
+    window_in_seconds: u64,
+}
+
+/// Login flows started per IP address by `/requestQr`.
+pub(crate) const REQUEST_QR_PER_IP: Limit = Limit {
+    name: "request_qr_ip",
+    attempts: 30,
+    window_in_seconds: 10 * 60,
+};
+
+/// Confirmations pushed per address by `/requestQr?login_hint=`.
+pub(crate) const HINT_PER_ADDR: Limit = Limit {
+    name: "hint_addr",
+    attempts: 5,
+    window_in_seconds: 60 * 60,
+};
+
+/// Login links emailed per address by `/emailLogin`.
+pub(crate) const EMAIL_PER_ADDR: Limit = Limit {
+    name: "email_addr",
+    attempts: 3,
+    window_in_seconds: 60 * 60,
+};
+
+/// Login links emailed per IP address by `/emailLogin`.
+pub(crate) const EMAIL_PER_IP: Limit = Limit {
+    name: "email_ip",
+    attempts: 10,
+    window_in_seconds: 60 * 60,
+};
+
+/// Attempts counted since `start`.
+#[derive(Debug, Serialize, Deserialize)]
+struct Window {
+    start: u64,
+    attempts: u32,
+}
+
+/// Count an attempt for `key` and return whether it is within `limit`.
+pub(crate) fn allow(db: &sled::Db, limit: &Limit, key: &str) -> Result<bool> {
+    let tree = db.open_tree(THROTTLE_TREE)?;
+    prune_expired(&tree, limit)?;
+    let now = unix_time();
+    let key = format!("{}:{key}", limit.name);
+    let window = match tree.get(&key)? {
+        Some(data) => serde_json::from_slice::<Window>(&data)?,
+        None => Window {
+            start: now,
+            attempts: 0,
+        },
+    };
+    let window = match now.saturating_sub(window.start) < limit.window_in_seconds {
+        true => Window {
+            attempts: window.attempts.saturating_add(1),
+            ..window
+        },
+        false => Window {
+            start: now,
+            attempts: 1,
+        },
+    };
+    tree.insert(&key, serde_json::to_vec(&window)?)?;
+    Ok(window.attempts <= limit.attempts)
+}
+
+/// Drop the windows of `limit` that have passed.
+fn prune_expired(tree: &sled::Tree, limit: &Limit) -> Result<()> {
+    let now = unix_time();
+    for entry in tree.scan_prefix(format!("{}:", limit.name)) {
+        let (key, data) = entry?;
+        let expired = serde_json::from_slice::<Window>(&data)
+            .map(|window| now.saturating_sub(window.start) >= limit.window_in_seconds)
+            .unwrap_or(true);
+        if expired {
+            tree.remove(key)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allow() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        for _ in 0..3 {
+            assert!(allow(&db, &EMAIL_PER_ADDR, "alice@example.org").unwrap());
+        }
+        assert!(!allow(&db, &EMAIL_PER_ADDR, "alice@example.org").unwrap());
+        // Other keys and other limits have their own counters.
+        assert!(allow(&db, &EMAIL_PER_ADDR, "bob@example.org").unwrap());
+        assert!(allow(&db, &EMAIL_PER_IP, "alice@example.org").unwrap());
+    }
+
+    #[test]
+    fn test_window_passed() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree(THROTTLE_TREE).unwrap();
+        let window = Window {
+            start: unix_time() - EMAIL_PER_ADDR.window_in_seconds,
+            attempts: 3,
+        };
+        tree.insert(
+            "email_addr:alice@example.org",
+            serde_json::to_vec(&window).unwrap(),
+        )
+        .unwrap();
+        assert!(allow(&db, &EMAIL_PER_ADDR, "alice@example.org").unwrap());
+        assert!(allow(&db, &EMAIL_PER_ADDR, "alice@example.org").unwrap());
+    }
+}
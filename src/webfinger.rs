@@ -0,0 +1,146 @@
+//! WebFinger at `/.well-known/webfinger`, so that OpenID Connect clients
+//! can discover the bot as issuer from a user's address.
+//!
+//! Only addresses on the configured domains are answered for, and unless
+//! `answer = "all"`, only those of users who logged in before.
+
+use anyhow::Result;
+use axum::{
+    extract::{RawQuery, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{policy, AppError, AppState};
+
+/// Link relation of the OpenID Connect issuer.
+const REL_ISSUER: &str = "http://openid.net/specs/connect/1.0/issuer";
+
+/// Configuration of `/.well-known/webfinger`.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct WebFingerConfig {
+    /// Domains whose addresses the bot is the issuer for, e.g. `["example.org"]`.
+    pub domains: Vec<String>,
+    /// Which addresses on `domains` are answered for.
+    #[serde(default)]
+    pub answer: WebFingerAnswer,
+}
+
+/// Which addresses `/.well-known/webfinger` answers for.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebFingerAnswer {
+    /// Only addresses with an identity, so that nobody can probe for users.
+    #[default]
+    Registered,
+    /// Every address, which does not reveal who logged in before.
+    All,
+}
+
+/// `GET /.well-known/webfinger?resource=acct:<address>`: the issuer link of
+/// the address, filtered by the `rel` parameters.
+pub(crate) async fn get_webfinger(
+    State(state): State<AppState>,
+    RawQuery(raw_query): RawQuery,
+) -> Result<Response, AppError> {
+    let (Some(config), Some(issuer)) = (&state.config.webfinger, &state.config.public_url) else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let query: Vec<(String, String)> =
+        url::form_urlencoded::parse(raw_query.unwrap_or_default().as_bytes())
+            .into_owned()
+            .collect();
+    let Some(resource) = query
+        .iter()
+        .find(|(key, _)| key == "resource")
+        .map(|(_, value)| value.as_str())
+    else {
+        return Ok((StatusCode::BAD_REQUEST, "resource is missing").into_response());
+    };
+    let Some(addr) = resource_addr(resource).filter(|addr| on_domains(addr, &config.domains))
+    else {
+        log::info!("/.well-known/webfinger not answering for {resource}");
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    if config.answer == WebFingerAnswer::Registered && !is_registered(&state, &addr)? {
+        log::info!("/.well-known/webfinger not answering for unregistered {addr}");
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    let rels: Vec<&str> = query
+        .iter()
+        .filter(|(key, _)| key == "rel")
+        .map(|(_, value)| value.as_str())
+        .collect();
+    let links: Vec<Value> = [(REL_ISSUER, issuer.trim_end_matches('/'))]
+        .into_iter()
+        .filter(|(rel, _)| rels.is_empty() || rels.contains(rel))
+        .map(|(rel, href)| json!({ "rel": rel, "href": href }))
+        .collect();
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/jrd+json"),
+            (header::ACCESS_CONTROL_ALLOW_ORIGIN, "*"),
+        ],
+        json!({ "subject": resource, "links": links }).to_string(),
+    )
+        .into_response())
+}
+
+/// The lowercased address of an `acct:` or `mailto:` `resource`.
+fn resource_addr(resource: &str) -> Option<String> {
+    let addr = resource
+        .strip_prefix("acct:")
+        .or_else(|| resource.strip_prefix("mailto:"))?;
+    let (local, domain) = addr.split_once('@')?;
+    match local.is_empty() || domain.is_empty() || domain.contains('@') {
+        true => None,
+        false => Some(addr.to_lowercase()),
+    }
+}
+
+/// Whether `addr` belongs to one of `domains`.
+fn on_domains(addr: &str, domains: &[String]) -> bool {
+    let domain = addr.rsplit('@').next().unwrap_or_default();
+    domains
+        .iter()
+        .any(|configured| configured.eq_ignore_ascii_case(domain))
+}
+
+/// Whether `addr` logged in with a key that has an identity.
+fn is_registered(state: &AppState, addr: &str) -> Result<bool> {
+    match policy::known_fingerprint(&state.db, addr)? {
+        Some(fp) => Ok(state.db.open_tree("identities")?.contains_key(fp)?),
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resources() {
+        assert_eq!(
+            resource_addr("acct:Alice@Example.org").as_deref(),
+            Some("alice@example.org")
+        );
+        assert_eq!(
+            resource_addr("mailto:bob@example.org").as_deref(),
+            Some("bob@example.org")
+        );
+        assert_eq!(resource_addr("https://example.org/alice"), None);
+        assert_eq!(resource_addr("acct:@example.org"), None);
+        assert_eq!(resource_addr("acct:alice"), None);
+    }
+
+    #[test]
+    fn test_domains() {
+        let domains = ["example.org".to_string()];
+        assert!(on_domains("alice@example.org", &domains));
+        assert!(!on_domains("alice@sub.example.org", &domains));
+        assert!(!on_domains("alice@example.com", &domains));
+    }
+}
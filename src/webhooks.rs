@@ -0,0 +1,499 @@
+//! Webhooks in both directions.
+//!
+//! Outgoing: relying parties hear about registrations, logins, address
+//! changes, new keys, blocks and deleted accounts. Events are queued in
+//! sled and delivered by [`run_webhooks`], which retries with growing
+//! delays, so a relying party that is down does not lose events. Each
+//! request body is signed with HMAC-SHA256 using the client secret, in the
+//! `X-Loginbot-Signature` header.
+//!
+//! Incoming: relying parties tell the bot on `/webhook` that they deleted,
+//! anonymized or suspended a user, e.g. with Discourse's user webhooks,
+//! signed with the client's `webhook_secret`.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use data_encoding::HEXLOWER;
+use deltachat::chat::{send_text_msg, ChatId};
+use deltachat::contact::{Contact, Origin};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    account, admin, blocks, consent, secrets_match, unix_time, AppError, AppState, OAuthConfig,
+};
+
+/// Sled tree of pending [`Delivery`]s, keyed by a sled id (u64 be) in queue order.
+const QUEUE_TREE: &str = "webhook_queue";
+
+/// Sled tree of the clients each identity logged in to, keyed by `"{identity} {client_id}"`,
+/// with the unix time (u64 be) of the last login.
+const LOGINS_TREE: &str = "webhook_logins";
+
+/// How often the queue is checked for due deliveries.
+const QUEUE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Deliveries are dropped after this many failed attempts, about two days.
+const MAX_ATTEMPTS: u32 = 16;
+
+/// Upper bound of the delay between two attempts.
+const MAX_RETRY_DELAY_IN_SECONDS: u64 = 6 * 60 * 60;
+
+/// Something that happened to a user's identity.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event {
+    /// A key logged in for the first time.
+    Registered { identity: String },
+    /// The user logged in to `client_id`; only sent to that client.
+    Login { identity: String, client_id: String },
+    /// The canonical address changed from `previous` to `identity`.
+    AddressChanged { identity: String, previous: String },
+    /// Another key now logs in as `identity`.
+    KeyLinked {
+        identity: String,
+        fingerprint: String,
+    },
+    /// The user was blocked.
+    Blocked { identity: String },
+    /// The user deleted their account.
+    Deleted { identity: String },
+}
+
+/// A webhook request waiting to be delivered.
+#[derive(Debug, Serialize, Deserialize)]
+struct Delivery {
+    client_id: String,
+    url: String,
+    body: String,
+    attempts: u32,
+    /// Unix time of the next attempt.
+    next_attempt: u64,
+}
+
+/// Queue `event` for every client with a `webhook_url` that the user
+/// granted access or logged in to.
+pub(crate) fn fire(state: &AppState, event: Event) -> Result<()> {
+    let clients = std::iter::once(&state.config.oauth).chain(&state.config.clients);
+    let recipients = recipients(&state.db, &event, clients)?;
+    if recipients.is_empty() {
+        return Ok(());
+    }
+    let mut payload = serde_json::to_value(&event)?;
+    if let Some(payload) = payload.as_object_mut() {
+        payload.insert(
+            "id".to_string(),
+            uuid::Uuid::new_v4().simple().to_string().into(),
+        );
+        payload.insert("time".to_string(), Value::from(unix_time()));
+    }
+    let body = payload.to_string();
+    let queue = state.db.open_tree(QUEUE_TREE)?;
+    for client in recipients {
+        let Some(url) = &client.webhook_url else {
+            continue;
+        };
+        let delivery = Delivery {
+            client_id: client.client_id.clone(),
+            url: url.clone(),
+            body: body.clone(),
+            attempts: 0,
+            next_attempt: unix_time(),
+        };
+        queue.insert(
+            state.db.generate_id()?.to_be_bytes(),
+            serde_json::to_vec(&delivery)?,
+        )?;
+    }
+    Ok(())
+}
+
+/// The clients of `clients` that hear about `event`.
+///
+/// A login is only sent to the client the user logged in to, and remembered
+/// for later events. Other events go to the clients the user granted access
+/// or logged in to, under the old or the new canonical address.
+fn recipients<'a>(
+    db: &sled::Db,
+    event: &Event,
+    clients: impl Iterator<Item = &'a OAuthConfig>,
+) -> Result<Vec<&'a OAuthConfig>> {
+    let logins = db.open_tree(LOGINS_TREE)?;
+    let identities = match event {
+        Event::Login {
+            identity,
+            client_id,
+        } => {
+            logins.insert(login_key(identity, client_id), &unix_time().to_be_bytes())?;
+            return Ok(clients
+                .filter(|client| client.client_id == *client_id)
+                .collect());
+        }
+        Event::AddressChanged { identity, previous } => {
+            for key in logins.scan_prefix(format!("{previous} ")).keys() {
+                let key = String::from_utf8(key?.to_vec())?;
+                if let Some(client_id) = key.strip_prefix(&format!("{previous} ")) {
+                    logins.insert(login_key(identity, client_id), &unix_time().to_be_bytes())?;
+                }
+            }
+            vec![identity, previous]
+        }
+        Event::Registered { identity }
+        | Event::KeyLinked { identity, .. }
+        | Event::Blocked { identity }
+        | Event::Deleted { identity } => vec![identity],
+    };
+    let mut known = Vec::new();
+    for identity in identities {
+        for (client_id, _) in consent::grants_of(db, identity)? {
+            known.push(client_id);
+        }
+        for key in logins.scan_prefix(format!("{identity} ")).keys() {
+            let key = String::from_utf8(key?.to_vec())?;
+            if let Some(client_id) = key.strip_prefix(&format!("{identity} ")) {
+                known.push(client_id.to_string());
+            }
+        }
+    }
+    Ok(clients
+        .filter(|client| known.contains(&client.client_id))
+        .collect())
+}
+
+fn login_key(identity: &str, client_id: &str) -> String {
+    format!("{identity} {client_id}")
+}
+
+/// Forget which clients `identity` logged in to.
+pub(crate) fn forget(db: &sled::Db, identity: &str) -> Result<()> {
+    let logins = db.open_tree(LOGINS_TREE)?;
+    for key in logins.scan_prefix(format!("{identity} ")).keys() {
+        logins.remove(key?)?;
+    }
+    Ok(())
+}
+
+/// Deliver queued webhooks, forever.
+pub async fn run_webhooks(state: AppState) {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .unwrap_or_default();
+    let mut interval = tokio::time::interval(QUEUE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(err) = deliver_due(&state, &client).await {
+            log::error!("failed to deliver webhooks: {err:#}");
+        }
+    }
+}
+
+/// Attempt every delivery whose time has come.
+async fn deliver_due(state: &AppState, http: &reqwest::Client) -> Result<()> {
+    let queue = state.db.open_tree(QUEUE_TREE)?;
+    for entry in &queue {
+        let (key, data) = entry?;
+        let mut delivery: Delivery = serde_json::from_slice(&data)?;
+        if delivery.next_attempt > unix_time() {
+            continue;
+        }
+        let Some(client) = state.config.client(&delivery.client_id) else {
+            queue.remove(key)?;
+            continue;
+        };
+        let result = http
+            .post(&delivery.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(
+                "X-Loginbot-Signature",
+                signature(delivery.body.as_bytes(), &client.client_secret),
+            )
+            .body(delivery.body.clone())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        let Err(err) = result else {
+            queue.remove(key)?;
+            continue;
+        };
+        delivery.attempts = delivery.attempts.saturating_add(1);
+        if delivery.attempts >= MAX_ATTEMPTS {
+            log::error!("giving up webhook to {}: {err}", delivery.url);
+            admin::alert(
+                state,
+                &format!(
+                    "Dropped a webhook to {} after {MAX_ATTEMPTS} failed attempts: {err}",
+                    delivery.client_id
+                ),
+            )
+            .await;
+            queue.remove(key)?;
+            continue;
+        }
+        log::warn!(
+            "webhook to {} failed (attempt {}): {err}",
+            delivery.url,
+            delivery.attempts
+        );
+        delivery.next_attempt = unix_time().saturating_add(retry_delay(delivery.attempts));
+        queue.insert(key, serde_json::to_vec(&delivery)?)?;
+    }
+    Ok(())
+}
+
+/// Seconds to wait after the `attempts`th failure: 30 seconds, doubling up to six hours.
+fn retry_delay(attempts: u32) -> u64 {
+    30u64
+        .saturating_mul(2u64.saturating_pow(attempts.saturating_sub(1)))
+        .min(MAX_RETRY_DELAY_IN_SECONDS)
+}
+
+/// The signature header value of `body`: `sha256=` and its hex HMAC-SHA256 keyed with `secret`.
+fn signature(body: &[u8], secret: &str) -> String {
+    let mac = hmac_sha256::HMAC::mac(body, secret.as_bytes());
+    format!("sha256={}", HEXLOWER.encode(&mac))
+}
+
+/// What the bot does about an event a relying party sent to `/webhook`.
+///
+/// Actions only affect the user's logins to the sending client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    /// Forget the user's grant to the client and revoke their logins to it.
+    Forget,
+    /// Block the user's keys for logins to the client.
+    Block,
+    /// Only pass the event's `text` on to the user.
+    Notify,
+}
+
+impl Action {
+    /// The action for the event named `event`; `None` for unknown events.
+    fn of(event: &str) -> Option<Action> {
+        match event {
+            "user_destroyed" | "user_anonymized" | "user_deleted" => Some(Action::Forget),
+            "user_suspended" => Some(Action::Block),
+            "notify" => Some(Action::Notify),
+            _ => None,
+        }
+    }
+}
+
+fn webhook_error(status: StatusCode, error: &str) -> (StatusCode, Json<Value>) {
+    (status, Json(json!({ "error": error })))
+}
+
+/// `POST /webhook`: an event about a user from a relying party.
+///
+/// The sender is the client whose `webhook_secret` matches the
+/// `X-Discourse-Event-Signature` or `X-Loginbot-Signature` header. The event
+/// is named by the `X-Discourse-Event` header or the `event` field, the user
+/// by the `user.email` or `identity` field.
+pub(crate) async fn post_webhook(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let sender = header("X-Discourse-Event-Signature")
+        .or_else(|| header("X-Loginbot-Signature"))
+        .and_then(|sent| {
+            let mut clients = std::iter::once(&state.config.oauth).chain(&state.config.clients);
+            clients.find(|client| {
+                client
+                    .webhook_secret
+                    .as_ref()
+                    .is_some_and(|secret| secrets_match(&signature(&body, secret), sent))
+            })
+        });
+    let Some(client) = sender else {
+        log::info!("/webhook rejected a request without a valid signature");
+        return Ok(webhook_error(StatusCode::UNAUTHORIZED, "invalid signature"));
+    };
+    let Ok(payload) = serde_json::from_slice::<Value>(&body) else {
+        return Ok(webhook_error(StatusCode::BAD_REQUEST, "invalid JSON"));
+    };
+    let event = header("X-Discourse-Event")
+        .or_else(|| payload.get("event")?.as_str())
+        .unwrap_or_default();
+    if event == "ping" {
+        return Ok((StatusCode::OK, Json(json!({ "ok": true }))));
+    }
+    let Some(action) = Action::of(event) else {
+        log::warn!(
+            "/webhook rejected unknown event {event:?} from {}",
+            client.client_id
+        );
+        return Ok(webhook_error(StatusCode::BAD_REQUEST, "unknown event"));
+    };
+    let Some(identity) = payload
+        .pointer("/user/email")
+        .or_else(|| payload.get("identity"))
+        .and_then(Value::as_str)
+        .map(|addr| addr.trim().to_lowercase())
+    else {
+        return Ok(webhook_error(StatusCode::BAD_REQUEST, "missing user email"));
+    };
+    let (fingerprints, addresses) = account::keys_of(&state.db, &identity)?;
+    let reason = format!("{event} by {}", client.client_id);
+    if fingerprints.is_empty() {
+        log::info!(
+            "/webhook ignored {event} from {} about unknown user {identity}",
+            client.client_id
+        );
+        return Ok((StatusCode::OK, Json(json!({ "ok": true, "known": false }))));
+    }
+
+    let text = match action {
+        Action::Forget => {
+            consent::ungrant(&state.db, &identity, &client.client_id)?;
+            for fp in &fingerprints {
+                blocks::revoke_for_client(&state, &client.client_id, fp).await?;
+            }
+            format!(
+                "{} deleted your account. The bot no longer shares your data with it; \
+                logging in there again creates a new account.",
+                client_name(client)
+            )
+        }
+        Action::Block => {
+            for fp in &fingerprints {
+                blocks::block_for_client(&state, &client.client_id, fp, Some(reason.clone()))
+                    .await?;
+            }
+            format!(
+                "{} suspended your account. You cannot log in to it with the bot \
+                until the bot's admins lift the block with /unblock.",
+                client_name(client)
+            )
+        }
+        Action::Notify => {
+            let Some(text) = payload.get("text").and_then(Value::as_str) else {
+                return Ok(webhook_error(StatusCode::BAD_REQUEST, "missing text"));
+            };
+            format!("{}: {text}", client_name(client))
+        }
+    };
+    notify_user(&state, &addresses, text).await?;
+    log::info!(
+        "/webhook {event} from {}: {action:?} {identity}",
+        client.client_id
+    );
+    Ok((StatusCode::OK, Json(json!({ "ok": true, "known": true }))))
+}
+
+fn client_name(client: &OAuthConfig) -> &str {
+    client.name.as_deref().unwrap_or(&client.client_id)
+}
+
+/// Send `text` to the user in the chat with the first of `addresses` the bot knows.
+async fn notify_user(state: &AppState, addresses: &[String], text: String) -> Result<()> {
+    for addr in addresses {
+        let Some(contact_id) =
+            Contact::lookup_id_by_addr(&state.dc_context, addr, Origin::IncomingUnknownFrom)
+                .await?
+        else {
+            continue;
+        };
+        let chat_id = ChatId::create_for_contact(&state.dc_context, contact_id).await?;
+        send_text_msg(&state.dc_context, chat_id, text).await?;
+        return Ok(());
+    }
+    log::warn!("cannot tell {addresses:?} about a webhook, no known contact");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delays() {
+        assert_eq!(retry_delay(1), 30);
+        assert_eq!(retry_delay(2), 60);
+        assert_eq!(retry_delay(MAX_ATTEMPTS), MAX_RETRY_DELAY_IN_SECONDS);
+    }
+
+    #[test]
+    fn test_signatures() {
+        assert_eq!(
+            signature(b"The quick brown fox jumps over the lazy dog", "key"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn test_actions() {
+        assert_eq!(Action::of("user_destroyed"), Some(Action::Forget));
+        assert_eq!(Action::of("user_suspended"), Some(Action::Block));
+        assert_eq!(Action::of("user_unsuspended"), None);
+        assert_eq!(Action::of("post_created"), None);
+    }
+
+    #[test]
+    fn test_recipients() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let clients: Vec<OAuthConfig> = ["forum", "wiki", "shop"]
+            .into_iter()
+            .map(|client_id| OAuthConfig {
+                client_id: client_id.to_string(),
+                ..Default::default()
+            })
+            .collect();
+        let ids = |event: Event| -> Vec<String> {
+            recipients(&db, &event, clients.iter())
+                .unwrap()
+                .into_iter()
+                .map(|client| client.client_id.clone())
+                .collect()
+        };
+        let blocked = |identity: &str| Event::Blocked {
+            identity: identity.to_string(),
+        };
+        // Nobody hears about users who never used a client.
+        assert!(ids(blocked("alice@example.org")).is_empty());
+
+        let login = Event::Login {
+            identity: "alice@example.org".to_string(),
+            client_id: "forum".to_string(),
+        };
+        assert_eq!(ids(login), ["forum"]);
+        db.open_tree("grants")
+            .unwrap()
+            .insert(
+                "alice@example.org wiki",
+                serde_json::to_vec(&json!({ "scopes": ["email"], "created": 0 })).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(ids(blocked("alice@example.org")), ["forum", "wiki"]);
+        assert!(ids(blocked("bob@example.org")).is_empty());
+
+        // The login records follow the canonical address.
+        let changed = Event::AddressChanged {
+            identity: "alice@example.net".to_string(),
+            previous: "alice@example.org".to_string(),
+        };
+        assert_eq!(ids(changed), ["forum", "wiki"]);
+        assert_eq!(ids(blocked("alice@example.net")), ["forum"]);
+        forget(&db, "alice@example.net").unwrap();
+        assert!(ids(blocked("alice@example.net")).is_empty());
+    }
+
+    #[test]
+    fn test_payload() {
+        let event = Event::AddressChanged {
+            identity: "new@example.org".to_string(),
+            previous: "old@example.org".to_string(),
+        };
+        let value = serde_json::to_value(event).unwrap();
+        assert_eq!(value["event"], "address_changed");
+        assert_eq!(value["previous"], "old@example.org");
+    }
+}
@@ -67,15 +67,26 @@ async fn test_full_login_flow() -> Result<()> {
             deltachat_db: dir.path().join("bot.db"),
             oauth_db: dir.path().join("oauth.db"),
             listen_addr: "127.0.0.1:0".parse()?,
+            public_url: None,
+            trusted_proxy: false,
             oauth: OAuthConfig {
                 client_id: CLIENT_ID.into(),
                 client_secret: CLIENT_SECRET.into(),
                 redirect_uri: REDIRECT_URI.into(),
+                ..Default::default()
             },
+            clients: Vec::new(),
             static_dir: Some(static_dir.clone()),
             log_level: None,
+            roles: Default::default(),
+            registration: Default::default(),
+            admin: Default::default(),
+            forward_auth: None,
+            saml: None,
+            webfinger: None,
         },
         login_html: "<html>login</html>".into(),
+        saml_key: None,
     };
     let router = build_router(state, static_dir.clone());
 
@@ -125,6 +136,21 @@ async fn test_full_login_flow() -> Result<()> {
     let json: serde_json::Value = resp.json().await?;
     assert_eq!(json["waiting"], true, "expected waiting, got: {json}");
 
+    // The flow id alone identifies the login, without the session cookie
+    let flow = json2["flow"]
+        .as_str()
+        .context("no flow in requestQr response")?;
+    let resp = reqwest::Client::new()
+        .get(format!("{base_url}/checkStatus"))
+        .query(&[("flow", flow)])
+        .send()
+        .await?;
+    let json: serde_json::Value = resp.json().await?;
+    assert_eq!(
+        json["waiting"], true,
+        "expected waiting without cookie, got: {json}"
+    );
+
     // 6) User joins the group via securejoin
     log::info!("User joining via securejoin…");
     join_securejoin(&user_ctx, &invite_link).await?;
@@ -146,6 +172,27 @@ async fn test_full_login_flow() -> Result<()> {
     }
     assert!(joined, "user was not detected within 60s");
 
+    // prompt=none cannot be combined with other prompt values
+    let resp = client
+        .get(format!("{base_url}/authorize"))
+        .query(&[
+            ("client_id", CLIENT_ID),
+            ("redirect_uri", REDIRECT_URI),
+            ("state", "test123"),
+            ("prompt", "none login"),
+        ])
+        .send()
+        .await?;
+    let location = resp
+        .headers()
+        .get("location")
+        .context("no location header for invalid prompt")?
+        .to_str()?;
+    assert!(
+        location.contains("error=invalid_request"),
+        "invalid prompt accepted: {location}"
+    );
+
     // 8) GET /authorize — should redirect with ?code=...
     let resp = client
         .get(format!("{base_url}/authorize"))
@@ -203,6 +250,29 @@ async fn test_full_login_flow() -> Result<()> {
     let user_addr = user_ctx.get_config(Config::Addr).await?.unwrap_or_default();
     assert_eq!(email, user_addr, "email mismatch");
     log::info!("Token exchange returned email={email}");
+    let access_token = json["access_token"]
+        .as_str()
+        .context("no access_token in token response")?
+        .to_string();
+
+    // Codes are single-use.
+    let resp = client
+        .post(format!("{base_url}/token"))
+        .basic_auth(CLIENT_ID, Some(CLIENT_SECRET))
+        .form(&[("grant_type", "authorization_code"), ("code", &code)])
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 401, "code was accepted twice");
+
+    // GET /userinfo — the access token yields the same user info
+    let resp = client
+        .get(format!("{base_url}/userinfo"))
+        .bearer_auth(&access_token)
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 200, "userinfo failed");
+    let json: serde_json::Value = resp.json().await?;
+    assert_eq!(json["email"], user_addr, "userinfo email mismatch");
 
     // 10) Second login from the same browser session (same cookie jar).
     //     This is the repeated-login regression: a stale `sent=true` session